@@ -13,6 +13,7 @@ pub enum Method {
 }
 
 impl Method {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(token: &str) -> Result<Self, String> {
         match token {
             "GET" => Ok(Method::GET),
@@ -28,6 +29,21 @@ impl Method {
             _ => Err("Invalid method".into()),
         }
     }
+
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::HEAD => "HEAD",
+            Method::OPTIONS => "OPTIONS",
+            Method::CONNECT => "CONNECT",
+            Method::TRACE => "TRACE",
+            Method::PATCH => "PATCH",
+            Method::LIST => "LIST",
+        }
+    }
 }
 
 #[derive(Default)]
@@ -48,6 +64,7 @@ pub enum MimeType {
 }
 
 impl MimeType {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(token: &str) -> Self {
         match token {
             "text/plain" => MimeType::TextPlain,
@@ -84,14 +101,14 @@ impl MimeType {
     }
 
     pub fn is_utf8(&self) -> bool {
-        match self {
-            MimeType::TextPlain => true,
-            MimeType::TextHtml => true,
-            MimeType::TextCss => true,
-            MimeType::TextJavascript => true,
-            MimeType::ApplicationJson => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            MimeType::TextPlain
+                | MimeType::TextHtml
+                | MimeType::TextCss
+                | MimeType::TextJavascript
+                | MimeType::ApplicationJson
+        )
     }
 }
 
@@ -121,7 +138,14 @@ pub fn status_code_lookup(code: u16) -> &'static str {
         402 => "Payment Required",
         403 => "Forbidden",
         404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
         418 => "I'm a teapot",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
 
         500 => "Internal Server Error",
         501 => "Not Implemented",
@@ -9,44 +9,85 @@ const CRLF_CHARS: &str = "\r\n";
 const CRLF_BYTES: &[u8] = b"\r\n";
 const TOKEN_SEPERATOR: &str = " ";
 const HEADER_SEPERATOR: &str = ": ";
+// Longest chunk size line, and most trailer bytes, unless set otherwise
+const DEFAULT_MAX_FIELD_SIZE: usize = 64 * 1024;
+
+// Where the parser is in a body sent with Transfer-Encoding: chunked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done,
+}
 
 pub struct Parser {
     buffer: Vec<u8>,
 
     has_parsed_request_line: bool,
     has_consumed_req_headers: bool,
+    chunk_state: Option<ChunkState>,
+    // Bytes at the start of the buffer known to hold no CRLF, so a line
+    // arriving in pieces isn't searched from the start again every time
+    scanned: usize,
+    // Cap on a chunk size line and on all trailers together, anything the
+    // parser would otherwise have to hold on to for as long as it is sent
+    max_field_size: usize,
+    trailer_bytes: usize,
 
     is_invalid: bool,
+    is_too_large: bool,
 
     request: Option<Request>,
 }
 
 // Default parsing behavior for HTTP
 impl TextStream for Parser {
-    fn as_line_stream<'a>(data: &'a str) -> Split<'a, &str> {
+    fn as_line_stream<'a>(data: &'a str) -> Split<'a, &'a str> {
         data.split(CRLF_CHARS)
     }
 
-    fn as_token_stream<'a>(line: &'a str) -> Split<'a, &str> {
+    fn as_token_stream<'a>(line: &'a str) -> Split<'a, &'a str> {
         line.split(TOKEN_SEPERATOR)
     }
 }
 
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Parser {
     pub fn new() -> Self {
         Self {
             buffer: Vec::new(),
             has_parsed_request_line: false,
             has_consumed_req_headers: false,
+            chunk_state: None,
+            scanned: 0,
+            max_field_size: DEFAULT_MAX_FIELD_SIZE,
+            trailer_bytes: 0,
             is_invalid: false,
+            is_too_large: false,
             request: None,
         }
     }
 
+    pub fn with_max_field_size(mut self, max_field_size: usize) -> Self {
+        self.max_field_size = max_field_size;
+        self
+    }
+
     pub fn consume_request(self) -> Option<Request> {
         self.request
     }
 
+    pub fn request(&self) -> Option<&Request> {
+        self.request.as_ref()
+    }
+
     pub fn update(&mut self, raw_data: &[u8]) {
         if self.is_invalid {
             return;
@@ -85,7 +126,7 @@ impl Parser {
 
             // Consume the line
             let drained = self.buffer.drain(..index + CRLF_BYTES.len());
-            let line = String::from_utf8_lossy(&drained.as_slice());
+            let line = String::from_utf8_lossy(drained.as_slice());
             let line = line.as_ref();
             let line = &line[..line.len() - CRLF_CHARS.len()];
 
@@ -150,16 +191,131 @@ impl Parser {
 
         if self.has_consumed_req_headers {
             // Ensure content length and such are parsed as the last header
-            self.request.as_mut().unwrap().post_process();
+            let request = self.request.as_mut().unwrap();
+            request.post_process();
+            if request.is_chunked() {
+                self.chunk_state = Some(ChunkState::Size);
+            }
             // Drain last CRLF
             self.buffer.drain(..CRLF_BYTES.len());
         }
     }
 
     fn parse_request_body(&mut self) {
+        if self.chunk_state.is_some() {
+            return self.parse_chunked_body();
+        }
+
         // Append rest of buffer to body
         if let Some(request) = &mut self.request {
-            request.raw_body.extend(self.buffer.drain(..));
+            request.raw_body.append(&mut self.buffer);
+        }
+    }
+
+    fn take_line(&mut self) -> Option<String> {
+        // A CR may have ended the bytes scanned before, its LF is new
+        let start = self.scanned.saturating_sub(1);
+        let index = match self.buffer[start..].windows(CRLF_BYTES.len()).position(|x| x == CRLF_BYTES) {
+            Some(index) => start + index,
+            None => {
+                self.scanned = self.buffer.len();
+                if self.buffer.len() > self.max_field_size {
+                    self.too_large();
+                }
+                return None;
+            }
+        };
+
+        self.scanned = 0;
+        let drained: Vec<u8> = self.buffer.drain(..index + CRLF_BYTES.len()).collect();
+        Some(String::from_utf8_lossy(&drained[..index]).to_string())
+    }
+
+    fn too_large(&mut self) {
+        self.is_invalid = true;
+        self.is_too_large = true;
+    }
+
+    // Decodes as much of a chunked body as has been received, the chunks are
+    // joined into the body and trailer fields are collected after the last one
+    fn parse_chunked_body(&mut self) {
+        loop {
+            match self.chunk_state {
+                Some(ChunkState::Size) => {
+                    let line = match self.take_line() {
+                        Some(line) => line,
+                        None => return,
+                    };
+
+                    // Chunk extensions are ignored
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    match usize::from_str_radix(size, 16) {
+                        Ok(0) => self.chunk_state = Some(ChunkState::Trailers),
+                        Ok(size) => self.chunk_state = Some(ChunkState::Data(size)),
+                        Err(_) => {
+                            self.is_invalid = true;
+                            return;
+                        }
+                    }
+                }
+                Some(ChunkState::Data(remaining)) => {
+                    if self.buffer.is_empty() {
+                        return;
+                    }
+
+                    let count = remaining.min(self.buffer.len());
+                    let data = self.buffer.drain(..count);
+                    if let Some(request) = &mut self.request {
+                        request.raw_body.extend(data);
+                    }
+                    self.chunk_state = Some(match remaining - count {
+                        0 => ChunkState::DataEnd,
+                        remaining => ChunkState::Data(remaining),
+                    });
+                }
+                Some(ChunkState::DataEnd) => {
+                    if self.buffer.len() < CRLF_BYTES.len() {
+                        return;
+                    }
+
+                    if !self.buffer.starts_with(CRLF_BYTES) {
+                        self.is_invalid = true;
+                        return;
+                    }
+
+                    self.buffer.drain(..CRLF_BYTES.len());
+                    self.chunk_state = Some(ChunkState::Size);
+                }
+                Some(ChunkState::Trailers) => {
+                    let line = match self.take_line() {
+                        Some(line) => line,
+                        None => return,
+                    };
+                    if line.is_empty() {
+                        self.chunk_state = Some(ChunkState::Done);
+                        continue;
+                    }
+
+                    self.trailer_bytes += line.len() + CRLF_BYTES.len();
+                    if self.trailer_bytes > self.max_field_size {
+                        self.too_large();
+                        return;
+                    }
+
+                    match (line.split_once(':'), &mut self.request) {
+                        (Some((name, value)), Some(request)) => {
+                            request
+                                .trailers
+                                .insert(name.trim().to_lowercase(), value.trim().to_string());
+                        }
+                        _ => {
+                            self.is_invalid = true;
+                            return;
+                        }
+                    }
+                }
+                Some(ChunkState::Done) | None => return,
+            }
         }
     }
 
@@ -172,18 +328,32 @@ impl Parser {
             return false;
         }
 
+        if let Some(state) = self.chunk_state {
+            return state == ChunkState::Done;
+        }
+
         if let Some(request) = &self.request {
             if let Some(content_length) = request.content_length {
                 return request.raw_body.len() >= content_length;
             }
         }
 
-        return true;
+        true
+    }
+
+    pub fn headers_complete(&self) -> bool {
+        self.has_consumed_req_headers
     }
 
     pub fn is_invalid(&self) -> bool {
         self.is_invalid || self.request.is_none()
     }
+
+    // Whether the request is invalid because a chunk size line or its
+    // trailers outgrew the cap
+    pub fn is_too_large(&self) -> bool {
+        self.is_too_large
+    }
 }
 
 #[cfg(test)]
@@ -216,6 +386,63 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_chunked_body_with_trailers() {
+        let mut parser = Parser::new();
+
+        parser.update(b"PUT /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n5\r\nhel");
+        assert!(!parser.is_done());
+
+        parser.update(b"lo\r\n6;ext=1\r\n world\r\n0\r\nX-Checksum: abc\r\n");
+        assert!(!parser.is_done());
+
+        parser.update(b"\r\n");
+        assert!(parser.is_done());
+        assert!(!parser.is_invalid());
+
+        let request = parser.request.as_ref().unwrap();
+        assert_eq!(request.raw_body, b"hello world");
+        assert_eq!(request.trailers.get("x-checksum"), Some(&"abc".to_string()));
+
+        let mut parser = Parser::new();
+        parser.update(b"PUT /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n");
+        assert!(parser.is_invalid());
+    }
+
+    #[test]
+    fn test_chunked_limits() {
+        let head = b"PUT /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        // A size line that never ends
+        let mut parser = Parser::new().with_max_field_size(64);
+        parser.update(head);
+        for _ in 0..10 {
+            parser.update(b"0000000000");
+        }
+        assert!(parser.is_invalid());
+        assert!(parser.is_too_large());
+
+        // Trailers that never end
+        let mut parser = Parser::new().with_max_field_size(64);
+        parser.update(head);
+        parser.update(b"1\r\na\r\n0\r\n");
+        for i in 0..10 {
+            parser.update(format!("X-Trailer-{}: value\r\n", i).as_bytes());
+        }
+        assert!(parser.is_invalid());
+        assert!(parser.is_too_large());
+
+        // A size line arriving a byte at a time is found all the same
+        let mut parser = Parser::new().with_max_field_size(64);
+        parser.update(head);
+        for byte in b"3\r\nabc\r\n0\r\n\r\n" {
+            parser.update(&[*byte]);
+        }
+        assert!(parser.is_done());
+        assert!(!parser.is_invalid());
+        assert_eq!(parser.request().unwrap().raw_body, b"abc");
+    }
+
     #[test]
     fn test_update_with_invalid_data() {
         let mut parser = Parser::new();
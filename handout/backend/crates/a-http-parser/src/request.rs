@@ -8,6 +8,8 @@ pub struct Request {
     pub version: String,
     pub headers: HashMap<String, String>,
     pub raw_body: Vec<u8>,
+    // Fields sent after a chunked body
+    pub trailers: HashMap<String, String>,
 
     // Post-processing
     pub content_length: Option<usize>,
@@ -27,6 +29,7 @@ impl Request {
             version,
             headers: HashMap::new(),
             raw_body: Vec::new(),
+            trailers: HashMap::new(),
             content_length: None,
             cookies: None,
             mime_type: None,
@@ -57,6 +60,12 @@ impl Request {
         });
     }
 
+    pub fn is_chunked(&self) -> bool {
+        self.headers
+            .get("transfer-encoding")
+            .is_some_and(|x| x.split(",").any(|coding| coding.trim().eq_ignore_ascii_case("chunked")))
+    }
+
     pub fn body_as_string(&mut self) {
         if self.mime_type.as_ref().unwrap().is_utf8() {
             self.body = Some(String::from_utf8_lossy(&self.raw_body).to_string());
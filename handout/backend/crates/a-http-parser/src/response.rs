@@ -32,6 +32,29 @@ impl Response {
         self.headers.insert(key.to_string(), value.to_string());
     }
 
+    // Adds a value to a comma separated list header such as Vary, values
+    // already present are not repeated
+    pub fn append_header(&mut self, key: &str, value: &str) {
+        let existing = self
+            .headers
+            .keys()
+            .find(|name| name.eq_ignore_ascii_case(key))
+            .cloned();
+
+        match existing.and_then(|name| self.headers.get_mut(&name)) {
+            Some(current) => {
+                if !current
+                    .split(',')
+                    .any(|item| item.trim().eq_ignore_ascii_case(value))
+                {
+                    current.push_str(", ");
+                    current.push_str(value);
+                }
+            }
+            None => self.set_header(key, value),
+        }
+    }
+
     pub fn set_cookie(&mut self, key: &str, value: &str, httponly: bool) {
         self.headers
             .insert("set-cookie".to_string(), format!("{}={}{}", key, value, if httponly { "; HttpOnly" } else { "" }));
@@ -68,3 +91,18 @@ impl Response {
         response_bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_header() {
+        let mut res = Response::new(200);
+        res.append_header("vary", "Origin");
+        res.append_header("Vary", "Accept-Encoding");
+        res.append_header("vary", "origin");
+
+        assert_eq!(res.headers.get("vary").unwrap(), "Origin, Accept-Encoding");
+    }
+}
@@ -0,0 +1,51 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::config;
+
+const AUDIT_FILE: &str = "audit.log";
+
+// Serializes appends so lines of concurrent events never interleave
+static LOCK: Mutex<()> = Mutex::new(());
+
+// Something done to an object that its protections would normally refuse
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    pub time: u64,
+    pub access_key: &'a str,
+    pub action: &'a str,
+    pub key: &'a str,
+    pub detail: String,
+}
+
+// Where audit events are appended, one JSON object per line
+pub fn path() -> PathBuf {
+    config::get().data_dir.join(AUDIT_FILE)
+}
+
+// Appends an event and syncs it to disk. Callers refuse the action when this
+// fails, nothing that must be audited happens unrecorded
+pub fn record(access_key: &str, action: &str, key: &str, detail: String) -> std::io::Result<()> {
+    let event = Event {
+        time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        access_key,
+        action,
+        key,
+        detail,
+    };
+
+    warn!("audit: {} {} {} - {}", event.access_key, event.action, event.key, event.detail);
+
+    let mut line = serde_json::to_vec(&event).map_err(std::io::Error::other)?;
+    line.push(b'\n');
+
+    let _guard = LOCK.lock().unwrap();
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path())?;
+    file.write_all(&line)?;
+    file.sync_data()
+}
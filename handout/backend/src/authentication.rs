@@ -7,7 +7,7 @@ use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use sha2::Sha384;
 
-use crate::{storable::{StorableBase, StorableJson}, TMP_PATH};
+use crate::{config, storable::StorableBase};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AuthLevel {
@@ -18,18 +18,19 @@ pub enum AuthLevel {
     Admin = 37,
 }
 
-impl AuthLevel {
-    pub fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for AuthLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             AuthLevel::Public => "Public",
             AuthLevel::Read => "Read",
             AuthLevel::ReadWrite => "ReadWrite",
             AuthLevel::Owner => "Owner",
             AuthLevel::Admin => "Admin",
-        }
-        .into()
+        })
     }
+}
 
+impl AuthLevel {
     pub fn from_string(level: &str) -> Self {
         match level {
             "Public" => AuthLevel::Public,
@@ -77,6 +78,11 @@ impl AuthContext {
         }
     }
 
+    // Signs S3 requests made with the access key, see sigv4
+    pub fn secret_key(&self) -> &str {
+        &self.secret_key
+    }
+
     pub fn update_last_used(&mut self) {
         self.last_used = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -85,9 +91,7 @@ impl AuthContext {
     }
 
     pub fn as_jwt(&self) -> String {
-        let Ok(secret_key) = std::env::var("JWT_SECRET") else {
-            panic!("JWT_SECRET not set");
-        };
+        let secret_key = &config::get().auth.jwt_secret;
 
         let key: Hmac<Sha384> = Hmac::new_from_slice(secret_key.as_bytes()).unwrap();
         let mut claims = BTreeMap::new();
@@ -97,10 +101,8 @@ impl AuthContext {
         claims.sign_with_key(&key).unwrap()
     }
 
-    pub fn id_from_jwt(token_str: &String) -> (Option<String>, Option<AuthLevel>) {
-        let Ok(secret_key) = std::env::var("JWT_SECRET") else {
-            panic!("JWT_SECRET not set");
-        };
+    pub fn id_from_jwt(token_str: &str) -> (Option<String>, Option<AuthLevel>) {
+        let secret_key = &config::get().auth.jwt_secret;
 
         let key: Hmac<Sha384> = Hmac::new_from_slice(secret_key.as_bytes()).unwrap();
         let token: Token<Header, BTreeMap<String, String>, _> =
@@ -122,16 +124,14 @@ impl AuthContext {
 
 impl StorableBase for AuthContext {
     fn base_dir() -> PathBuf {
-        format!("{}/auth", TMP_PATH).into()
+        config::get().data_dir.join("auth")
     }
 
     fn id(&self) -> &str {
-        &self.access_key.as_str()
+        self.access_key.as_str()
     }
 }
 
-impl StorableJson for AuthContext {}
-
 // Write tests to see if == works
 
 #[cfg(test)]
@@ -0,0 +1,146 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::search::Query;
+use crate::storage::{Storage, StorageError};
+
+// Body of a DELETE on the root, naming the objects to delete either by key
+// or by a prefix they share
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchDelete {
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // Only report the keys that could not be deleted
+    #[serde(default)]
+    pub quiet: bool,
+    // Report what would be deleted without deleting anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Deleted,
+    NotFound,
+    Forbidden,
+    Locked,
+    Error,
+}
+
+impl From<StorageError> for Outcome {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::NotFound => Outcome::NotFound,
+            StorageError::Forbidden => Outcome::Forbidden,
+            StorageError::Locked => Outcome::Locked,
+            _ => Outcome::Error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyResult {
+    pub key: String,
+    pub result: Outcome,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub dry_run: bool,
+    pub deleted: usize,
+    pub failed: usize,
+    // More objects share the prefix than one batch may delete
+    pub truncated: bool,
+    pub results: Vec<KeyResult>,
+}
+
+impl BatchDelete {
+    pub fn parse(body: &[u8], max_batch_size: usize) -> Result<Self, String> {
+        let batch: Self = serde_json::from_slice(body).map_err(|e| format!("Invalid batch - {}", e))?;
+
+        match (batch.keys.is_empty(), batch.prefix.as_deref()) {
+            (true, None | Some("")) => return Err("Batch names no keys".to_string()),
+            (false, Some(_)) => return Err("Batch takes either keys or a prefix".to_string()),
+            _ => {}
+        }
+
+        if batch.keys.len() > max_batch_size {
+            return Err(format!("Batch exceeds {} keys", max_batch_size));
+        }
+
+        Ok(batch)
+    }
+
+    // Deletes every named object the caller may write. A prefix only covers
+    // objects the caller can see and at most `max_batch_size` of them
+    pub async fn run(self, storage: &Storage, max_batch_size: usize) -> Report {
+        let mut report = Report {
+            dry_run: self.dry_run,
+            ..Default::default()
+        };
+
+        let keys = match &self.prefix {
+            Some(prefix) => {
+                let (objects, cursor) = storage.search(Query::prefix(prefix, max_batch_size)).await;
+                report.truncated = cursor.is_some();
+                objects
+                    .iter()
+                    .map(|metadata| storage.relative_key(&metadata.key).to_string())
+                    .collect()
+            }
+            None => self.keys,
+        };
+
+        for key in keys {
+            let result = match self.dry_run {
+                true => storage.find_deletable(&key).await.map(|_| ()),
+                false => storage.delete_object(&key).await,
+            };
+
+            let result = match result {
+                Ok(()) => Outcome::Deleted,
+                Err(error) => Outcome::from(error),
+            };
+
+            match result {
+                Outcome::Deleted => report.deleted += 1,
+                _ => report.failed += 1,
+            }
+
+            if !self.quiet || result != Outcome::Deleted {
+                report.results.push(KeyResult { key, result });
+            }
+        }
+
+        if !self.dry_run {
+            info!("Batch deleted {} object(s), {} failed", report.deleted, report.failed);
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let batch = BatchDelete::parse(br#"{"keys": ["a", "b"], "quiet": true}"#, 2).unwrap();
+        assert_eq!(batch.keys, ["a", "b"]);
+        assert!(batch.quiet && !batch.dry_run);
+
+        let batch = BatchDelete::parse(br#"{"prefix": "logs/", "dry_run": true}"#, 2).unwrap();
+        assert_eq!(batch.prefix.as_deref(), Some("logs/"));
+
+        assert!(BatchDelete::parse(br#"{"keys": ["a", "b", "c"]}"#, 2).is_err());
+        assert!(BatchDelete::parse(br#"{"keys": ["a"], "prefix": "logs/"}"#, 2).is_err());
+        assert!(BatchDelete::parse(br#"{}"#, 2).is_err());
+        assert!(BatchDelete::parse(br#"{"prefix": ""}"#, 2).is_err());
+        assert!(BatchDelete::parse(br#"{"key": "a"}"#, 2).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use a_http_parser::request::Request;
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::authentication::{AuthContext, AuthLevel};
+use crate::config::{self, CorsConfig};
+use crate::index;
+use crate::search;
+use crate::storable::{StorableBase, StorableJson};
+use crate::storage::Storage;
+
+// Served by the bucket endpoints, no object can be stored under it
+pub const PATH: &str = ".buckets";
+
+static BUCKETS: OnceLock<BucketStore> = OnceLock::new();
+
+// A namespace for objects, its objects are stored under `<name>/<key>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bucket {
+    pub name: String,
+    // Only the owner and admins store objects in the bucket
+    pub owner_id: String,
+    pub created_at: u64,
+    #[serde(flatten)]
+    pub settings: BucketSettings,
+}
+
+// What the owner of a bucket can change, sent as JSON to create or update it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BucketSettings {
+    // Used for uploads without X-Readable-By instead of the server default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_readable_by: Option<AuthLevel>,
+    pub quota: Quota,
+    // Replaces the server's CORS settings for requests to the bucket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors: Option<CorsConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quota {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_objects: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+impl Quota {
+    // Whether a bucket holding `objects` objects of `bytes` in total fits
+    pub fn allows(&self, objects: u64, bytes: u64) -> bool {
+        self.max_objects.is_none_or(|max| objects <= max) && self.max_bytes.is_none_or(|max| bytes <= max)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_objects.is_none() && self.max_bytes.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketError {
+    InvalidName,
+    // The CORS settings fail the checks the server's own settings pass
+    InvalidCors,
+    NotFound,
+    // Taken by another bucket or by objects stored before buckets existed
+    NameInUse,
+    NotEmpty,
+    Failed,
+}
+
+impl BucketError {
+    pub fn status_code(self) -> u16 {
+        match self {
+            BucketError::InvalidName | BucketError::InvalidCors => 400,
+            BucketError::NotFound => 404,
+            BucketError::NameInUse | BucketError::NotEmpty => 409,
+            BucketError::Failed => 500,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            BucketError::InvalidName => "Bucket names are 3 to 63 lowercase letters, digits and dashes",
+            BucketError::InvalidCors => "Invalid CORS settings",
+            BucketError::NotFound => "No such bucket",
+            BucketError::NameInUse => "Bucket name is already in use",
+            BucketError::NotEmpty => "Bucket is not empty",
+            BucketError::Failed => "Failed to save bucket",
+        }
+    }
+}
+
+impl StorableBase for Bucket {
+    fn base_dir() -> PathBuf {
+        config::get().data_dir.join("buckets")
+    }
+
+    fn id(&self) -> &str {
+        &self.name
+    }
+}
+
+impl StorableJson for Bucket {}
+
+impl Bucket {
+    // Key the object `key` of this bucket is stored under
+    pub fn object_key(&self, key: &str) -> String {
+        format!("{}/{}", self.name, key)
+    }
+
+    // Prefix shared by the stored keys of every object in the bucket
+    pub fn prefix(&self) -> String {
+        self.object_key("")
+    }
+
+    pub fn is_managed_by(&self, auth_context: &AuthContext) -> bool {
+        auth_context.access_level == AuthLevel::Admin || self.owner_id == auth_context.access_key
+    }
+}
+
+// Lowercase letters, digits and dashes, so a name is also a valid hostname
+// label and can't be mistaken for a reserved path
+pub fn is_valid_name(name: &str) -> bool {
+    (3..=63).contains(&name.len())
+        && name.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && !Storage::is_reserved_key(name)
+}
+
+// The bucket a virtual-host request names, `photos` for
+// `photos.cdn.example.com:8000` when `cdn.example.com` is a bucket domain
+fn name_from_host<'a>(host: &'a str, domains: &[String]) -> Option<&'a str> {
+    let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+
+    domains.iter().find_map(|domain| {
+        let name = host.strip_suffix(domain.as_str())?.strip_suffix('.')?;
+        Some(name).filter(|name| !name.is_empty() && !name.contains('.'))
+    })
+}
+
+// The bucket named by the Host header of a virtual-host request, None for
+// path-style requests
+pub fn host_bucket(host: &str) -> Option<String> {
+    let host = host.to_ascii_lowercase();
+    name_from_host(&host, &config::get().buckets.domains).map(str::to_string)
+}
+
+// Buckets are few and read on every request, they are kept in memory and
+// written to one JSON file each
+pub struct BucketStore {
+    buckets: Mutex<BTreeMap<String, Bucket>>,
+    // Held from the quota check of a write until it is stored, by bucket
+    quota_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl BucketStore {
+    async fn load() -> std::io::Result<Self> {
+        Bucket::ensure_base_dir_exists()?;
+        let mut list = Bucket::list().await?;
+        let mut buckets = BTreeMap::new();
+
+        while let Some(bucket) = list.next().await {
+            buckets.insert(bucket.name.clone(), bucket);
+        }
+
+        Ok(Self {
+            buckets: Mutex::new(buckets),
+            quota_locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Bucket> {
+        self.buckets.lock().unwrap().get(name).cloned()
+    }
+
+    // Serializes writes counted against the quota of a bucket, so two of
+    // them can't both fit in the room left for one. None without a quota
+    pub async fn lock_quota(&self, bucket: &Bucket) -> Option<OwnedMutexGuard<()>> {
+        if bucket.settings.quota.is_unlimited() {
+            return None;
+        }
+
+        let lock = self.quota_locks.lock().unwrap().entry(bucket.name.clone()).or_default().clone();
+        Some(lock.lock_owned().await)
+    }
+
+    // Buckets the caller manages, ordered by name
+    pub fn list(&self, auth_context: &AuthContext) -> Vec<Bucket> {
+        let buckets = self.buckets.lock().unwrap();
+        buckets
+            .values()
+            .filter(|bucket| bucket.is_managed_by(auth_context))
+            .cloned()
+            .collect()
+    }
+
+    // Splits a request path into the bucket it addresses and the key within
+    // it. Virtual-host requests name the bucket in Host, path-style ones in
+    // the first segment. Paths whose first segment is no bucket stay in the
+    // global namespace objects were stored in before buckets existed
+    pub fn resolve<'a>(&self, host: Option<&str>, path: &'a str) -> Result<(Option<Bucket>, &'a str), BucketError> {
+        let path = path.trim_start_matches('/');
+
+        if let Some(name) = host.and_then(host_bucket) {
+            return self.get(&name).map(|bucket| (Some(bucket), path)).ok_or(BucketError::NotFound);
+        }
+
+        let (name, key) = path.split_once('/').unwrap_or((path, ""));
+        match self.get(name) {
+            Some(bucket) => Ok((Some(bucket), key)),
+            None => Ok((None, path)),
+        }
+    }
+
+    // The CORS settings of the bucket a request addresses, None when the
+    // server's settings apply
+    pub fn cors_for(&self, request: &Request) -> Option<CorsConfig> {
+        let path = search::split_uri(&request.uri).0;
+        let (bucket, _) = self.resolve(request.headers.get("host").map(String::as_str), path).ok()?;
+        bucket?.settings.cors
+    }
+
+    // Creates a bucket, or changes the settings of one the caller manages
+    pub async fn put(&self, name: &str, settings: BucketSettings, auth_context: &AuthContext) -> Result<Bucket, BucketError> {
+        if !is_valid_name(name) {
+            return Err(BucketError::InvalidName);
+        }
+
+        if settings.cors.as_ref().is_some_and(|cors| !cors.validate().is_empty()) {
+            return Err(BucketError::InvalidCors);
+        }
+
+        let (bucket, created) = {
+            let mut buckets = self.buckets.lock().unwrap();
+
+            match buckets.get(name) {
+                Some(existing) if !existing.is_managed_by(auth_context) => return Err(BucketError::NameInUse),
+                Some(existing) => {
+                    let bucket = Bucket {
+                        settings,
+                        ..existing.clone()
+                    };
+                    (bucket, false)
+                }
+                None => {
+                    // Objects stored under the name before would end up in the bucket
+                    let index = index::get();
+                    if index.get(name).is_some() || index.prefix_totals(&format!("{}/", name)).0 > 0 {
+                        return Err(BucketError::NameInUse);
+                    }
+
+                    let bucket = Bucket {
+                        name: name.to_string(),
+                        owner_id: auth_context.access_key.clone(),
+                        created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                        settings,
+                    };
+
+                    // Reserved right away so concurrent requests can't both create it
+                    buckets.insert(name.to_string(), bucket.clone());
+                    (bucket, true)
+                }
+            }
+        };
+
+        if bucket.save().await.is_err() {
+            if created {
+                self.buckets.lock().unwrap().remove(name);
+            }
+            return Err(BucketError::Failed);
+        }
+
+        if created {
+            info!("{} created bucket {}", auth_context.access_key, name);
+        }
+
+        self.buckets.lock().unwrap().insert(name.to_string(), bucket.clone());
+        Ok(bucket)
+    }
+
+    // Deletes an empty bucket the caller manages
+    pub async fn remove(&self, name: &str, auth_context: &AuthContext) -> Result<(), BucketError> {
+        let bucket = self
+            .get(name)
+            .filter(|bucket| bucket.is_managed_by(auth_context))
+            .ok_or(BucketError::NotFound)?;
+
+        if index::get().prefix_totals(&bucket.prefix()).0 > 0 {
+            return Err(BucketError::NotEmpty);
+        }
+
+        bucket.delete().await.map_err(|_| BucketError::Failed)?;
+        self.buckets.lock().unwrap().remove(name);
+        self.quota_locks.lock().unwrap().remove(name);
+        info!("{} deleted bucket {}", auth_context.access_key, name);
+
+        Ok(())
+    }
+}
+
+// Loads the buckets, must be called once before serving requests
+pub async fn init() -> std::io::Result<()> {
+    let store = BucketStore::load().await?;
+    let _ = BUCKETS.set(store);
+    Ok(())
+}
+
+pub fn get() -> &'static BucketStore {
+    BUCKETS.get().expect("bucket store is initialized at startup")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_and_hosts() {
+        assert!(is_valid_name("team-photos"));
+        assert!(!is_valid_name("ab"));
+        assert!(!is_valid_name("Photos"));
+        assert!(!is_valid_name("-photos"));
+        assert!(!is_valid_name("photos.old"));
+        assert!(!is_valid_name("healthz"));
+
+        let domains = vec!["cdn.example.com".to_string()];
+        assert_eq!(name_from_host("photos.cdn.example.com", &domains), Some("photos"));
+        assert_eq!(name_from_host("photos.cdn.example.com:8000", &domains), Some("photos"));
+        assert_eq!(name_from_host("cdn.example.com", &domains), None);
+        assert_eq!(name_from_host("a.b.cdn.example.com", &domains), None);
+        assert_eq!(name_from_host("photoscdn.example.com", &domains), None);
+
+        let quota = Quota {
+            max_objects: Some(2),
+            max_bytes: None,
+        };
+        assert!(quota.allows(2, u64::MAX));
+        assert!(!quota.allows(3, 0));
+    }
+
+    #[tokio::test]
+    async fn test_put_checks_cors() {
+        let _guard = crate::testing::setup().await;
+        let owner = crate::testing::session(AuthLevel::ReadWrite);
+
+        let settings = BucketSettings {
+            cors: Some(CorsConfig {
+                allowed_origins: vec!["*".to_string()],
+                allow_credentials: true,
+                ..CorsConfig::default()
+            }),
+            ..BucketSettings::default()
+        };
+        let result = get().put("cors-checked", settings, &owner).await;
+        assert_eq!(result.err(), Some(BucketError::InvalidCors));
+        assert!(get().get("cors-checked").is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use a_http_parser::response::Response;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::metadata::Metadata;
+
+// Digests of the object contents, base64 encoded the way clients send them.
+// The SHA-256 digest is the etag and not stored again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksums {
+    pub md5: String,
+    pub crc32c: String,
+}
+
+impl Checksums {
+    pub fn compute(data: &[u8]) -> Self {
+        Self {
+            md5: STANDARD.encode(Md5::digest(data)),
+            crc32c: STANDARD.encode(crc32c::crc32c(data).to_be_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Algorithm {
+    Md5,
+    Sha256,
+    Crc32,
+    Crc32c,
+}
+
+impl Algorithm {
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Md5 => Md5::digest(data).to_vec(),
+            Algorithm::Sha256 => Sha256::digest(data).to_vec(),
+            Algorithm::Crc32 => {
+                let mut crc = flate2::Crc::new();
+                crc.update(data);
+                crc.sum().to_be_bytes().to_vec()
+            }
+            Algorithm::Crc32c => crc32c::crc32c(data).to_be_bytes().to_vec(),
+        }
+    }
+}
+
+// What a checksum sent with an upload describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Covers {
+    // The body as sent, before any Content-Encoding is undone (RFC 1864)
+    Body,
+    // The contents that are stored
+    Contents,
+}
+
+// Checksums a client may send with an upload, as headers or as trailers of a
+// chunked body, along with the error answered when they don't match. S3
+// clients send theirs as x-amz-checksum-*
+const EXPECTED: [(&str, Algorithm, Covers, &str); 6] = [
+    ("content-md5", Algorithm::Md5, Covers::Body, "Content-MD5 does not match the body"),
+    ("x-checksum-sha256", Algorithm::Sha256, Covers::Contents, "X-Checksum-SHA256 does not match the body"),
+    ("x-checksum-crc32c", Algorithm::Crc32c, Covers::Contents, "X-Checksum-CRC32C does not match the body"),
+    ("x-amz-checksum-sha256", Algorithm::Sha256, Covers::Contents, "x-amz-checksum-sha256 does not match the body"),
+    ("x-amz-checksum-crc32", Algorithm::Crc32, Covers::Contents, "x-amz-checksum-crc32 does not match the body"),
+    ("x-amz-checksum-crc32c", Algorithm::Crc32c, Covers::Contents, "x-amz-checksum-crc32c does not match the body"),
+];
+
+// Checks an upload against every checksum the client sent. Content-MD5
+// covers the `body` as it was sent, the others cover the `contents` after
+// any Content-Encoding has been undone
+pub fn verify(
+    headers: &HashMap<String, String>,
+    trailers: &HashMap<String, String>,
+    body: &[u8],
+    contents: &[u8],
+) -> Result<(), &'static str> {
+    for (name, algorithm, covers, message) in EXPECTED {
+        let Some(expected) = headers.get(name).or_else(|| trailers.get(name)) else {
+            continue;
+        };
+
+        let data = match covers {
+            Covers::Body => body,
+            Covers::Contents => contents,
+        };
+
+        match STANDARD.decode(expected.trim()) {
+            Ok(expected) if expected == algorithm.digest(data) => {}
+            _ => return Err(message),
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the checksums of an object. Content-MD5 describes the body, so it is
+// only sent along with the complete uncompressed contents
+pub fn set_headers(res: &mut Response, metadata: &Metadata, content_md5: bool) {
+    if metadata.is_customer_encrypted() {
+        return;
+    }
+
+    if let Ok(sha256) = hex::decode(&metadata.etag) {
+        res.set_header("x-checksum-sha256", &STANDARD.encode(sha256));
+    }
+
+    if let Some(checksums) = &metadata.checksums {
+        res.set_header("x-checksum-crc32c", &checksums.crc32c);
+        if content_md5 {
+            res.set_header("content-md5", &checksums.md5);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let data = b"hello world";
+        let checksums = Checksums::compute(data);
+        // Known CRC32C of "hello world"
+        assert_eq!(checksums.crc32c, STANDARD.encode(0xc99465aa_u32.to_be_bytes()));
+
+        let headers = HashMap::from([("content-md5".to_string(), checksums.md5.clone())]);
+        let trailers = HashMap::from([(
+            "x-checksum-sha256".to_string(),
+            STANDARD.encode(Sha256::digest(data)),
+        )]);
+        assert!(verify(&headers, &trailers, data, data).is_ok());
+        assert_eq!(
+            verify(&headers, &trailers, b"hello there", b"hello there"),
+            Err("Content-MD5 does not match the body")
+        );
+
+        // Known CRC32 of "hello world", as S3 clients send it
+        let headers = HashMap::from([(
+            "x-amz-checksum-crc32".to_string(),
+            STANDARD.encode(0x0d4a1185_u32.to_be_bytes()),
+        )]);
+        assert!(verify(&headers, &HashMap::new(), data, data).is_ok());
+
+        let trailers = HashMap::from([("x-checksum-crc32c".to_string(), "not base64!".to_string())]);
+        assert!(verify(&HashMap::new(), &trailers, data, data).is_err());
+    }
+
+    #[test]
+    fn test_verify_encoded_upload() {
+        let contents = b"hello world";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, contents).unwrap();
+        let body = encoder.finish().unwrap();
+
+        // Content-MD5 is taken over the gzip body, the others over the contents
+        let headers = HashMap::from([
+            ("content-md5".to_string(), STANDARD.encode(Md5::digest(&body))),
+            ("x-checksum-sha256".to_string(), STANDARD.encode(Sha256::digest(contents))),
+        ]);
+        assert!(verify(&headers, &HashMap::new(), &body, contents).is_ok());
+
+        let headers = HashMap::from([("content-md5".to_string(), STANDARD.encode(Md5::digest(contents)))]);
+        assert_eq!(
+            verify(&headers, &HashMap::new(), &body, contents),
+            Err("Content-MD5 does not match the body")
+        );
+    }
+}
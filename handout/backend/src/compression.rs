@@ -0,0 +1,364 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use a_http_parser::http::MimeType;
+use a_http_parser::response::Response;
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+
+use crate::config::{self, CompressionConfig};
+use crate::metrics::METRICS;
+
+// Window size brotli compresses with, 2^22 bytes is what most encoders use
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+// Text based types shrink well, media types are already compressed
+pub fn is_compressible(mime_type: &str) -> bool {
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+
+    match MimeType::from_str(essence) {
+        MimeType::ImageSvg | MimeType::ApplicationXml => true,
+        mime_type => mime_type.is_utf8(),
+    }
+}
+
+// Whether an Accept-Encoding header allows `coding`
+pub fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let quality = quality(parts);
+
+        if name.eq_ignore_ascii_case(coding) {
+            return quality > 0.0;
+        } else if name == "*" {
+            wildcard = Some(quality);
+        }
+    }
+
+    wildcard.is_some_and(|quality| quality > 0.0)
+}
+
+fn quality<'a>(mut params: impl Iterator<Item = &'a str>) -> f32 {
+    params
+        .find_map(|param| param.trim().strip_prefix("q="))
+        .and_then(|value| value.trim().parse::<f32>().ok())
+        .unwrap_or(1.0)
+}
+
+// Picks the encoding the client prefers from an Accept-Encoding header,
+// brotli wins ties. None means the body is sent as is
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut brotli = None;
+    let mut wildcard = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let quality = quality(parts);
+
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(quality),
+            "br" => brotli = Some(quality),
+            "*" => wildcard = Some(quality),
+            _ => {}
+        }
+    }
+
+    let gzip = gzip.or(wildcard).unwrap_or(0.0);
+    let brotli = brotli.or(wildcard).unwrap_or(0.0);
+
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+// The encoding to send a body of `size` bytes in. Also marks the response as
+// varying by Accept-Encoding whenever the answer could have been different
+pub fn select(
+    accept_encoding: Option<&String>,
+    mime_type: &str,
+    size: u64,
+    res: &mut Response,
+) -> Option<Encoding> {
+    let config = &config::get().compression;
+
+    if !config.enabled || !is_compressible(mime_type) {
+        return None;
+    }
+
+    res.append_header("vary", "Accept-Encoding");
+
+    if size < config.min_size {
+        return None;
+    }
+
+    negotiate(accept_encoding?)
+}
+
+// Compresses the in memory body of `res` if the client accepts it
+pub fn compress_body(accept_encoding: Option<&String>, res: &mut Response) {
+    let mime_type = res.headers.get("content-type").cloned().unwrap_or_default();
+    let Some(encoding) = select(accept_encoding, &mime_type, res.body.len() as u64, res) else {
+        return;
+    };
+
+    let mut compressor = Compressor::new(encoding, &config::get().compression);
+    let compressed = compressor
+        .update(&res.body)
+        .and_then(|mut output| {
+            output.extend(compressor.finish()?);
+            Ok(output)
+        });
+
+    if let Ok(compressed) = compressed {
+        METRICS.response_compressed(encoding.to_str());
+        res.set_header("content-length", &compressed.len().to_string());
+        res.set_header("content-encoding", encoding.to_str());
+        res.body = compressed;
+    }
+}
+
+enum Inner {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+// Compresses a body chunk by chunk, output is handed back as soon as the
+// encoder produces it
+pub struct Compressor {
+    inner: Inner,
+}
+
+impl Compressor {
+    pub fn new(encoding: Encoding, config: &CompressionConfig) -> Self {
+        let inner = match encoding {
+            Encoding::Gzip => Inner::Gzip(GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(config.gzip_level),
+            )),
+            Encoding::Brotli => Inner::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER,
+                config.brotli_quality,
+                BROTLI_WINDOW,
+            ))),
+        };
+
+        Self { inner }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        match &mut self.inner {
+            Inner::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Inner::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self.inner {
+            Inner::Gzip(encoder) => encoder.finish(),
+            Inner::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+// Undoes the Content-Encoding of an upload, codings are listed in the order
+// they were applied. Fails with the status to answer with when the coding is
+// unknown, the body is corrupt or it decodes to more than `limit` bytes
+pub fn decode(content_encoding: &str, data: &[u8], limit: usize) -> Result<Vec<u8>, (u16, &'static str)> {
+    let mut data = data.to_vec();
+
+    for coding in content_encoding.rsplit(',').map(str::trim) {
+        let reader: Box<dyn Read + '_> = match coding.to_ascii_lowercase().as_str() {
+            "identity" | "" => continue,
+            "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(&data[..])),
+            "br" => Box::new(brotli::Decompressor::new(&data[..], BROTLI_BUFFER)),
+            "zstd" => Box::new(
+                zstd::stream::read::Decoder::new(&data[..]).map_err(|_| (400, "Invalid encoded body"))?,
+            ),
+            _ => return Err((415, "Unsupported Content-Encoding")),
+        };
+
+        // Read one byte past the limit to tell a body at the limit from a larger one
+        let mut decoded = Vec::new();
+        reader
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|_| (400, "Invalid encoded body"))?;
+
+        if decoded.len() > limit {
+            return Err((413, "Payload Too Large"));
+        }
+
+        data = decoded;
+    }
+
+    Ok(data)
+}
+
+// Encoding objects are stored with at rest, when enabled
+pub const STORED_ENCODING: &str = "zstd";
+
+// Compresses an object for storage, None when that would not save space
+pub fn compress_at_rest(data: &[u8], mime_type: &str) -> Option<Vec<u8>> {
+    let config = &config::get().compression;
+
+    if !config.store_compressed || !is_compressible(mime_type) || (data.len() as u64) < config.min_size {
+        return None;
+    }
+
+    zstd::encode_all(data, config.zstd_level)
+        .ok()
+        .filter(|compressed| compressed.len() < data.len())
+}
+
+// Reverses the compression of a stored object chunk by chunk
+pub struct Decompressor {
+    decoder: zstd::stream::write::Decoder<'static, Vec<u8>>,
+}
+
+impl Decompressor {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            decoder: zstd::stream::write::Decoder::new(Vec::new())?,
+        })
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.decoder.write_all(chunk)?;
+        self.decoder.flush()?;
+        Ok(std::mem::take(self.decoder.get_mut()))
+    }
+}
+
+// Compressed copies of objects, keyed by content so objects with the same
+// contents share them and changed objects never see a stale copy
+pub fn variants_dir() -> PathBuf {
+    config::get().data_dir.join("variants")
+}
+
+pub fn variant_path(etag: &str, encoding: Encoding) -> PathBuf {
+    variants_dir().join(format!("{}.{}", etag, encoding.extension()))
+}
+
+// Deletes cached variants of contents no object has anymore and leftover
+// temporary files. Files younger than `min_age` may still be being written
+pub async fn remove_stale_variants(
+    etags: &HashSet<String>,
+    min_age: Duration,
+) -> std::io::Result<usize> {
+    let mut entries = match tokio::fs::read_dir(variants_dir()).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error),
+    };
+    let mut removed = 0;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_str().unwrap_or_default();
+        let etag = name.split('.').next().unwrap_or_default();
+
+        // Temporary files of a crashed transfer are never completed
+        if etags.contains(etag) && !name.ends_with(".tmp") {
+            continue;
+        }
+
+        let age = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .unwrap_or_default();
+
+        if age >= min_age && tokio::fs::remove_file(entry.path()).await.is_ok() {
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_and_roundtrip() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, br;q=0"), None);
+        assert_eq!(negotiate("*;q=0.1, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity"), None);
+        assert!(accepts("gzip, zstd;q=0.5", "zstd"));
+        assert!(!accepts("*, zstd;q=0", "zstd"));
+
+        let config = CompressionConfig::default();
+        let data = "hello compression ".repeat(200);
+
+        let mut compressor = Compressor::new(Encoding::Gzip, &config);
+        let mut compressed = Vec::new();
+        for chunk in data.as_bytes().chunks(100) {
+            compressed.extend(compressor.update(chunk).unwrap());
+        }
+        compressed.extend(compressor.finish().unwrap());
+        assert!(compressed.len() < data.len());
+
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&compressed[..]), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let mut compressor = Compressor::new(Encoding::Brotli, &config);
+        let mut compressed = compressor.update(data.as_bytes()).unwrap();
+        compressed.extend(compressor.finish().unwrap());
+
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut brotli::Decompressor::new(&compressed[..], 4096), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(decode("br", &compressed, data.len()).unwrap(), data.as_bytes());
+        assert_eq!(decode("br", &compressed, 100).unwrap_err().0, 413);
+        assert_eq!(decode("compress", &compressed, 100).unwrap_err().0, 415);
+    }
+}
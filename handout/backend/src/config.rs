@@ -0,0 +1,1266 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::authentication::AuthLevel;
+use crate::connections::ConnectionLimits;
+use crate::encryption::{self, Algorithm};
+use crate::proxy::{Cidr, TrustedProxies};
+
+const DEFAULT_CONFIG_PATH: &str = "/etc/a-bucket/config.toml";
+const CONFIG_PATH_ENV: &str = "A_BUCKET_CONFIG";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Addresses the CDN is served on
+    pub listen: Vec<String>,
+    // Address of the admin listener (metrics, health), empty to disable it
+    pub admin_listen: String,
+    // Root directory of all persisted state
+    pub data_dir: PathBuf,
+    pub limits: LimitsConfig,
+    pub timeouts: TimeoutsConfig,
+    pub sessions: SessionsConfig,
+    pub uploads: UploadsConfig,
+    pub logging: LoggingConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub proxy: ProxyConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub maintenance: MaintenanceConfig,
+    pub cors: CorsConfig,
+    pub compression: CompressionConfig,
+    pub encryption: EncryptionConfig,
+    pub trash: TrashConfig,
+    pub locks: LocksConfig,
+    pub buckets: BucketsConfig,
+    pub s3: S3Config,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    // Largest request body accepted, in bytes
+    pub max_body_size: usize,
+    // Largest request line plus headers accepted, in bytes. Also caps a
+    // chunk size line and the trailers of a chunked body
+    pub max_header_size: usize,
+    // Keys a single batch delete may remove
+    pub max_batch_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub idle_secs: u64,
+    pub header_secs: u64,
+    pub body_secs: u64,
+    pub write_secs: u64,
+    pub shutdown_grace_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    // Sessions unused for longer than this are removed
+    pub ttl_secs: u64,
+    // Anonymous sessions that may exist at once, creation fails beyond it
+    pub max_anonymous: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsConfig {
+    // Used when an upload has no X-Readable-By header
+    pub default_readable_by: AuthLevel,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LevelFilter,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // HMAC key used to sign session JWTs
+    pub jwt_secret: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // Addresses the CDN is served on over TLS
+    pub listen: Vec<String>,
+    // Seconds between checks for changed certificate files, 0 disables reloading
+    pub reload_interval_secs: u64,
+    // The first certificate is used for clients that send no or an unknown SNI
+    pub certificates: Vec<CertificateConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    // Names selected through SNI, `*.example.com` matches a single label
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    // PEM encoded certificate chain, leaf first
+    pub cert: PathBuf,
+    // PEM encoded private key
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    // Addresses or CIDR ranges of proxies whose forwarding headers are trusted
+    pub trusted: Vec<String>,
+    // Expect a PROXY protocol (v1 or v2) header from trusted peers
+    pub proxy_protocol: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    pub enabled: bool,
+    // Shared by every request from a client address, or from an IPv6 /64,
+    // this is also the only budget that applies to session creation
+    pub per_ip: ClassLimits,
+    // Budgets of a single session, chosen by its access level
+    pub per_key: LevelLimits,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LevelLimits {
+    #[serde(rename = "Public")]
+    pub public: ClassLimits,
+    #[serde(rename = "Read")]
+    pub read: ClassLimits,
+    #[serde(rename = "ReadWrite")]
+    pub read_write: ClassLimits,
+    #[serde(rename = "Owner")]
+    pub owner: ClassLimits,
+    #[serde(rename = "Admin")]
+    pub admin: ClassLimits,
+}
+
+// Tables are replaced as a whole so a partial table can't silently lift the
+// limits it leaves out
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassLimits {
+    pub read: BucketLimit,
+    pub write: BucketLimit,
+    pub list: BucketLimit,
+    // Only used for per_ip, sessions are created before a key exists
+    #[serde(default)]
+    pub session: BucketLimit,
+}
+
+// A token bucket refilled at `per_minute` tokens a minute holding at most
+// `burst` tokens. A rate of zero disables the limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketLimit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+// Seconds between runs of each maintenance task, 0 disables the task
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    pub sessions_interval_secs: u64,
+    pub expired_objects_interval_secs: u64,
+    pub orphaned_blobs_interval_secs: u64,
+    pub fsck_interval_secs: u64,
+    pub trash_interval_secs: u64,
+    pub multipart_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // Origins such as `https://app.example.com`, `*` allows any origin and
+    // an empty list disables CORS
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    // Request headers browsers may send, matched case insensitively
+    pub allowed_headers: Vec<String>,
+    // Response headers scripts may read
+    pub exposed_headers: Vec<String>,
+    // Lets browsers send the session cookie with cross origin requests
+    pub allow_credentials: bool,
+    // Seconds browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+// Compression of text responses negotiated through Accept-Encoding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // Bodies smaller than this many bytes are sent as is
+    pub min_size: u64,
+    // 0 (fastest) to 9 (smallest)
+    pub gzip_level: u32,
+    // 0 (fastest) to 11 (smallest)
+    pub brotli_quality: u32,
+    // Keeps compressed copies of objects in data_dir/variants so each
+    // version of an object is only compressed once
+    pub cache_variants: bool,
+    // Stores text objects zstd compressed, they are decompressed on the way
+    // out for clients that don't accept zstd
+    pub store_compressed: bool,
+    // 1 (fastest) to 19 (smallest)
+    pub zstd_level: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    // Encrypts new uploads, encrypted objects are readable either way
+    pub enabled: bool,
+    pub algorithm: Algorithm,
+    // 32 byte key encoded as base64 that wraps the per object data keys
+    pub master_key: String,
+    // Earlier master keys, data keys they wrap stay readable until
+    // `a-bucket rotate-keys` rewrapped them with the current key
+    pub previous_master_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BucketsConfig {
+    // Hosts such as `cdn.example.com` whose subdomains name a bucket, so
+    // `photos.cdn.example.com/a.png` is `a.png` in the bucket `photos`
+    pub domains: Vec<String>,
+    // Refuses uploads outside of a bucket, objects already stored stay readable
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    // Address of the S3 compatible listener, empty to disable it
+    pub listen: String,
+    // Largest object a multipart upload can complete, each part is limited
+    // by limits.max_body_size like any request
+    pub max_object_size: u64,
+    // Seconds an unfinished multipart upload is kept before it is aborted
+    pub upload_expiry_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    // Seconds deleted objects can be restored for, 0 deletes them right away
+    pub retention_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockMode {
+    // Admins may bypass a retention with X-Bypass-Governance, audited
+    Governance,
+    // Nobody can shorten or bypass a retention
+    Compliance,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocksConfig {
+    pub mode: LockMode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec!["0.0.0.0:8000".to_string()],
+            admin_listen: "0.0.0.0:9000".to_string(),
+            data_dir: PathBuf::from("/tmp"),
+            limits: LimitsConfig::default(),
+            timeouts: TimeoutsConfig::default(),
+            sessions: SessionsConfig::default(),
+            uploads: UploadsConfig::default(),
+            logging: LoggingConfig::default(),
+            auth: AuthConfig::default(),
+            tls: TlsConfig::default(),
+            proxy: ProxyConfig::default(),
+            rate_limits: RateLimitsConfig::default(),
+            maintenance: MaintenanceConfig::default(),
+            cors: CorsConfig::default(),
+            compression: CompressionConfig::default(),
+            encryption: EncryptionConfig::default(),
+            trash: TrashConfig::default(),
+            locks: LocksConfig::default(),
+            buckets: BucketsConfig::default(),
+            s3: S3Config::default(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_connections_per_ip: 64,
+            max_body_size: 100 * 1024 * 1024,
+            max_header_size: 64 * 1024,
+            max_batch_size: 1000,
+        }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            idle_secs: 10,
+            header_secs: 10,
+            body_secs: 30,
+            write_secs: 30,
+            shutdown_grace_secs: 30,
+        }
+    }
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 30 * 60,
+            max_anonymous: 100_000,
+        }
+    }
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        // This is a CDN so we should make it public by default
+        Self {
+            default_readable_by: AuthLevel::Public,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            reload_interval_secs: 30,
+            certificates: Vec::new(),
+        }
+    }
+}
+
+impl BucketLimit {
+    const fn new(per_minute: u32, burst: u32) -> Self {
+        Self { per_minute, burst }
+    }
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            per_ip: ClassLimits {
+                read: BucketLimit::new(1200, 200),
+                write: BucketLimit::new(240, 60),
+                list: BucketLimit::new(60, 10),
+                session: BucketLimit::new(30, 10),
+            },
+            per_key: LevelLimits::default(),
+        }
+    }
+}
+
+impl Default for LevelLimits {
+    fn default() -> Self {
+        let anonymous = ClassLimits {
+            read: BucketLimit::new(600, 100),
+            write: BucketLimit::new(60, 20),
+            list: BucketLimit::new(30, 5),
+            session: BucketLimit::default(),
+        };
+
+        let trusted = ClassLimits {
+            read: BucketLimit::new(1200, 200),
+            write: BucketLimit::new(240, 60),
+            list: BucketLimit::new(60, 10),
+            session: BucketLimit::default(),
+        };
+
+        Self {
+            public: anonymous,
+            read: anonymous,
+            read_write: trusted,
+            owner: trusted,
+            // Admins are never limited
+            admin: ClassLimits::default(),
+        }
+    }
+}
+
+impl LevelLimits {
+    pub fn for_level(&self, level: &AuthLevel) -> &ClassLimits {
+        match level {
+            AuthLevel::Public => &self.public,
+            AuthLevel::Read => &self.read,
+            AuthLevel::ReadWrite => &self.read_write,
+            AuthLevel::Owner => &self.owner,
+            AuthLevel::Admin => &self.admin,
+        }
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            sessions_interval_secs: 60,
+            expired_objects_interval_secs: 60,
+            orphaned_blobs_interval_secs: 60 * 60,
+            fsck_interval_secs: 24 * 60 * 60,
+            trash_interval_secs: 60 * 60,
+            multipart_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            listen: String::new(),
+            max_object_size: 1024 * 1024 * 1024,
+            upload_expiry_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for LocksConfig {
+    fn default() -> Self {
+        Self {
+            mode: LockMode::Governance,
+        }
+    }
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            retention_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: strings(&["GET", "HEAD", "PUT", "POST", "PATCH", "DELETE", "LIST"]),
+            allowed_headers: strings(&["Content-Type", "Content-Encoding", "Range", "X-Readable-By", "X-Expires-After", "X-Encryption-Key", "X-Encryption-Key-MD5", "X-Encryption-Algorithm", "Content-MD5", "X-Checksum-SHA256", "X-Checksum-CRC32C", "X-Lock-Retain-Until", "X-Legal-Hold", "X-Bypass-Governance"]),
+            exposed_headers: strings(&["ETag", "Last-Modified", "Content-Length", "Content-Range", "Content-Disposition", "Retry-After", "X-Encryption-Key-MD5", "X-Encryption-Algorithm", "Content-MD5", "X-Checksum-SHA256", "X-Checksum-CRC32C", "X-Next-Cursor", "X-Lock-Retain-Until", "X-Legal-Hold"]),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl CorsConfig {
+    // Problems with the settings, also checked for the settings of buckets
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        for method in &self.allowed_methods {
+            if a_http_parser::http::Method::from_str(method).is_err() {
+                errors.push(format!("allowed_methods: '{}' is not a known method", method));
+            }
+        }
+
+        if self.allow_credentials && self.allowed_origins.iter().any(|o| o == "*") {
+            errors.push("allow_credentials: cannot be used with the '*' origin".to_string());
+        }
+
+        errors
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: 1024,
+            gzip_level: 6,
+            brotli_quality: 5,
+            cache_variants: true,
+            store_compressed: false,
+            zstd_level: 3,
+        }
+    }
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithm: Algorithm::Aes256Gcm,
+            master_key: String::new(),
+            previous_master_keys: Vec::new(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+// A single setting that can be overridden from the environment and the
+// command line, both use the same parser
+struct Setting {
+    env: &'static str,
+    flag: &'static str,
+    help: &'static str,
+    apply: fn(&mut Config, &str) -> Result<(), String>,
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("invalid value '{}'", value))
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting {
+        env: "A_BUCKET_LISTEN",
+        flag: "--listen",
+        help: "comma separated addresses to serve the CDN on",
+        apply: |config, value| {
+            config.listen = value.split(',').map(|address| address.trim().to_string()).collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_TLS_LISTEN",
+        flag: "--tls-listen",
+        help: "comma separated addresses to serve the CDN on over TLS",
+        apply: |config, value| {
+            config.tls.listen = value
+                .split(',')
+                .map(|address| address.trim().to_string())
+                .filter(|address| !address.is_empty())
+                .collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_TRUSTED_PROXIES",
+        flag: "--trusted-proxies",
+        help: "comma separated addresses or CIDR ranges of trusted proxies",
+        apply: |config, value| {
+            config.proxy.trusted = value
+                .split(',')
+                .map(|range| range.trim().to_string())
+                .filter(|range| !range.is_empty())
+                .collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_PROXY_PROTOCOL",
+        flag: "--proxy-protocol",
+        help: "expect a PROXY protocol header from trusted proxies (true, false)",
+        apply: |config, value| {
+            config.proxy.proxy_protocol = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_ADMIN_LISTEN",
+        flag: "--admin-listen",
+        help: "address of the admin listener, empty to disable",
+        apply: |config, value| {
+            config.admin_listen = value.to_string();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_S3_LISTEN",
+        flag: "--s3-listen",
+        help: "address of the S3 compatible listener, empty to disable",
+        apply: |config, value| {
+            config.s3.listen = value.to_string();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_DATA_DIR",
+        flag: "--data-dir",
+        help: "directory holding objects, metadata and sessions",
+        apply: |config, value| {
+            config.data_dir = PathBuf::from(value);
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_MAX_CONNECTIONS",
+        flag: "--max-connections",
+        help: "connections served at once",
+        apply: |config, value| {
+            config.limits.max_connections = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_MAX_CONNECTIONS_PER_IP",
+        flag: "--max-connections-per-ip",
+        help: "connections a single address may hold",
+        apply: |config, value| {
+            config.limits.max_connections_per_ip = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_MAX_BODY_SIZE",
+        flag: "--max-body-size",
+        help: "largest accepted request body in bytes",
+        apply: |config, value| {
+            config.limits.max_body_size = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_MAX_HEADER_SIZE",
+        flag: "--max-header-size",
+        help: "largest accepted request line and headers in bytes",
+        apply: |config, value| {
+            config.limits.max_header_size = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_MAX_BATCH_SIZE",
+        flag: "--max-batch-size",
+        help: "keys a single batch delete may remove",
+        apply: |config, value| {
+            config.limits.max_batch_size = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_IDLE_TIMEOUT",
+        flag: "--idle-timeout",
+        help: "seconds to wait for the first byte of a request",
+        apply: |config, value| {
+            config.timeouts.idle_secs = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_HEADER_TIMEOUT",
+        flag: "--header-timeout",
+        help: "seconds allowed to receive the request headers",
+        apply: |config, value| {
+            config.timeouts.header_secs = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_BODY_TIMEOUT",
+        flag: "--body-timeout",
+        help: "seconds allowed between two reads of the request body",
+        apply: |config, value| {
+            config.timeouts.body_secs = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_WRITE_TIMEOUT",
+        flag: "--write-timeout",
+        help: "seconds allowed for a single write to the client",
+        apply: |config, value| {
+            config.timeouts.write_secs = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_SHUTDOWN_GRACE_PERIOD",
+        flag: "--shutdown-grace-period",
+        help: "seconds in-flight requests get to finish on shutdown",
+        apply: |config, value| {
+            config.timeouts.shutdown_grace_secs = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_SESSION_TTL",
+        flag: "--session-ttl",
+        help: "seconds before an unused session is removed",
+        apply: |config, value| {
+            config.sessions.ttl_secs = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_MAX_ANONYMOUS_SESSIONS",
+        flag: "--max-anonymous-sessions",
+        help: "anonymous sessions that may exist at once",
+        apply: |config, value| {
+            config.sessions.max_anonymous = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_DEFAULT_READABLE_BY",
+        flag: "--default-readable-by",
+        help: "read access of uploads without X-Readable-By (Public, Read, Owner)",
+        apply: |config, value| {
+            config.uploads.default_readable_by = match value {
+                "Public" => AuthLevel::Public,
+                "Read" => AuthLevel::Read,
+                "Owner" => AuthLevel::Owner,
+                _ => return Err(format!("invalid value '{}'", value)),
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_RATE_LIMITS",
+        flag: "--rate-limits",
+        help: "enable per address and per session rate limits (true, false)",
+        apply: |config, value| {
+            config.rate_limits.enabled = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_CORS_ORIGINS",
+        flag: "--cors-origins",
+        help: "comma separated origins allowed to make cross origin requests",
+        apply: |config, value| {
+            config.cors.allowed_origins = value
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_COMPRESSION",
+        flag: "--compression",
+        help: "compress text responses for clients that accept it (true, false)",
+        apply: |config, value| {
+            config.compression.enabled = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_ENCRYPTION",
+        flag: "--encryption",
+        help: "encrypt new uploads at rest (true, false)",
+        apply: |config, value| {
+            config.encryption.enabled = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_MASTER_KEY",
+        flag: "--master-key",
+        help: "base64 encoded 32 byte key that wraps the data keys of encrypted objects",
+        apply: |config, value| {
+            config.encryption.master_key = value.to_string();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_TRASH_RETENTION",
+        flag: "--trash-retention",
+        help: "seconds deleted objects can be restored for, 0 disables the trash",
+        apply: |config, value| {
+            config.trash.retention_secs = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_LOCK_MODE",
+        flag: "--lock-mode",
+        help: "governance lets admins bypass object locks, compliance lets nobody",
+        apply: |config, value| {
+            config.locks.mode = match value {
+                "governance" => LockMode::Governance,
+                "compliance" => LockMode::Compliance,
+                _ => return Err(format!("invalid value '{}'", value)),
+            };
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_BUCKET_DOMAINS",
+        flag: "--bucket-domains",
+        help: "comma separated hosts whose subdomains name buckets",
+        apply: |config, value| {
+            config.buckets.domains = value
+                .split(',')
+                .map(|domain| domain.trim().to_ascii_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect();
+            Ok(())
+        },
+    },
+    Setting {
+        env: "A_BUCKET_LOG_LEVEL",
+        flag: "--log-level",
+        help: "one of off, error, warn, info, debug, trace",
+        apply: |config, value| {
+            config.logging.level = parse(value)?;
+            Ok(())
+        },
+    },
+    Setting {
+        env: "JWT_SECRET",
+        flag: "--jwt-secret",
+        help: "key used to sign session tokens",
+        apply: |config, value| {
+            config.auth.jwt_secret = value.to_string();
+            Ok(())
+        },
+    },
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    #[default]
+    Serve,
+    RotateKeys,
+    Fsck,
+}
+
+impl Command {
+    const ALL: [(&'static str, Command, &'static str); 3] = [
+        ("serve", Command::Serve, "run the server (default)"),
+        ("rotate-keys", Command::RotateKeys, "rewrap data keys with the current master key and exit"),
+        ("fsck", Command::Fsck, "check objects for consistency, print a JSON report and exit"),
+    ];
+}
+
+#[derive(Debug, Default)]
+pub struct CliOptions {
+    pub command: Command,
+    pub config_path: Option<PathBuf>,
+    pub print_config: bool,
+    // Let fsck repair what it finds
+    pub repair: bool,
+    pub help: bool,
+    overrides: Vec<(&'static Setting, String)>,
+}
+
+impl std::fmt::Debug for Setting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.flag)
+    }
+}
+
+impl CliOptions {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        while let Some(arg) = args.next() {
+            // Accept both `--flag value` and `--flag=value`
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+
+            match flag.as_str() {
+                "--print-config" => options.print_config = true,
+                "--repair" => options.repair = true,
+                "--help" | "-h" => options.help = true,
+                command if !command.starts_with('-') => {
+                    options.command = Command::ALL
+                        .iter()
+                        .find(|(name, _, _)| *name == command)
+                        .map(|(_, command, _)| *command)
+                        .ok_or_else(|| format!("unknown command '{}'", arg))?;
+                }
+                _ => {
+                    let mut value = || {
+                        inline_value
+                            .clone()
+                            .or_else(|| args.next())
+                            .ok_or_else(|| format!("{} requires a value", flag))
+                    };
+
+                    if flag == "--config" {
+                        options.config_path = Some(PathBuf::from(value()?));
+                    } else if let Some(setting) = SETTINGS.iter().find(|s| s.flag == flag) {
+                        options.overrides.push((setting, value()?));
+                    } else {
+                        return Err(format!("unknown argument '{}'", arg));
+                    }
+                }
+            }
+        }
+
+        Ok(options)
+    }
+
+    pub fn usage() -> String {
+        let mut usage = String::from(
+            "Usage: a-bucket [COMMAND] [OPTIONS]\n\n\
+             Commands:\n",
+        );
+
+        for (name, _, help) in Command::ALL {
+            usage.push_str(&format!("  {:<22} {}\n", name, help));
+        }
+
+        usage.push_str(
+            "\nOptions:\n  \
+             --config <path>        TOML configuration file (env A_BUCKET_CONFIG)\n  \
+             --print-config         print the effective configuration and exit\n  \
+             --repair               quarantine corrupt records and remove orphans (fsck)\n  \
+             --help                 print this message\n",
+        );
+
+        for setting in SETTINGS {
+            usage.push_str(&format!(
+                "  {} <value>\n        {} (env {})\n",
+                setting.flag, setting.help, setting.env
+            ));
+        }
+
+        usage
+    }
+}
+
+impl Config {
+    // Builds the configuration from defaults, the TOML file, the
+    // environment and the command line, in increasing order of precedence
+    pub fn load(options: &CliOptions) -> Result<Self, Vec<String>> {
+        let explicit_path = options
+            .config_path
+            .clone()
+            .or_else(|| std::env::var(CONFIG_PATH_ENV).ok().map(PathBuf::from));
+
+        let mut config = match &explicit_path {
+            Some(path) => Self::from_file(path).map_err(|error| vec![error])?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH)).map_err(|error| vec![error])?
+            }
+            None => Self::default(),
+        };
+
+        let mut errors = Vec::new();
+
+        for setting in SETTINGS {
+            if let Ok(value) = std::env::var(setting.env) {
+                if let Err(error) = (setting.apply)(&mut config, &value) {
+                    errors.push(format!("{}: {}", setting.env, error));
+                }
+            }
+        }
+
+        for (setting, value) in &options.overrides {
+            if let Err(error) = (setting.apply)(&mut config, value) {
+                errors.push(format!("{}: {}", setting.flag, error));
+            }
+        }
+
+        errors.extend(config.validate());
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("{}: failed to read - {}", path.display(), e))?;
+
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn validate(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.listen.is_empty() && self.tls.listen.is_empty() {
+            errors.push("listen: at least one plain or TLS address is required".to_string());
+        }
+
+        let admin = Some(&self.admin_listen).filter(|address| !address.is_empty());
+        let s3 = Some(&self.s3.listen).filter(|address| !address.is_empty());
+        for address in self.listen.iter().chain(&self.tls.listen).chain(admin).chain(s3) {
+            if address.parse::<SocketAddr>().is_err() {
+                errors.push(format!("listen: '{}' is not a valid socket address", address));
+            }
+        }
+
+        if !self.tls.listen.is_empty() && self.tls.certificates.is_empty() {
+            errors.push("tls.certificates: required when tls.listen is set".to_string());
+        }
+
+        for certificate in &self.tls.certificates {
+            for path in [&certificate.cert, &certificate.key] {
+                if !path.is_file() {
+                    errors.push(format!("tls.certificates: {} does not exist", path.display()));
+                }
+            }
+        }
+
+        for range in &self.proxy.trusted {
+            if Cidr::parse(range).is_none() {
+                errors.push(format!("proxy.trusted: '{}' is not an address or CIDR range", range));
+            }
+        }
+
+        if self.limits.max_connections == 0 {
+            errors.push("limits.max_connections: must be greater than zero".to_string());
+        }
+
+        if self.sessions.max_anonymous == 0 {
+            errors.push("sessions.max_anonymous: must be greater than zero".to_string());
+        }
+
+        if self.limits.max_connections_per_ip == 0 {
+            errors.push("limits.max_connections_per_ip: must be greater than zero".to_string());
+        }
+
+        if self.limits.max_batch_size == 0 {
+            errors.push("limits.max_batch_size: must be greater than zero".to_string());
+        }
+
+        if self.s3.max_object_size == 0 {
+            errors.push("s3.max_object_size: must be greater than zero".to_string());
+        }
+
+        for (name, value) in [
+            ("timeouts.idle_secs", self.timeouts.idle_secs),
+            ("timeouts.header_secs", self.timeouts.header_secs),
+            ("timeouts.body_secs", self.timeouts.body_secs),
+            ("timeouts.write_secs", self.timeouts.write_secs),
+            ("sessions.ttl_secs", self.sessions.ttl_secs),
+        ] {
+            if value == 0 {
+                errors.push(format!("{}: must be greater than zero", name));
+            }
+        }
+
+        let levels = &self.rate_limits.per_key;
+        for (name, limits) in [
+            ("per_ip", &self.rate_limits.per_ip),
+            ("per_key.Public", &levels.public),
+            ("per_key.Read", &levels.read),
+            ("per_key.ReadWrite", &levels.read_write),
+            ("per_key.Owner", &levels.owner),
+            ("per_key.Admin", &levels.admin),
+        ] {
+            for (class, limit) in [
+                ("read", limits.read),
+                ("write", limits.write),
+                ("list", limits.list),
+                ("session", limits.session),
+            ] {
+                if limit.per_minute > 0 && limit.burst == 0 {
+                    errors.push(format!(
+                        "rate_limits.{}.{}: burst must be greater than zero",
+                        name, class
+                    ));
+                }
+            }
+        }
+
+        errors.extend(self.cors.validate().into_iter().map(|e| format!("cors.{}", e)));
+
+        if self.compression.gzip_level > 9 {
+            errors.push("compression.gzip_level: must be between 0 and 9".to_string());
+        }
+
+        if self.compression.brotli_quality > 11 {
+            errors.push("compression.brotli_quality: must be between 0 and 11".to_string());
+        }
+
+        if !(1..=19).contains(&self.compression.zstd_level) {
+            errors.push("compression.zstd_level: must be between 1 and 19".to_string());
+        }
+
+        if self.encryption.enabled && self.encryption.master_key.is_empty() {
+            errors.push("encryption.master_key: required when encryption is enabled".to_string());
+        }
+
+        let master_keys = Some(&self.encryption.master_key).filter(|key| !key.is_empty());
+        for key in master_keys.into_iter().chain(&self.encryption.previous_master_keys) {
+            if let Err(error) = encryption::decode_master_key(key) {
+                errors.push(format!("encryption: master key {}", error));
+            }
+        }
+
+        if self.uploads.default_readable_by > AuthLevel::Owner
+            || self.uploads.default_readable_by == AuthLevel::ReadWrite
+        {
+            errors.push("uploads.default_readable_by: must be Public, Read or Owner".to_string());
+        }
+
+        if self.auth.jwt_secret.is_empty() {
+            errors.push("auth.jwt_secret: must be set (or JWT_SECRET)".to_string());
+        }
+
+        // Paths are checked against the data directory after
+        // canonicalization, so it has to be canonical itself
+        match std::fs::create_dir_all(&self.data_dir).and_then(|_| self.data_dir.canonicalize()) {
+            Ok(data_dir) => self.data_dir = data_dir,
+            Err(error) => errors.push(format!(
+                "data_dir: {} is not usable - {}",
+                self.data_dir.display(),
+                error
+            )),
+        }
+
+        errors
+    }
+
+    // The configuration as TOML with secrets left out
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+
+        if !config.auth.jwt_secret.is_empty() {
+            config.auth.jwt_secret = "<redacted>".to_string();
+        }
+
+        let encryption = &mut config.encryption;
+        for key in std::iter::once(&mut encryption.master_key).chain(&mut encryption.previous_master_keys) {
+            if !key.is_empty() {
+                *key = "<redacted>".to_string();
+            }
+        }
+
+        toml::to_string_pretty(&config).expect("configuration is always serializable")
+    }
+
+    pub fn trusted_proxies(&self) -> TrustedProxies {
+        TrustedProxies::new(self.proxy.trusted.iter().filter_map(|range| Cidr::parse(range)).collect())
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: self.limits.max_connections,
+            max_connections_per_ip: self.limits.max_connections_per_ip,
+            max_body_size: self.limits.max_body_size,
+            max_header_size: self.limits.max_header_size,
+            idle_timeout: Duration::from_secs(self.timeouts.idle_secs),
+            header_timeout: Duration::from_secs(self.timeouts.header_secs),
+            body_timeout: Duration::from_secs(self.timeouts.body_secs),
+            write_timeout: Duration::from_secs(self.timeouts.write_secs),
+        }
+    }
+}
+
+pub fn init(config: Config) {
+    CONFIG
+        .set(config)
+        .expect("configuration is only initialized once");
+}
+
+// The active configuration, falls back to the defaults when `init` has not
+// been called (e.g. in tests)
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(fallback)
+}
+
+#[cfg(not(test))]
+fn fallback() -> Config {
+    Config::default()
+}
+
+// Tests keep their data out of the default data directory
+#[cfg(test)]
+fn fallback() -> Config {
+    Config {
+        data_dir: std::env::temp_dir().join(format!("a-bucket-tests-{}", std::process::id())),
+        ..Config::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_toml_with_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = ["127.0.0.1:8080"]
+
+            [sessions]
+            ttl_secs = 60
+
+            [uploads]
+            default_readable_by = "Owner"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen, vec!["127.0.0.1:8080".to_string()]);
+        assert_eq!(config.sessions.ttl_secs, 60);
+        assert_eq!(config.uploads.default_readable_by, AuthLevel::Owner);
+        assert_eq!(config.limits.max_connections, LimitsConfig::default().max_connections);
+        assert!(toml::from_str::<Config>("unknown_key = 1").is_err());
+    }
+
+    #[test]
+    fn test_example_config_parses() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        assert_eq!(config.rate_limits.per_key.public.list.burst, 5);
+        assert!(toml::from_str::<Config>("[rate_limits.per_ip]\nread = { per_minute = 1, burst = 1 }").is_err());
+    }
+
+    #[test]
+    fn test_cli_overrides_and_validation() {
+        let args = ["--listen", "127.0.0.1:1,nonsense", "--session-ttl=0", "--print-config"];
+        let options = CliOptions::parse(args.iter().map(|arg| arg.to_string())).unwrap();
+        assert!(options.print_config);
+
+        let mut config = Config::default();
+        for (setting, value) in &options.overrides {
+            (setting.apply)(&mut config, value).unwrap();
+        }
+
+        let errors = config.validate();
+        assert!(errors.iter().any(|e| e.contains("'nonsense'")));
+        assert!(errors.iter().any(|e| e.starts_with("sessions.ttl_secs")));
+        assert!(errors.iter().any(|e| e.starts_with("auth.jwt_secret")));
+
+        config.cors.allowed_methods.push("FETCH".to_string());
+        config.cors.allowed_origins = vec!["*".to_string()];
+        config.cors.allow_credentials = true;
+        assert_eq!(config.cors.validate().len(), 2);
+        assert!(config.validate().iter().any(|e| e.starts_with("cors.allow_credentials")));
+
+        assert!(CliOptions::parse(["--bogus".to_string()].into_iter()).is_err());
+        assert!(CliOptions::parse(["--data-dir".to_string()].into_iter()).is_err());
+
+        let options = CliOptions::parse(["rotate-keys".to_string()].into_iter()).unwrap();
+        assert_eq!(options.command, Command::RotateKeys);
+
+        let options = CliOptions::parse(["fsck", "--repair"].iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!(options.command, Command::Fsck);
+        assert!(options.repair);
+        assert!(CliOptions::parse(["rotate".to_string()].into_iter()).is_err());
+    }
+}
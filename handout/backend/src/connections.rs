@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    // Connections served at once, further clients wait in the accept backlog
+    pub max_connections: usize,
+    // Connections a single client address may hold open at once
+    pub max_connections_per_ip: usize,
+    // Largest request body accepted, in bytes
+    pub max_body_size: usize,
+    // Largest request line plus headers accepted, in bytes
+    pub max_header_size: usize,
+    // Time allowed between accepting a connection and its first byte
+    pub idle_timeout: Duration,
+    // Time allowed to receive the complete request line and headers
+    pub header_timeout: Duration,
+    // Time allowed between two reads of the request body
+    pub body_timeout: Duration,
+    // Time allowed for a single write to the client
+    pub write_timeout: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Config::default().connection_limits()
+    }
+}
+
+pub struct ConnectionInfo {
+    pub address: SocketAddr,
+    pub opened_at: Instant,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    next_id: u64,
+    connections: HashMap<u64, ConnectionInfo>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// Tracks the live connections of a listener. Entries are removed when the
+// `ConnectionGuard` handed out on registration is dropped
+pub struct ConnRegistry {
+    slots: Arc<Semaphore>,
+    max_per_ip: usize,
+    state: Mutex<RegistryState>,
+}
+
+pub struct ConnectionGuard {
+    registry: Arc<ConnRegistry>,
+    id: u64,
+    ip: IpAddr,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConnRegistry {
+    pub fn new(limits: &ConnectionLimits) -> Arc<Self> {
+        Arc::new(Self {
+            slots: Arc::new(Semaphore::new(limits.max_connections)),
+            max_per_ip: limits.max_connections_per_ip,
+            state: Mutex::new(RegistryState::default()),
+        })
+    }
+
+    // Waits until fewer than `max_connections` connections are live
+    pub async fn reserve(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.slots)
+            .acquire_owned()
+            .await
+            .expect("connection semaphore is never closed")
+    }
+
+    // Registers an accepted connection, or hands the permit back if its
+    // address already holds the maximum number of connections, whatever is
+    // done to turn the client away still takes a slot. Trusted proxies carry
+    // many clients over one address and are therefore exempt from that cap
+    pub fn register(
+        self: &Arc<Self>,
+        address: SocketAddr,
+        permit: OwnedSemaphorePermit,
+        exempt: bool,
+    ) -> Result<ConnectionGuard, OwnedSemaphorePermit> {
+        let mut state = self.state.lock().unwrap();
+        let ip = address.ip();
+
+        let open = state.per_ip.entry(ip).or_insert(0);
+        if *open >= self.max_per_ip && !exempt {
+            if *open == 0 {
+                state.per_ip.remove(&ip);
+            }
+            return Err(permit);
+        }
+        *open += 1;
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.connections.insert(
+            id,
+            ConnectionInfo {
+                address,
+                opened_at: Instant::now(),
+            },
+        );
+
+        Ok(ConnectionGuard {
+            registry: Arc::clone(self),
+            id,
+            ip,
+            _permit: permit,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().connections.len()
+    }
+
+    // Age of the oldest live connection, useful to spot stuck clients
+    pub fn oldest(&self) -> Option<(SocketAddr, Duration)> {
+        let state = self.state.lock().unwrap();
+        state
+            .connections
+            .values()
+            .min_by_key(|info| info.opened_at)
+            .map(|info| (info.address, info.opened_at.elapsed()))
+    }
+
+    fn deregister(&self, id: u64, ip: IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.connections.remove(&id);
+
+        if let Some(open) = state.per_ip.get_mut(&ip) {
+            *open -= 1;
+            if *open == 0 {
+                state.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.deregister(self.id, self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_registry_per_ip_cap_and_release() {
+        let limits = ConnectionLimits {
+            max_connections: 4,
+            max_connections_per_ip: 2,
+            ..Default::default()
+        };
+        let registry = ConnRegistry::new(&limits);
+        let client: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+
+        let first = registry.register(client, registry.reserve().await, false).ok().unwrap();
+        let _second = registry.register(client, registry.reserve().await, false).ok().unwrap();
+        // A rejected connection keeps its slot until it is turned away
+        let rejected = registry.register(client, registry.reserve().await, false).err().unwrap();
+        assert_eq!(registry.slots.available_permits(), 1);
+        drop(rejected);
+        let _third = registry.register(other, registry.reserve().await, false).ok().unwrap();
+        assert_eq!(registry.len(), 3);
+
+        drop(first);
+        assert_eq!(registry.len(), 2);
+        assert!(registry.register(client, registry.reserve().await, false).is_ok());
+        assert!(registry.register(client, registry.reserve().await, true).is_ok());
+    }
+}
@@ -0,0 +1,178 @@
+use a_http_parser::http::Method;
+use a_http_parser::request::Request;
+use a_http_parser::response::Response;
+
+use crate::config::CorsConfig;
+
+fn origin_allowed<'a>(cors: &CorsConfig, origin: &'a str) -> Option<&'a str> {
+    cors.allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+        .then_some(origin)
+}
+
+// The value of Access-Control-Allow-Origin, `*` is only sent when any origin
+// is allowed and no credentials are involved
+fn allow_origin_value<'a>(cors: &CorsConfig, origin: &'a str) -> &'a str {
+    if !cors.allow_credentials && cors.allowed_origins.iter().any(|allowed| allowed == "*") {
+        "*"
+    } else {
+        origin
+    }
+}
+
+// Adds the CORS headers to a response for a request from `origin`. Vary is
+// always set when CORS is enabled so caches keep responses per origin
+pub fn apply(cors: &CorsConfig, origin: Option<&str>, res: &mut Response) {
+    if cors.allowed_origins.is_empty() {
+        return;
+    }
+
+    res.append_header("vary", "Origin");
+
+    let Some(origin) = origin.and_then(|origin| origin_allowed(cors, origin)) else {
+        return;
+    };
+
+    res.set_header("access-control-allow-origin", allow_origin_value(cors, origin));
+
+    if cors.allow_credentials {
+        res.set_header("access-control-allow-credentials", "true");
+    }
+
+    if !cors.exposed_headers.is_empty() {
+        res.set_header("access-control-expose-headers", &cors.exposed_headers.join(", "));
+    }
+}
+
+// Answers an OPTIONS request. Preflights that ask for a disallowed origin,
+// method or header get no CORS headers, which makes the browser refuse the
+// actual request
+pub fn preflight(cors: &CorsConfig, req: &Request) -> Response {
+    let mut res = Response::new(204);
+
+    let mut allow = cors.allowed_methods.clone();
+    allow.push(Method::OPTIONS.to_str().to_string());
+    res.set_header("allow", &allow.join(", "));
+
+    if cors.allowed_origins.is_empty() {
+        return res;
+    }
+
+    res.append_header("vary", "Origin");
+    res.append_header("vary", "Access-Control-Request-Method");
+    res.append_header("vary", "Access-Control-Request-Headers");
+
+    let (Some(origin), Some(method)) = (
+        req.headers.get("origin"),
+        req.headers.get("access-control-request-method"),
+    ) else {
+        return res;
+    };
+
+    let Some(origin) = origin_allowed(cors, origin) else {
+        return res;
+    };
+
+    let method = method.trim();
+    if !cors.allowed_methods.iter().any(|allowed| allowed == method) {
+        return res;
+    }
+
+    let requested_headers: Vec<&str> = req
+        .headers
+        .get("access-control-request-headers")
+        .map(|headers| {
+            headers
+                .split(',')
+                .map(str::trim)
+                .filter(|header| !header.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let headers_allowed = requested_headers.iter().all(|requested| {
+        cors.allowed_headers
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(requested))
+    });
+    if !headers_allowed {
+        return res;
+    }
+
+    res.set_header("access-control-allow-origin", allow_origin_value(cors, origin));
+    res.set_header("access-control-allow-methods", &cors.allowed_methods.join(", "));
+    if !cors.allowed_headers.is_empty() {
+        res.set_header("access-control-allow-headers", &cors.allowed_headers.join(", "));
+    }
+    res.set_header("access-control-max-age", &cors.max_age_secs.to_string());
+
+    if cors.allow_credentials {
+        res.set_header("access-control-allow-credentials", "true");
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use a_http_parser::parser::Parser;
+
+    fn request(raw: &str) -> Request {
+        let mut parser = Parser::new();
+        parser.update(raw.as_bytes());
+        parser.consume_request().unwrap()
+    }
+
+    fn config() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_preflight() {
+        let cors = config();
+
+        let req = request(
+            "OPTIONS /a.png HTTP/1.1\r\nOrigin: https://app.example.com\r\n\
+             Access-Control-Request-Method: LIST\r\n\
+             Access-Control-Request-Headers: x-readable-by, content-type\r\n\r\n",
+        );
+        let res = preflight(&cors, &req);
+        assert_eq!(res.status_code, 204);
+        assert_eq!(res.headers.get("access-control-allow-origin").unwrap(), "https://app.example.com");
+        assert_eq!(res.headers.get("access-control-allow-credentials").unwrap(), "true");
+        assert!(res.headers.get("vary").unwrap().starts_with("Origin"));
+
+        let req = request(
+            "OPTIONS /a.png HTTP/1.1\r\nOrigin: https://evil.example.com\r\n\
+             Access-Control-Request-Method: GET\r\n\r\n",
+        );
+        assert!(!preflight(&cors, &req).headers.contains_key("access-control-allow-origin"));
+
+        let req = request(
+            "OPTIONS /a.png HTTP/1.1\r\nOrigin: https://app.example.com\r\n\
+             Access-Control-Request-Method: TRACE\r\n\r\n",
+        );
+        assert!(!preflight(&cors, &req).headers.contains_key("access-control-allow-origin"));
+    }
+
+    #[test]
+    fn test_apply() {
+        let cors = config();
+
+        let mut res = Response::new(200);
+        apply(&cors, Some("https://app.example.com"), &mut res);
+        assert_eq!(res.headers.get("access-control-allow-origin").unwrap(), "https://app.example.com");
+        assert!(res.headers.get("access-control-expose-headers").unwrap().contains("ETag"));
+
+        let mut res = Response::new(200);
+        apply(&cors, Some("https://other.example.com"), &mut res);
+        assert!(!res.headers.contains_key("access-control-allow-origin"));
+        assert_eq!(res.headers.get("vary").unwrap(), "Origin");
+    }
+}
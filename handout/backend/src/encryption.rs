@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::config::{self, EncryptionConfig};
+use crate::index;
+use crate::metadata::Metadata;
+use crate::storable::{StorableBase, StorableJson};
+use crate::trash::TrashedObject;
+
+// Plaintext bytes sealed per chunk, each chunk can be decrypted on its own
+pub const CHUNK_SIZE: u32 = 64 * 1024;
+pub const TAG_LEN: usize = 16;
+pub const KEY_LEN: usize = 32;
+
+// Binds wrapped data keys to their purpose
+const WRAP_AAD: &[u8] = b"a-bucket data key";
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Algorithm {
+    #[serde(rename = "AES-256-GCM")]
+    Aes256Gcm,
+    #[serde(rename = "ChaCha20-Poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl Algorithm {
+    fn from_str(name: &str) -> Option<Self> {
+        match name {
+            "AES-256-GCM" | "AES256" => Some(Algorithm::Aes256Gcm),
+            "ChaCha20-Poly1305" => Some(Algorithm::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Algorithm::Aes256Gcm => "AES-256-GCM",
+            Algorithm::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+        }
+    }
+
+    fn aead(self) -> &'static aead::Algorithm {
+        match self {
+            Algorithm::Aes256Gcm => &aead::AES_256_GCM,
+            Algorithm::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+
+    fn key(self, bytes: &[u8]) -> Result<LessSafeKey, String> {
+        UnboundKey::new(self.aead(), bytes)
+            .map(LessSafeKey::new)
+            .map_err(|_| "invalid key length".to_string())
+    }
+}
+
+// Where the data key of a blob comes from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DataKey {
+    Wrapped {
+        // Identifies the master key the data key is wrapped with
+        key_id: String,
+        // Nonce followed by the sealed data key, base64 encoded
+        wrapped_key: String,
+    },
+    // Derived from a key the client sends with every request, the server only
+    // keeps a fingerprint to recognise it
+    Customer {
+        customer_key_salt: String,
+        customer_key_fingerprint: String,
+    },
+}
+
+// How a blob was encrypted, stored in its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionInfo {
+    pub algorithm: Algorithm,
+    #[serde(flatten)]
+    pub key: DataKey,
+    pub chunk_size: u32,
+}
+
+impl EncryptionInfo {
+    // Offset of a chunk in the stored blob
+    pub fn chunk_offset(&self, index: u64) -> u64 {
+        index * (self.chunk_size as u64 + TAG_LEN as u64)
+    }
+
+    pub fn is_customer_key(&self) -> bool {
+        matches!(self.key, DataKey::Customer { .. })
+    }
+}
+
+// An encryption key supplied by the client in the X-Encryption-* headers
+#[derive(Clone)]
+pub struct CustomerKey {
+    pub algorithm: Algorithm,
+    key: Vec<u8>,
+    // Base64 MD5 of the key, echoed back to the client
+    pub key_md5: String,
+}
+
+impl std::fmt::Debug for CustomerKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomerKey")
+            .field("algorithm", &self.algorithm)
+            .field("key_md5", &self.key_md5)
+            .finish()
+    }
+}
+
+impl CustomerKey {
+    // Reads the key from request headers, None when the client sent none
+    pub fn from_headers(headers: &HashMap<String, String>) -> Result<Option<Self>, &'static str> {
+        let Some(encoded) = headers.get("x-encryption-key") else {
+            if headers.contains_key("x-encryption-key-md5") || headers.contains_key("x-encryption-algorithm") {
+                return Err("X-Encryption-Key is missing");
+            }
+            return Ok(None);
+        };
+
+        let algorithm = match headers.get("x-encryption-algorithm") {
+            Some(name) => Algorithm::from_str(name.trim()).ok_or("Unsupported X-Encryption-Algorithm")?,
+            None => Algorithm::Aes256Gcm,
+        };
+
+        let key = STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .filter(|key| key.len() == KEY_LEN)
+            .ok_or("X-Encryption-Key must be 32 bytes encoded as base64")?;
+
+        let key_md5 = STANDARD.encode(Md5::digest(&key));
+        match headers.get("x-encryption-key-md5") {
+            Some(expected) if expected.trim() == key_md5 => {}
+            Some(_) => return Err("X-Encryption-Key-MD5 does not match the key"),
+            None => return Err("X-Encryption-Key-MD5 is missing"),
+        }
+
+        Ok(Some(Self {
+            algorithm,
+            key,
+            key_md5,
+        }))
+    }
+
+    fn derive(&self, salt: &[u8], purpose: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(purpose);
+        mac.update(salt);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    // Whether this is the key `info` was encrypted with
+    pub fn matches(&self, info: &EncryptionInfo) -> bool {
+        match &info.key {
+            DataKey::Customer {
+                customer_key_salt,
+                customer_key_fingerprint,
+            } => STANDARD.decode(customer_key_salt).is_ok_and(|salt| {
+                hex::encode(self.derive(&salt, b"fingerprint")) == *customer_key_fingerprint
+            }),
+            DataKey::Wrapped { .. } => false,
+        }
+    }
+}
+
+struct MasterKey {
+    id: String,
+    key: LessSafeKey,
+}
+
+// The master keys from the configuration, the current one wraps new data keys
+// and previous ones can still unwrap keys until they are rotated
+struct Keyring {
+    current: Option<MasterKey>,
+    previous: Vec<MasterKey>,
+}
+
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+impl Keyring {
+    fn from_config(config: &EncryptionConfig) -> Self {
+        let load = |encoded: &String| {
+            let bytes = decode_master_key(encoded).ok()?;
+            Some(MasterKey {
+                id: key_id(&bytes),
+                key: Algorithm::Aes256Gcm.key(&bytes).ok()?,
+            })
+        };
+
+        Self {
+            current: Some(&config.master_key)
+                .filter(|key| !key.is_empty())
+                .and_then(load),
+            previous: config.previous_master_keys.iter().filter_map(load).collect(),
+        }
+    }
+
+    fn find(&self, id: &str) -> Option<&MasterKey> {
+        self.current
+            .iter()
+            .chain(&self.previous)
+            .find(|master| master.id == id)
+    }
+}
+
+fn keyring() -> &'static Keyring {
+    KEYRING.get_or_init(|| Keyring::from_config(&config::get().encryption))
+}
+
+pub fn decode_master_key(encoded: &str) -> Result<Vec<u8>, String> {
+    match STANDARD.decode(encoded.trim()) {
+        Ok(bytes) if bytes.len() == KEY_LEN => Ok(bytes),
+        _ => Err(format!("must be {} bytes encoded as base64", KEY_LEN)),
+    }
+}
+
+fn key_id(key: &[u8]) -> String {
+    hex::encode(&Sha256::digest(key)[..8])
+}
+
+fn random_bytes(length: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0; length];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "no randomness available".to_string())?;
+    Ok(bytes)
+}
+
+fn wrap(master: &MasterKey, data_key: &[u8]) -> Result<String, String> {
+    let nonce = random_bytes(NONCE_LEN)?;
+    let mut sealed = data_key.to_vec();
+    master
+        .key
+        .seal_in_place_append_tag(
+            Nonce::try_assume_unique_for_key(&nonce).unwrap(),
+            Aad::from(WRAP_AAD),
+            &mut sealed,
+        )
+        .map_err(|_| "failed to wrap data key".to_string())?;
+
+    Ok(STANDARD.encode([nonce, sealed].concat()))
+}
+
+fn unwrap(key_id: &str, wrapped_key: &str) -> Result<Vec<u8>, String> {
+    let master = keyring()
+        .find(key_id)
+        .ok_or_else(|| format!("master key {} is not configured", key_id))?;
+
+    let wrapped = STANDARD
+        .decode(wrapped_key)
+        .map_err(|_| "malformed wrapped data key".to_string())?;
+    if wrapped.len() < NONCE_LEN {
+        return Err("malformed wrapped data key".to_string());
+    }
+
+    let (nonce, sealed) = wrapped.split_at(NONCE_LEN);
+    let mut sealed = sealed.to_vec();
+    let data_key = master
+        .key
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).unwrap(),
+            Aad::from(WRAP_AAD),
+            &mut sealed,
+        )
+        .map_err(|_| format!("data key does not open with master key {}", key_id))?;
+
+    Ok(data_key.to_vec())
+}
+
+// Every data key is only used for one blob, so the chunk index makes a unique
+// nonce. The final chunk is marked so a truncated blob fails to decrypt
+fn chunk_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+// Seals `data` with `key` chunk by chunk
+pub fn seal_chunks(algorithm: Algorithm, key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let key = algorithm.key(key)?;
+    let chunks = data.len().div_ceil(CHUNK_SIZE as usize).max(1);
+    let mut sealed = Vec::with_capacity(data.len() + chunks * TAG_LEN);
+
+    for index in 0..chunks {
+        let start = index * CHUNK_SIZE as usize;
+        let end = (start + CHUNK_SIZE as usize).min(data.len());
+
+        let mut chunk = data[start..end].to_vec();
+        key.seal_in_place_append_tag(
+            chunk_nonce(index as u64, index == chunks - 1),
+            Aad::empty(),
+            &mut chunk,
+        )
+        .map_err(|_| "failed to encrypt".to_string())?;
+        sealed.extend(chunk);
+    }
+
+    Ok(sealed)
+}
+
+// Encrypts a blob under a fresh data key, derived from the customer key when
+// there is one and wrapped by the current master key otherwise
+pub fn encrypt(data: &[u8], customer_key: Option<&CustomerKey>) -> Result<(Vec<u8>, EncryptionInfo), String> {
+    let (algorithm, data_key, key) = match customer_key {
+        // Every blob gets its own salt so reusing a customer key never reuses
+        // a data key
+        Some(customer_key) => {
+            let salt = random_bytes(SALT_LEN)?;
+            let key = DataKey::Customer {
+                customer_key_salt: STANDARD.encode(&salt),
+                customer_key_fingerprint: hex::encode(customer_key.derive(&salt, b"fingerprint")),
+            };
+
+            (customer_key.algorithm, customer_key.derive(&salt, b"data key"), key)
+        }
+        None => {
+            let master = keyring()
+                .current
+                .as_ref()
+                .ok_or_else(|| "no master key configured".to_string())?;
+
+            let data_key = random_bytes(KEY_LEN)?;
+            let key = DataKey::Wrapped {
+                key_id: master.id.clone(),
+                wrapped_key: wrap(master, &data_key)?,
+            };
+
+            (config::get().encryption.algorithm, data_key, key)
+        }
+    };
+
+    let sealed = seal_chunks(algorithm, &data_key, data)?;
+    let info = EncryptionInfo {
+        algorithm,
+        key,
+        chunk_size: CHUNK_SIZE,
+    };
+
+    Ok((sealed, info))
+}
+
+fn data_key(info: &EncryptionInfo, customer_key: Option<&CustomerKey>) -> Result<Vec<u8>, String> {
+    match &info.key {
+        DataKey::Wrapped { key_id, wrapped_key } => unwrap(key_id, wrapped_key),
+        DataKey::Customer { customer_key_salt, .. } => {
+            let customer_key = customer_key
+                .filter(|customer_key| customer_key.matches(info))
+                .ok_or_else(|| "encrypted with a different customer key".to_string())?;
+            let salt = STANDARD
+                .decode(customer_key_salt)
+                .map_err(|_| "malformed customer key salt".to_string())?;
+
+            Ok(customer_key.derive(&salt, b"data key"))
+        }
+    }
+}
+
+// Decrypts a blob as it is streamed, starting at chunk `first_chunk`
+pub struct Decryptor {
+    key: LessSafeKey,
+    sealed_len: usize,
+    index: u64,
+    buffer: Vec<u8>,
+}
+
+impl Decryptor {
+    pub fn new(
+        info: &EncryptionInfo,
+        customer_key: Option<&CustomerKey>,
+        first_chunk: u64,
+    ) -> Result<Self, String> {
+        Self::with_key(info.algorithm, &data_key(info, customer_key)?, info.chunk_size, first_chunk)
+    }
+
+    pub fn with_key(
+        algorithm: Algorithm,
+        key: &[u8],
+        chunk_size: u32,
+        first_chunk: u64,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            key: algorithm.key(key)?,
+            sealed_len: chunk_size as usize + TAG_LEN,
+            index: first_chunk,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn open(&mut self, length: usize, last: bool) -> std::io::Result<Vec<u8>> {
+        let mut chunk: Vec<u8> = self.buffer.drain(..length).collect();
+        let plaintext = self
+            .key
+            .open_in_place(chunk_nonce(self.index, last), Aad::empty(), &mut chunk)
+            .map_err(|_| std::io::Error::other(format!("chunk {} failed to decrypt", self.index)))?;
+        let plaintext = plaintext.to_vec();
+
+        self.index += 1;
+        Ok(plaintext)
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut plaintext = Vec::new();
+
+        // A complete chunk may still be the last one, it is held back until
+        // more data shows it isn't
+        while self.buffer.len() > self.sealed_len {
+            plaintext.extend(self.open(self.sealed_len, false)?);
+        }
+
+        Ok(plaintext)
+    }
+
+    pub fn finish(mut self) -> std::io::Result<Vec<u8>> {
+        if self.buffer.len() < TAG_LEN {
+            return Err(std::io::Error::other("blob is truncated"));
+        }
+
+        self.open(self.buffer.len(), true)
+    }
+}
+
+// Decrypts a whole blob held in memory
+pub fn decrypt(
+    info: &EncryptionInfo,
+    customer_key: Option<&CustomerKey>,
+    data: &[u8],
+) -> Result<Vec<u8>, String> {
+    let mut decryptor = Decryptor::new(info, customer_key, 0)?;
+    let mut plaintext = decryptor.update(data).map_err(|e| e.to_string())?;
+    plaintext.extend(decryptor.finish().map_err(|e| e.to_string())?);
+    Ok(plaintext)
+}
+
+// Rewraps every data key not wrapped by the current master key, trashed
+// objects included, returns how many were rewrapped or the number of objects
+// that failed. Customer keys are never known to the server and left alone
+pub async fn rotate_keys() -> Result<usize, usize> {
+    let Some(current) = keyring().current.as_ref() else {
+        error!("encryption.master_key must be set to rotate keys");
+        return Err(0);
+    };
+
+    let _ = Metadata::ensure_base_dir_exists();
+    let _ = TrashedObject::ensure_base_dir_exists();
+    let (Ok(mut list), Ok(mut trashed)) = (Metadata::list().await, TrashedObject::list().await) else {
+        error!("Failed to list objects");
+        return Err(0);
+    };
+
+    let mut rotated = 0;
+    let mut failed = 0;
+
+    while let Some(mut metadata) = list.next().await {
+        match rewrap(current, &mut metadata) {
+            Some(Ok(())) => {
+                if let Err(e) = metadata.save().await {
+                    error!("{}: failed to save metadata - {}", metadata.key, e);
+                    failed += 1;
+                    continue;
+                }
+                if let Err(e) = index::get().put(&metadata) {
+                    error!("{}: failed to log the metadata index change - {}", metadata.key, e);
+                }
+                rotated += 1;
+            }
+            Some(Err(e)) => {
+                error!("{}: {}", metadata.key, e);
+                failed += 1;
+            }
+            None => {}
+        }
+    }
+
+    while let Some(mut object) = trashed.next().await {
+        match rewrap(current, &mut object.metadata) {
+            Some(Ok(())) => {
+                if let Err(e) = object.save().await {
+                    error!("{}: failed to save trash record - {}", object.id, e);
+                    failed += 1;
+                    continue;
+                }
+                rotated += 1;
+            }
+            Some(Err(e)) => {
+                error!("{}: {}", object.id, e);
+                failed += 1;
+            }
+            None => {}
+        }
+    }
+
+    info!("Rewrapped {} data key(s) with master key {}", rotated, current.id);
+
+    match failed {
+        0 => Ok(rotated),
+        failed => Err(failed),
+    }
+}
+
+// Rewraps the data key of an object, None when there is nothing to do
+fn rewrap(current: &MasterKey, metadata: &mut Metadata) -> Option<Result<(), String>> {
+    let Some(DataKey::Wrapped { key_id, wrapped_key }) = metadata.encryption.as_mut().map(|info| &mut info.key) else {
+        return None;
+    };
+
+    if *key_id == current.id {
+        return None;
+    }
+
+    Some(unwrap(key_id, wrapped_key).and_then(|data_key| wrap(current, &data_key)).map(|rewrapped| {
+        *wrapped_key = rewrapped;
+        *key_id = current.id.clone();
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_roundtrip_and_seek() {
+        let key = [7; KEY_LEN];
+        let data: Vec<u8> = (0..CHUNK_SIZE as usize * 2 + 100).map(|i| i as u8).collect();
+
+        for algorithm in [Algorithm::Aes256Gcm, Algorithm::ChaCha20Poly1305] {
+            let sealed = seal_chunks(algorithm, &key, &data).unwrap();
+            assert_eq!(sealed.len(), data.len() + 3 * TAG_LEN);
+
+            // Fed in odd sized pieces like a file read
+            let mut decryptor = Decryptor::with_key(algorithm, &key, CHUNK_SIZE, 0).unwrap();
+            let mut plaintext = Vec::new();
+            for piece in sealed.chunks(4096 + 3) {
+                plaintext.extend(decryptor.update(piece).unwrap());
+            }
+            plaintext.extend(decryptor.finish().unwrap());
+            assert_eq!(plaintext, data);
+
+            // Starting at the second chunk
+            let offset = CHUNK_SIZE as usize + TAG_LEN;
+            let mut decryptor = Decryptor::with_key(algorithm, &key, CHUNK_SIZE, 1).unwrap();
+            let mut plaintext = decryptor.update(&sealed[offset..]).unwrap();
+            plaintext.extend(decryptor.finish().unwrap());
+            assert_eq!(plaintext, &data[CHUNK_SIZE as usize..]);
+
+            // Dropping the final chunk is detected
+            let mut decryptor = Decryptor::with_key(algorithm, &key, CHUNK_SIZE, 0).unwrap();
+            decryptor.update(&sealed[..offset * 2]).unwrap();
+            assert!(decryptor.finish().is_err());
+        }
+    }
+
+    #[test]
+    fn test_customer_key() {
+        let key = [3; KEY_LEN];
+        let mut headers = HashMap::from([
+            ("x-encryption-key".to_string(), STANDARD.encode(key)),
+            ("x-encryption-key-md5".to_string(), STANDARD.encode(Md5::digest(key))),
+        ]);
+        let customer_key = CustomerKey::from_headers(&headers).unwrap().unwrap();
+
+        let (sealed, info) = encrypt(b"secret", Some(&customer_key)).unwrap();
+        assert!(customer_key.matches(&info));
+        assert_eq!(decrypt(&info, Some(&customer_key), &sealed).unwrap(), b"secret");
+
+        // The same key encrypts every blob with its own data key
+        let (other, _) = encrypt(b"secret", Some(&customer_key)).unwrap();
+        assert_ne!(sealed, other);
+
+        headers.insert("x-encryption-key".to_string(), STANDARD.encode([4; KEY_LEN]));
+        headers.insert("x-encryption-key-md5".to_string(), STANDARD.encode(Md5::digest([4; KEY_LEN])));
+        let wrong = CustomerKey::from_headers(&headers).unwrap().unwrap();
+        assert!(!wrong.matches(&info));
+        assert!(decrypt(&info, Some(&wrong), &sealed).is_err());
+
+        headers.insert("x-encryption-key-md5".to_string(), "bogus".to_string());
+        assert!(CustomerKey::from_headers(&headers).is_err());
+    }
+}
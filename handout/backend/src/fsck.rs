@@ -0,0 +1,519 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use a_http_parser::http::MimeType;
+use log::{error, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::authentication::AuthLevel;
+use crate::checksum::Checksums;
+use crate::config;
+use crate::index;
+use crate::metadata::Metadata;
+use crate::storable::{StorableBase, StorableJson};
+use crate::storage::{self, Object};
+
+// Records and blobs younger than this may belong to an upload still being written
+const MIN_AGE: Duration = Duration::from_secs(10 * 60);
+const REPORT_FILE: &str = "fsck.json";
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    // A metadata file that is not valid JSON for a record
+    UnparsableRecord,
+    MissingBlob,
+    SizeMismatch,
+    EtagMismatch,
+    // The blob could not be decrypted or decompressed
+    UnreadableBlob,
+    // A blob without a metadata record
+    OrphanedBlob,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub detail: String,
+    // What was done about it, None when it was only reported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub repair: bool,
+    pub records: usize,
+    pub blobs: usize,
+    // Objects encrypted with a customer key can only be checked for presence
+    pub unverified: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    // Issues still present after the run
+    pub fn unresolved(&self) -> usize {
+        self.issues.iter().filter(|issue| issue.repair.is_none()).count()
+    }
+
+    fn issue(&mut self, kind: IssueKind, path: &Path, key: Option<&str>, detail: String) -> &mut Issue {
+        warn!("fsck: {}: {}", path.display(), detail);
+        self.issues.push(Issue {
+            kind,
+            path: path.to_path_buf(),
+            key: key.map(str::to_string),
+            detail,
+            repair: None,
+        });
+        self.issues.last_mut().unwrap()
+    }
+}
+
+pub fn quarantine_dir() -> PathBuf {
+    config::get().data_dir.join("quarantine")
+}
+
+// Where the report of the last run is kept
+pub fn report_path() -> PathBuf {
+    config::get().data_dir.join(REPORT_FILE)
+}
+
+// Checks every metadata record against its blob and looks for blobs without
+// a record. With `repair` corrupt records are quarantined, records are
+// rebuilt for plain blobs whose record was quarantined and other orphans
+// deleted. Repairs race with uploads, only run them while the server is
+// stopped
+pub async fn run(repair: bool) -> std::io::Result<Report> {
+    let started = SystemTime::now();
+    let mut report = Report {
+        started_at: started.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        repair,
+        ..Default::default()
+    };
+
+    Metadata::ensure_base_dir_exists()?;
+    Object::ensure_base_dir_exists()?;
+
+    let keys = check_records(&mut report, repair).await?;
+    check_blobs(&mut report, repair, &keys).await?;
+
+    // Repairs moved and wrote records behind the index's back
+    if repair {
+        index::get().reconcile()?;
+    }
+
+    report.duration_ms = started.elapsed().unwrap_or_default().as_millis() as u64;
+
+    let json = serde_json::to_string_pretty(&report).map_err(std::io::Error::other)?;
+    tokio::fs::write(report_path(), json).await?;
+
+    info!(
+        "fsck: checked {} record(s) and {} blob(s), found {} issue(s), {} unresolved",
+        report.records,
+        report.blobs,
+        report.issues.len(),
+        report.unresolved()
+    );
+
+    Ok(report)
+}
+
+// Validates every metadata record, returning the keys of the valid ones
+async fn check_records(report: &mut Report, repair: bool) -> std::io::Result<HashSet<String>> {
+    let mut keys = HashSet::new();
+    let mut entries = tokio::fs::read_dir(Metadata::base_dir()).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !entry.file_type().await.is_ok_and(|file_type| file_type.is_file()) {
+            continue;
+        }
+        report.records += 1;
+
+        let metadata = match tokio::fs::read(&path)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|contents| serde_json::from_slice::<Metadata>(&contents).map_err(|e| e.to_string()))
+        {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                if is_young(&path).await {
+                    continue;
+                }
+
+                let detail = format!("not a metadata record - {}", e);
+                let issue = report.issue(IssueKind::UnparsableRecord, &path, None, detail);
+                if repair {
+                    issue.repair = quarantine(&path, "metadata").await;
+                }
+                continue;
+            }
+        };
+
+        keys.insert(metadata.key.clone());
+
+        // Records and blobs are written one after the other without being
+        // atomic, an object being overwritten briefly pairs a new record
+        // with the old blob
+        let blob = Object::base_dir().join(&metadata.key);
+        if now().saturating_sub(metadata.last_modified) < MIN_AGE.as_secs()
+            || is_young(&path).await
+            || is_young(&blob).await
+        {
+            continue;
+        }
+
+        if let Some((kind, detail)) = check_object(report, &metadata).await {
+            let issue = report.issue(kind, &path, Some(&metadata.key), detail);
+            // Records whose blob could not be read might only lack a key
+            if repair && kind != IssueKind::UnreadableBlob {
+                issue.repair = quarantine(&path, "metadata").await;
+
+                if issue.repair.is_some() {
+                    keys.remove(&metadata.key);
+                    if kind != IssueKind::MissingBlob && quarantine(&blob, "storage").await.is_none() {
+                        error!("fsck: {}: failed to quarantine blob", blob.display());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+async fn check_object(report: &mut Report, metadata: &Metadata) -> Option<(IssueKind, String)> {
+    let path = Object::base_dir().join(&metadata.key);
+
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(e) => return Some((IssueKind::MissingBlob, format!("blob {} - {}", path.display(), e))),
+    };
+
+    if metadata.encryption.as_ref().is_some_and(|info| info.is_customer_key()) {
+        report.unverified += 1;
+        return None;
+    }
+
+    let data = match storage::decode_stored(metadata, None, data) {
+        Ok(data) => data,
+        Err(e) => return Some((IssueKind::UnreadableBlob, e)),
+    };
+
+    if data.len() as u64 != metadata.size {
+        let detail = format!("size is {}, recorded as {}", data.len(), metadata.size);
+        return Some((IssueKind::SizeMismatch, detail));
+    }
+
+    let etag = hex::encode(Sha256::digest(&data));
+    if etag != metadata.etag {
+        let detail = format!("SHA-256 is {}, recorded as {}", etag, metadata.etag);
+        return Some((IssueKind::EtagMismatch, detail));
+    }
+
+    None
+}
+
+// Looks for blobs without a valid record
+async fn check_blobs(report: &mut Report, repair: bool, keys: &HashSet<String>) -> std::io::Result<()> {
+    let base_dir = Object::base_dir();
+    let quarantined = quarantined_records().await;
+    let mut pending = vec![base_dir.clone()];
+
+    while let Some(dir) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type().await else {
+                continue;
+            };
+
+            if file_type.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            report.blobs += 1;
+
+            let Some(key) = path.strip_prefix(&base_dir).ok().and_then(Path::to_str) else {
+                continue;
+            };
+
+            if keys.contains(key) || is_young(&path).await {
+                continue;
+            }
+
+            let key = key.to_string();
+            let issue = report.issue(IssueKind::OrphanedBlob, &path, Some(&key), "no metadata record".to_string());
+            if !repair {
+                continue;
+            }
+
+            // The record was lost to corruption, the contents are still good
+            if let Some(record) = quarantined.get(&hex::encode(Sha256::digest(key.as_bytes()))) {
+                if can_rebuild(record, &path).await {
+                    issue.repair = rebuild_record(&path, &key).await;
+                } else {
+                    // Without its record a compressed or encrypted blob can't
+                    // be read, it is kept aside for whoever still has the key
+                    let moved = quarantine(&path, "storage").await;
+                    issue.detail = format!(
+                        "no metadata record, the blob may be compressed or encrypted and is unrecoverable ({})",
+                        moved.as_deref().unwrap_or("left in place")
+                    );
+                }
+            } else if tokio::fs::remove_file(&path).await.is_ok() {
+                issue.repair = Some("deleted".to_string());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The records quarantined now or by earlier runs, by the hash of their key
+async fn quarantined_records() -> HashMap<String, PathBuf> {
+    let mut records = HashMap::new();
+    let Ok(mut entries) = tokio::fs::read_dir(quarantine_dir().join("metadata")).await else {
+        return records;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let name = name.to_str().unwrap_or_default();
+
+        if let Some(hash) = name.split_once('-').and_then(|(_, rest)| rest.strip_suffix(".json")) {
+            records.insert(hash.to_string(), entry.path());
+        }
+    }
+
+    records
+}
+
+// Whether a blob can only hold the plain contents of its object. Nothing
+// tells compressed or encrypted blobs apart from plain ones, so records are
+// only rebuilt while neither is configured and the remains of the lost
+// record don't mention either (customer keys work regardless of settings)
+async fn can_rebuild(record: &Path, blob: &Path) -> bool {
+    let config = config::get();
+    if config.compression.store_compressed
+        || config.encryption.enabled
+        || !config.encryption.master_key.is_empty()
+        || !config.encryption.previous_master_keys.is_empty()
+    {
+        return false;
+    }
+
+    let Ok(record) = tokio::fs::read(record).await else {
+        return false;
+    };
+    let record = String::from_utf8_lossy(&record);
+    if record.contains("\"stored_encoding\"") || record.contains("\"encryption\"") {
+        return false;
+    }
+
+    // Zstd frames start with a magic number
+    let mut magic = [0; 4];
+    let Ok(mut file) = tokio::fs::File::open(blob).await else {
+        return false;
+    };
+    let read = file.read(&mut magic).await.unwrap_or(0);
+    read < magic.len() || magic != ZSTD_MAGIC
+}
+
+// Creates a record for a blob from its contents. The owner is unknown, so
+// only admins can read it until it is uploaded again
+async fn rebuild_record(path: &Path, key: &str) -> Option<String> {
+    let data = tokio::fs::read(path).await.ok()?;
+    let last_modified = tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(now, |modified| modified.as_secs());
+
+    let metadata = Metadata {
+        name: key.rsplit('/').next().unwrap_or(key).to_string(),
+        key: key.to_string(),
+        size: data.len() as u64,
+        last_modified,
+        etag: hex::encode(Sha256::digest(&data)),
+        mime_type: MimeType::default().to_str().to_string(),
+        owner_id: String::new(),
+        readable_by: AuthLevel::Owner,
+        expires_at: None,
+        stored_encoding: None,
+        encryption: None,
+        checksums: Some(Checksums::compute(&data)),
+        user_metadata: BTreeMap::new(),
+        retain_until: None,
+        legal_hold: false,
+    };
+
+    match metadata.save().await {
+        Ok(_) => Some("rebuilt record from blob".to_string()),
+        Err(e) => {
+            error!("fsck: {}: failed to rebuild record - {}", key, e);
+            None
+        }
+    }
+}
+
+// Moves a file out of the way into the quarantine directory, returning a
+// description of where it went
+async fn quarantine(path: &Path, kind: &str) -> Option<String> {
+    let base_dir = config::get().data_dir.join(kind);
+    let relative = path.strip_prefix(&base_dir).ok()?.to_str()?;
+
+    let dir = quarantine_dir().join(kind);
+    tokio::fs::create_dir_all(&dir).await.ok()?;
+
+    let target = dir.join(format!("{}-{}", now(), relative.replace('/', "%2F")));
+    match tokio::fs::rename(path, &target).await {
+        Ok(_) => Some(format!("quarantined to {}", target.display())),
+        Err(e) => {
+            error!("fsck: {}: failed to quarantine - {}", path.display(), e);
+            None
+        }
+    }
+}
+
+async fn is_young(path: &Path) -> bool {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < MIN_AGE)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn record_path(key: &str) -> PathBuf {
+        Metadata::base_dir().join(format!("{}.json", hex::encode(Sha256::digest(key.as_bytes()))))
+    }
+
+    // Writes a blob and its record as if they were stored an hour ago
+    async fn store_old(key: &str, contents: &[u8], etag_of: &[u8]) {
+        let metadata = Metadata {
+            name: key.to_string(),
+            key: key.to_string(),
+            size: contents.len() as u64,
+            last_modified: now() - 3600,
+            etag: hex::encode(Sha256::digest(etag_of)),
+            mime_type: MimeType::default().to_str().to_string(),
+            owner_id: String::new(),
+            readable_by: AuthLevel::Owner,
+            expires_at: None,
+            stored_encoding: None,
+            encryption: None,
+            checksums: None,
+            user_metadata: BTreeMap::new(),
+            retain_until: None,
+            legal_hold: false,
+        };
+        metadata.save().await.unwrap();
+        write_old(&Object::base_dir().join(key), contents);
+        age(&record_path(key));
+    }
+
+    fn write_old(path: &Path, contents: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+        age(path);
+    }
+
+    fn age(path: &Path) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+    }
+
+    fn issues<'a>(report: &'a Report, key: &str) -> Vec<&'a Issue> {
+        report.issues.iter().filter(|issue| issue.key.as_deref() == Some(key)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_reports_and_quarantines_mismatches() {
+        let _guard = testing::setup().await;
+
+        store_old("fsck/good", b"contents", b"contents").await;
+        store_old("fsck/bad", b"contents", b"other contents").await;
+
+        let report = run(false).await.unwrap();
+        assert!(issues(&report, "fsck/good").is_empty());
+        let bad = issues(&report, "fsck/bad");
+        assert_eq!(bad.len(), 1);
+        assert_eq!(bad[0].kind, IssueKind::EtagMismatch);
+        assert!(bad[0].repair.is_none());
+        assert!(record_path("fsck/bad").exists());
+
+        let report = run(true).await.unwrap();
+        assert!(issues(&report, "fsck/bad")[0].repair.is_some());
+        assert!(!record_path("fsck/bad").exists());
+        assert!(!Object::base_dir().join("fsck/bad").exists());
+        assert!(record_path("fsck/good").exists());
+    }
+
+    #[tokio::test]
+    async fn test_skips_objects_being_written() {
+        let _guard = testing::setup().await;
+
+        // An overwrite in progress pairs the new record with the old blob
+        store_old("fsck/overwritten", b"old", b"old").await;
+        let mut metadata: Metadata =
+            serde_json::from_slice(&std::fs::read(record_path("fsck/overwritten")).unwrap()).unwrap();
+        metadata.etag = hex::encode(Sha256::digest(b"new contents"));
+        metadata.last_modified = now();
+        metadata.save().await.unwrap();
+
+        let report = run(true).await.unwrap();
+        assert!(issues(&report, "fsck/overwritten").is_empty());
+        assert!(record_path("fsck/overwritten").exists());
+        assert!(Object::base_dir().join("fsck/overwritten").exists());
+    }
+
+    #[tokio::test]
+    async fn test_rebuilds_only_plain_blobs() {
+        let _guard = testing::setup().await;
+
+        write_old(&Object::base_dir().join("fsck/plain"), b"plain contents");
+        write_old(&record_path("fsck/plain"), b"{\"name\":\"pla");
+        write_old(&Object::base_dir().join("fsck/sealed"), b"sealed contents");
+        write_old(&record_path("fsck/sealed"), b"{\"name\":\"sealed\",\"encryption\":{\"algo");
+
+        let report = run(true).await.unwrap();
+
+        let plain = issues(&report, "fsck/plain");
+        assert_eq!(plain.len(), 1);
+        assert_eq!(plain[0].kind, IssueKind::OrphanedBlob);
+        assert!(plain[0].repair.is_some());
+        let rebuilt: Metadata = serde_json::from_slice(&std::fs::read(record_path("fsck/plain")).unwrap()).unwrap();
+        assert_eq!(rebuilt.etag, hex::encode(Sha256::digest(b"plain contents")));
+
+        // A blob that may be encrypted is moved aside, not served as is
+        let sealed = issues(&report, "fsck/sealed");
+        assert_eq!(sealed.len(), 1);
+        assert!(sealed[0].repair.is_none());
+        assert!(!record_path("fsck/sealed").exists());
+        assert!(!Object::base_dir().join("fsck/sealed").exists());
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::authentication::AuthContext;
+use crate::config;
+use crate::metadata::Metadata;
+use crate::storable::StorableBase;
+use crate::storage::Object;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_shutting_down() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+// Creates and removes a uniquely named file to prove the directory is writable
+async fn probe_directory(dir: &Path) -> bool {
+    if !dir.exists() && tokio::fs::create_dir_all(dir).await.is_err() {
+        return false;
+    }
+
+    let suffix: String = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    let probe = dir.join(format!(".readyz-{}", suffix));
+
+    let written = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .await
+        .is_ok();
+
+    let _ = tokio::fs::remove_file(&probe).await;
+
+    written
+}
+
+// Returns the reasons the server is not ready to receive traffic, an empty
+// list means it is ready
+pub async fn check_readiness() -> Vec<String> {
+    let mut failures = Vec::new();
+
+    if is_shutting_down() {
+        failures.push("server is shutting down".to_string());
+    }
+
+    if config::get().auth.jwt_secret.is_empty() {
+        failures.push("signing key is not loaded".to_string());
+    }
+
+    for dir in [Object::base_dir(), Metadata::base_dir(), AuthContext::base_dir()] {
+        if !probe_directory(&dir).await {
+            failures.push(format!("{} is not writable", dir.display()));
+        }
+    }
+
+    failures
+}
@@ -0,0 +1,591 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::metadata::Metadata;
+use crate::metrics::METRICS;
+use crate::storable::StorableBase;
+
+const LOG_FILE: &str = "metadata.log";
+// The log is rewritten once it holds this many more records than objects
+const COMPACTION_SLACK: usize = 1024;
+
+static INDEX: OnceLock<MetadataIndex> = OnceLock::new();
+
+// Identifies the version of a metadata file the index entry was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Stamp {
+    modified_ns: u64,
+    len: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+        Some(Self {
+            modified_ns: modified.as_nanos() as u64,
+            len: metadata.len(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Put { metadata: Box<Metadata>, stamp: Option<Stamp> },
+    Delete { key: String },
+}
+
+// Work for the log writer, handled in the order it was queued
+enum Command {
+    Append(String),
+    // Rewrites the log with these records, records queued later follow them
+    Compact(Vec<Record>),
+    Flush(Sender<()>),
+}
+
+struct Entry {
+    metadata: Metadata,
+    stamp: Option<Stamp>,
+}
+
+struct State {
+    by_key: BTreeMap<String, Entry>,
+    by_owner: HashMap<String, BTreeSet<String>>,
+    by_mime_type: HashMap<String, BTreeSet<String>>,
+    // Objects with an expiry ordered by it, so expiry only visits due ones
+    by_expiry: BTreeSet<(u64, String)>,
+    records: usize,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Put { metadata, stamp } => {
+                self.remove(&metadata.key);
+
+                let key = metadata.key.clone();
+                self.by_owner
+                    .entry(metadata.owner_id.clone())
+                    .or_default()
+                    .insert(key.clone());
+                self.by_mime_type
+                    .entry(metadata.mime_type.clone())
+                    .or_default()
+                    .insert(key.clone());
+                if let Some(expires_at) = metadata.expires_at {
+                    self.by_expiry.insert((expires_at, key.clone()));
+                }
+                self.by_key.insert(
+                    key,
+                    Entry {
+                        metadata: *metadata,
+                        stamp,
+                    },
+                );
+            }
+            Record::Delete { key } => self.remove(&key),
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(previous) = self.by_key.remove(key) else {
+            return;
+        };
+
+        if let Some(expires_at) = previous.metadata.expires_at {
+            self.by_expiry.remove(&(expires_at, key.to_string()));
+        }
+
+        for (index, value) in [
+            (&mut self.by_owner, &previous.metadata.owner_id),
+            (&mut self.by_mime_type, &previous.metadata.mime_type),
+        ] {
+            if let Some(keys) = index.get_mut(value) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    index.remove(value);
+                }
+            }
+        }
+    }
+
+    fn collect<'a>(&self, keys: impl Iterator<Item = &'a String>) -> Vec<Metadata> {
+        keys.filter_map(|key| self.by_key.get(key))
+            .map(|entry| entry.metadata.clone())
+            .collect()
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a String> {
+        self.by_key
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(move |key| key.starts_with(prefix))
+    }
+}
+
+// All metadata records held in memory, ordered by key with secondary indexes
+// by owner and mime type. The JSON files stay the source of truth: the index
+// is persisted to an append-only log so restarts don't have to parse every
+// file, and only files changed behind its back are read again on startup.
+// Like the session log it is written by a dedicated thread, lookups never
+// wait on the disk
+pub struct MetadataIndex {
+    metadata_dir: PathBuf,
+    state: Mutex<State>,
+    writer: Sender<Command>,
+}
+
+impl MetadataIndex {
+    pub fn open(dir: &Path, metadata_dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE);
+
+        let mut state = State {
+            by_key: BTreeMap::new(),
+            by_owner: HashMap::new(),
+            by_mime_type: HashMap::new(),
+            by_expiry: BTreeSet::new(),
+            records: 0,
+        };
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        for (number, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+            let line = line?;
+            // A crash while appending leaves at most the last line truncated
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) => {
+                    state.apply(record);
+                    state.records += 1;
+                }
+                Err(e) => warn!("{}:{}: skipping invalid record - {}", path.display(), number + 1, e),
+            }
+        }
+
+        let (writer, commands) = mpsc::channel();
+        thread::Builder::new()
+            .name("index-log".to_string())
+            .spawn(move || write_log(path, log, commands))?;
+
+        let index = Self {
+            metadata_dir: metadata_dir.to_path_buf(),
+            state: Mutex::new(state),
+            writer,
+        };
+
+        index.reconcile()?;
+        Ok(index)
+    }
+
+    // Brings the index in line with the metadata files, only those added or
+    // changed since they were indexed are parsed. The files are read without
+    // holding the lock, entries changed meanwhile are left as they are
+    pub fn reconcile(&self) -> std::io::Result<()> {
+        let indexed: HashMap<String, Option<Stamp>> = {
+            let state = self.state.lock().unwrap();
+            state.by_key.iter().map(|(key, entry)| (key.clone(), entry.stamp)).collect()
+        };
+        let mut hashes: HashMap<String, &String> = indexed
+            .keys()
+            .map(|key| (hex::encode(Sha256::digest(key.as_bytes())), key))
+            .collect();
+        let mut changed = Vec::new();
+        let mut seen = HashSet::new();
+
+        if self.metadata_dir.exists() {
+            for entry in fs::read_dir(&self.metadata_dir)? {
+                let path = entry?.path();
+                let Some(hash) = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_suffix(".json"))
+                else {
+                    continue;
+                };
+
+                let stamp = Stamp::of(&path);
+                let key = hashes.remove(hash);
+                let current = key
+                    .and_then(|key| indexed.get(key))
+                    .is_some_and(|indexed| indexed.is_some() && *indexed == stamp);
+
+                if current {
+                    continue;
+                }
+
+                match fs::read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|contents| serde_json::from_str::<Metadata>(&contents).map_err(|e| e.to_string()))
+                {
+                    Ok(metadata) => {
+                        seen.insert(metadata.key.clone());
+                        changed.push(Record::Put {
+                            metadata: Box::new(metadata),
+                            stamp,
+                        });
+                    }
+                    Err(e) => {
+                        warn!("{}: skipping corrupt record - {}", path.display(), e);
+                        METRICS.corrupt_record();
+                        if let Some(key) = key {
+                            changed.push(Record::Delete { key: key.clone() });
+                        }
+                    }
+                }
+            }
+        }
+
+        // Whatever is left has no file anymore
+        for key in hashes.into_values().filter(|key| !seen.contains(*key)) {
+            changed.push(Record::Delete { key: key.clone() });
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let unchanged = |state: &State, key: &str| state.by_key.get(key).map(|entry| entry.stamp) == indexed.get(key).copied();
+        changed.retain(|record| match record {
+            Record::Put { metadata, .. } => unchanged(&state, &metadata.key),
+            Record::Delete { key } => unchanged(&state, key),
+        });
+
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        info!("Updated {} metadata index entries from {}", changed.len(), self.metadata_dir.display());
+        let mut queued = Ok(());
+        for record in changed {
+            queued = queued.and(self.append(&mut state, record));
+        }
+
+        queued
+    }
+
+    fn send(&self, command: Command) -> std::io::Result<()> {
+        self.writer
+            .send(command)
+            .map_err(|_| std::io::Error::other("metadata log writer stopped"))
+    }
+
+    // Applies a record and queues it for the log. The record is applied even
+    // when it can't be queued: the metadata files are the source of truth,
+    // a record missing from the log only means the file is read again on
+    // the next startup
+    fn append(&self, state: &mut State, record: Record) -> std::io::Result<()> {
+        let queued = serde_json::to_string(&record)
+            .map_err(std::io::Error::other)
+            .and_then(|mut line| {
+                line.push('\n');
+                // Queued while holding the lock so the log sees the same
+                // order as the in-memory state
+                self.send(Command::Append(line))
+            });
+        state.apply(record);
+        state.records += 1;
+
+        if state.records > state.by_key.len() * 2 + COMPACTION_SLACK {
+            self.compact(state)?;
+        }
+
+        queued
+    }
+
+    // Queues a rewrite of the log with one record per object
+    fn compact(&self, state: &mut State) -> std::io::Result<()> {
+        let records = state
+            .by_key
+            .values()
+            .map(|entry| Record::Put {
+                metadata: Box::new(entry.metadata.clone()),
+                stamp: entry.stamp,
+            })
+            .collect();
+        self.send(Command::Compact(records))?;
+        state.records = state.by_key.len();
+
+        Ok(())
+    }
+
+    // Blocks until everything queued so far has been written to the log
+    pub fn flush(&self) -> std::io::Result<()> {
+        let (done, wait) = mpsc::channel();
+        self.send(Command::Flush(done))?;
+
+        wait.recv()
+            .map_err(|_| std::io::Error::other("metadata log writer stopped"))
+    }
+
+    // Records a metadata file that was just written. The entry is updated
+    // even when this fails, the error only means the log fell behind
+    pub fn put(&self, metadata: &Metadata) -> std::io::Result<()> {
+        let file = self
+            .metadata_dir
+            .join(format!("{}.json", hex::encode(Sha256::digest(metadata.key.as_bytes()))));
+        let record = Record::Put {
+            metadata: Box::new(metadata.clone()),
+            stamp: Stamp::of(&file),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        self.append(&mut state, record)
+    }
+
+    pub fn remove(&self, key: &str) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.by_key.contains_key(key) {
+            return Ok(());
+        }
+
+        self.append(&mut state, Record::Delete { key: key.to_string() })
+    }
+
+    pub fn get(&self, key: &str) -> Option<Metadata> {
+        let state = self.state.lock().unwrap();
+        state.by_key.get(key).map(|entry| entry.metadata.clone())
+    }
+
+    // Every object ordered by key
+    pub fn list(&self) -> Vec<Metadata> {
+        let state = self.state.lock().unwrap();
+        state.collect(state.by_key.keys())
+    }
+
+    // Objects whose key starts with `prefix`, ordered by key
+    pub fn with_prefix(&self, prefix: &str) -> Vec<Metadata> {
+        let state = self.state.lock().unwrap();
+        state.collect(state.keys_with_prefix(prefix))
+    }
+
+    // Up to `limit` objects whose key starts with `prefix` and sorts after
+    // `after`, ordered by key
+    pub fn with_prefix_after(&self, prefix: &str, after: &str, limit: usize) -> Vec<Metadata> {
+        let state = self.state.lock().unwrap();
+        let start = match after >= prefix {
+            true => Bound::Excluded(after),
+            false => Bound::Included(prefix),
+        };
+
+        let keys = state
+            .by_key
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit);
+        state.collect(keys)
+    }
+
+    // Number and total size of the objects whose key starts with `prefix`
+    pub fn prefix_totals(&self, prefix: &str) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        state
+            .keys_with_prefix(prefix)
+            .filter_map(|key| state.by_key.get(key))
+            .fold((0, 0), |(count, bytes), entry| (count + 1, bytes + entry.metadata.size))
+    }
+
+    pub fn owned_by(&self, owner_id: &str) -> Vec<Metadata> {
+        let state = self.state.lock().unwrap();
+        state.collect(state.by_owner.get(owner_id).into_iter().flatten())
+    }
+
+    pub fn with_mime_type(&self, mime_type: &str) -> Vec<Metadata> {
+        let state = self.state.lock().unwrap();
+        state.collect(state.by_mime_type.get(mime_type).into_iter().flatten())
+    }
+
+    // Objects whose expiry is due at `now`, soonest first
+    pub fn expired(&self, now: u64) -> Vec<Metadata> {
+        let state = self.state.lock().unwrap();
+        let due = state
+            .by_expiry
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .map(|(_, key)| key);
+        state.collect(due)
+    }
+
+    // Etags of every object
+    pub fn etags(&self) -> HashSet<String> {
+        let state = self.state.lock().unwrap();
+        state.by_key.values().map(|entry| entry.metadata.etag.clone()).collect()
+    }
+
+    pub fn count_owned_by(&self, owner_id: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.by_owner.get(owner_id).map_or(0, BTreeSet::len)
+    }
+
+    // Number of objects and their total size
+    pub fn totals(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        let bytes = state.by_key.values().map(|entry| entry.metadata.size).sum();
+        (state.by_key.len() as u64, bytes)
+    }
+
+    // Number of objects of each mime type
+    pub fn mime_type_counts(&self) -> BTreeMap<String, u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .by_mime_type
+            .iter()
+            .map(|(mime_type, keys)| (mime_type.clone(), keys.len() as u64))
+            .collect()
+    }
+}
+
+fn write_log(path: PathBuf, mut log: File, commands: Receiver<Command>) {
+    for command in commands {
+        match command {
+            Command::Append(line) => {
+                if let Err(e) = log.write_all(line.as_bytes()) {
+                    warn!("{}: failed to append metadata record - {}", path.display(), e);
+                }
+            }
+            Command::Compact(records) => match rewrite_log(&path, &records) {
+                Ok(file) => log = file,
+                Err(e) => warn!("{}: failed to compact - {}", path.display(), e),
+            },
+            Command::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+// Rewrites the log with the records and returns it reopened for appending
+fn rewrite_log(path: &Path, records: &[Record]) -> std::io::Result<File> {
+    let temporary = path.with_extension("log.tmp");
+    let mut file = File::create(&temporary)?;
+
+    for record in records {
+        let mut line = serde_json::to_string(record).map_err(std::io::Error::other)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+    }
+
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+
+    OpenOptions::new().append(true).open(path)
+}
+
+// Loads the index, must be called once before objects are accessed
+pub fn init() -> std::io::Result<()> {
+    let dir = config::get().data_dir.join("index");
+    let index = MetadataIndex::open(&dir, &Metadata::base_dir())?;
+    let _ = INDEX.set(index);
+    Ok(())
+}
+
+pub fn get() -> &'static MetadataIndex {
+    INDEX.get().expect("metadata index is initialized at startup")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::AuthLevel;
+
+    fn metadata(key: &str, owner_id: &str, mime_type: &str) -> Metadata {
+        Metadata {
+            name: key.to_string(),
+            key: key.to_string(),
+            size: 10,
+            last_modified: 0,
+            etag: String::new(),
+            mime_type: mime_type.to_string(),
+            owner_id: owner_id.to_string(),
+            readable_by: AuthLevel::Public,
+            expires_at: None,
+            stored_encoding: None,
+            encryption: None,
+            checksums: None,
+            user_metadata: BTreeMap::new(),
+            retain_until: None,
+            legal_hold: false,
+        }
+    }
+
+    fn write(dir: &Path, metadata: &Metadata) {
+        let path = dir.join(format!("{}.json", hex::encode(Sha256::digest(metadata.key.as_bytes()))));
+        fs::write(path, serde_json::to_string(metadata).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_replay_and_reconcile() {
+        let dir = std::env::temp_dir().join(format!("a-bucket-index-{}", std::process::id()));
+        let metadata_dir = dir.join("metadata");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&metadata_dir).unwrap();
+
+        for (key, owner_id, mime_type) in [("b", "alice", "text/plain"), ("a", "bob", "image/png")] {
+            write(&metadata_dir, &metadata(key, owner_id, mime_type));
+        }
+
+        let index = MetadataIndex::open(&dir.join("index"), &metadata_dir).unwrap();
+        let keys = |list: Vec<Metadata>| list.into_iter().map(|m| m.key).collect::<Vec<_>>();
+        assert_eq!(keys(index.list()), ["a", "b"]);
+
+        let c = metadata("c", "alice", "text/plain");
+        write(&metadata_dir, &c);
+        index.put(&c).unwrap();
+        assert_eq!(keys(index.owned_by("alice")), ["b", "c"]);
+        assert_eq!(index.count_owned_by("bob"), 1);
+
+        fs::remove_file(metadata_dir.join(format!("{}.json", hex::encode(Sha256::digest(b"a"))))).unwrap();
+        index.remove("a").unwrap();
+        assert_eq!(index.count_owned_by("bob"), 0);
+        assert!(!index.mime_type_counts().contains_key("image/png"));
+
+        // Changes made while the index was closed are picked up
+        index.flush().unwrap();
+        drop(index);
+        write(&metadata_dir, &metadata("d", "bob", "text/plain"));
+        fs::remove_file(metadata_dir.join(format!("{}.json", hex::encode(Sha256::digest(b"b"))))).unwrap();
+
+        let index = MetadataIndex::open(&dir.join("index"), &metadata_dir).unwrap();
+        assert_eq!(keys(index.list()), ["c", "d"]);
+        assert_eq!(keys(index.with_mime_type("text/plain")), ["c", "d"]);
+        assert_eq!(keys(index.with_prefix("c")), ["c"]);
+        assert_eq!(index.totals(), (2, 20));
+
+        let expiring = Metadata {
+            expires_at: Some(100),
+            ..metadata("e", "bob", "text/plain")
+        };
+        write(&metadata_dir, &expiring);
+        index.put(&expiring).unwrap();
+        assert_eq!(keys(index.expired(99)), Vec::<String>::new());
+        assert_eq!(keys(index.expired(100)), ["e"]);
+        fs::remove_file(metadata_dir.join(format!("{}.json", hex::encode(Sha256::digest(b"e"))))).unwrap();
+        index.remove("e").unwrap();
+        assert!(index.expired(100).is_empty());
+
+        // The writer thread compacts the log once it grows stale
+        for _ in 0..COMPACTION_SLACK + 8 {
+            index.put(&c).unwrap();
+        }
+        index.flush().unwrap();
+        let records = fs::read_to_string(dir.join("index").join(LOG_FILE)).unwrap().lines().count();
+        assert!(records < COMPACTION_SLACK);
+        drop(index);
+
+        let index = MetadataIndex::open(&dir.join("index"), &metadata_dir).unwrap();
+        assert_eq!(keys(index.list()), ["c", "d"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,15 +1,201 @@
+mod audit;
 mod authentication;
+mod batch;
+mod buckets;
+mod checksum;
+mod compression;
+mod config;
+mod connections;
+mod cors;
+mod encryption;
+mod fsck;
+mod health;
+mod index;
+mod logging;
+mod maintenance;
 mod metadata;
+mod metrics;
+mod multipart;
+mod proxy;
+mod ratelimit;
+mod s3;
+mod search;
 mod server;
+mod sessions;
+mod shutdown;
+mod sigv4;
 mod storable;
 mod storage;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod transfer;
+mod trash;
 
-use server::Server;
+use std::time::Duration;
 
-const TMP_PATH: &str = "/tmp";
+use config::{CliOptions, Command, Config};
+use server::{ListenerKind, Server};
+use tls::CertificateStore;
+use tokio::sync::watch;
 
 #[tokio::main]
 async fn main() {
-    let server = Server::new("0.0.0.0:8000").await;
-    server.run().await;
+    let options = match CliOptions::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, CliOptions::usage());
+            std::process::exit(2);
+        }
+    };
+
+    if options.help {
+        print!("{}", CliOptions::usage());
+        return;
+    }
+
+    let config = match Config::load(&options) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("Invalid configuration:");
+            for error in errors {
+                eprintln!("  {}", error);
+            }
+            std::process::exit(2);
+        }
+    };
+
+    if options.print_config {
+        print!("{}", config.to_redacted_toml());
+        return;
+    }
+
+    logging::init(config.logging.level);
+    config::init(config);
+
+    let config = config::get();
+
+    if let Err(error) = index::init() {
+        log::error!("Failed to load the metadata index: {}", error);
+        std::process::exit(1);
+    }
+
+    if options.command == Command::RotateKeys {
+        match encryption::rotate_keys().await {
+            Ok(_) => return,
+            Err(failed) => {
+                log::error!("Failed to rotate the keys of {} object(s)", failed);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if options.command == Command::Fsck {
+        match fsck::run(options.repair).await {
+            Ok(report) => {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                if report.unresolved() > 0 {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            Err(error) => {
+                log::error!("fsck failed: {}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Err(error) = sessions::init() {
+        log::error!("Failed to load sessions: {}", error);
+        std::process::exit(1);
+    }
+
+    if let Err(error) = buckets::init().await {
+        log::error!("Failed to load buckets: {}", error);
+        std::process::exit(1);
+    }
+    let limits = config.connection_limits();
+    let grace_period = Duration::from_secs(config.timeouts.shutdown_grace_secs);
+
+    let mut servers = Vec::new();
+    for address in &config.listen {
+        servers.push(Server::new(address, ListenerKind::Storage, limits.clone()).await);
+    }
+
+    if !config.tls.listen.is_empty() {
+        let acceptor = match CertificateStore::load(&config.tls.certificates)
+            .and_then(|store| store.acceptor().map(|acceptor| (store, acceptor)))
+        {
+            Ok((store, acceptor)) => {
+                if config.tls.reload_interval_secs > 0 {
+                    let interval = Duration::from_secs(config.tls.reload_interval_secs);
+                    tokio::spawn(store.watch(interval));
+                }
+                acceptor
+            }
+            Err(error) => {
+                log::error!("Failed to load TLS certificates: {}", error);
+                std::process::exit(2);
+            }
+        };
+
+        for address in &config.tls.listen {
+            let server = Server::new(address, ListenerKind::Storage, limits.clone()).await;
+            servers.push(server.with_tls(acceptor.clone()));
+        }
+    }
+
+    // The S3-compatible API drains along with the storage listeners
+    if !config.s3.listen.is_empty() {
+        servers.push(Server::new(&config.s3.listen, ListenerKind::S3, limits.clone()).await);
+    }
+
+    let admin = if config.admin_listen.is_empty() {
+        None
+    } else {
+        Some(Server::new(&config.admin_listen, ListenerKind::Admin, limits.clone()).await)
+    };
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (admin_shutdown_tx, admin_shutdown_rx) = watch::channel(false);
+
+    tokio::spawn(async move {
+        shutdown::wait_for_signal().await;
+        // Fail readiness first so proxies stop routing to us while we drain
+        health::set_shutting_down();
+        let _ = shutdown_tx.send(true);
+    });
+
+    tokio::spawn(maintenance::run(maintenance::tasks(config), shutdown_rx.clone()));
+
+    // The admin listener keeps answering /readyz and /metrics while the
+    // storage listeners drain and is stopped last
+    let admin_handle = admin.map(|admin| tokio::spawn(admin.run(admin_shutdown_rx, grace_period)));
+
+    let storage_handles: Vec<_> = servers
+        .into_iter()
+        .map(|server| tokio::spawn(server.run(shutdown_rx.clone(), grace_period)))
+        .collect();
+
+    for handle in storage_handles {
+        let _ = handle.await;
+    }
+
+    let _ = admin_shutdown_tx.send(true);
+    if let Some(handle) = admin_handle {
+        let _ = handle.await;
+    }
+
+    // Session and index records are written in the background, wait for
+    // the queues
+    if let Ok(Err(error)) = tokio::task::spawn_blocking(|| sessions::get().flush()).await {
+        log::error!("Failed to flush the session log: {}", error);
+    }
+    if let Ok(Err(error)) = tokio::task::spawn_blocking(|| index::get().flush()).await {
+        log::error!("Failed to flush the metadata index log: {}", error);
+    }
+
+    log::info!("Shutdown complete");
+    log::logger().flush();
 }
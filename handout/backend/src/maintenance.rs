@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info};
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::compression;
+use crate::config::{self, Config};
+use crate::fsck;
+use crate::multipart;
+use crate::index;
+use crate::sessions;
+use crate::storage::Storage;
+use crate::trash;
+
+// Blobs younger than this may belong to an upload still being written
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(10 * 60);
+// How long running tasks get to stop at a safe point when shutting down
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+type TaskFuture = Pin<Box<dyn Future<Output = Result<String, String>> + Send>>;
+
+// A named job run on its own interval, it returns a short summary of what
+// it did or the reason it failed. Jobs should stop early once the receiver
+// they are given flips to true
+pub struct Task {
+    pub name: &'static str,
+    pub interval: Duration,
+    pub run: fn(watch::Receiver<bool>) -> TaskFuture,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskStatus {
+    pub interval_secs: u64,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    // Unix time the last run started at
+    pub last_run: Option<u64>,
+    pub last_duration_ms: Option<u64>,
+    pub last_success: Option<bool>,
+    pub last_message: Option<String>,
+}
+
+static STATUS: Mutex<BTreeMap<&'static str, TaskStatus>> = Mutex::new(BTreeMap::new());
+
+pub fn statuses() -> Vec<(&'static str, TaskStatus)> {
+    STATUS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, status)| (*name, status.clone()))
+        .collect()
+}
+
+// The maintenance tasks enabled in `config`, an interval of zero disables one
+pub fn tasks(config: &Config) -> Vec<Task> {
+    let intervals = &config.maintenance;
+
+    let tasks = [
+        Task {
+            name: "sessions",
+            interval: Duration::from_secs(intervals.sessions_interval_secs),
+            run: |_| Box::pin(expire_sessions()),
+        },
+        Task {
+            name: "expired-objects",
+            interval: Duration::from_secs(intervals.expired_objects_interval_secs),
+            run: |shutdown| Box::pin(remove_expired_objects(shutdown)),
+        },
+        Task {
+            name: "orphaned-blobs",
+            interval: Duration::from_secs(intervals.orphaned_blobs_interval_secs),
+            run: |_| Box::pin(remove_orphaned_blobs()),
+        },
+        Task {
+            name: "fsck",
+            interval: Duration::from_secs(intervals.fsck_interval_secs),
+            run: |_| Box::pin(check_consistency()),
+        },
+        Task {
+            name: "trash",
+            interval: Duration::from_secs(intervals.trash_interval_secs),
+            run: |shutdown| Box::pin(purge_trash(shutdown)),
+        },
+        Task {
+            name: "multipart",
+            interval: Duration::from_secs(intervals.multipart_interval_secs),
+            run: |shutdown| Box::pin(abort_stale_uploads(shutdown)),
+        },
+    ];
+
+    tasks
+        .into_iter()
+        .filter(|task| !task.interval.is_zero())
+        .collect()
+}
+
+// Runs every task on its interval until `shutdown` flips to true. A run in
+// progress gets a moment to stop by itself and is abandoned after that, all
+// tasks are safe to restart
+pub async fn run(tasks: Vec<Task>, mut shutdown: watch::Receiver<bool>) {
+    let mut running = JoinSet::new();
+
+    for task in tasks {
+        STATUS.lock().unwrap().insert(
+            task.name,
+            TaskStatus {
+                interval_secs: task.interval.as_secs(),
+                ..Default::default()
+            },
+        );
+
+        running.spawn(run_task(task, shutdown.clone()));
+    }
+
+    loop {
+        match shutdown.changed().await {
+            Ok(_) if !*shutdown.borrow() => continue,
+            _ => break,
+        }
+    }
+
+    let stopped = async { while running.join_next().await.is_some() {} };
+    if tokio::time::timeout(STOP_TIMEOUT, stopped).await.is_err() {
+        running.shutdown().await;
+    }
+}
+
+async fn run_task(task: Task, mut shutdown: watch::Receiver<bool>) {
+    let mut ticker = tokio::time::interval(task.interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.wait_for(|stop| *stop) => return,
+        }
+
+        let started = Instant::now();
+        update(task.name, |status| {
+            status.running = true;
+            status.last_run = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            );
+        });
+
+        let result = (task.run)(shutdown.clone()).await;
+
+        match &result {
+            Ok(message) => debug!("maintenance {}: {}", task.name, message),
+            Err(message) => error!("maintenance {}: failed - {}", task.name, message),
+        }
+
+        update(task.name, |status| {
+            status.running = false;
+            status.runs += 1;
+            status.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+            status.last_success = Some(result.is_ok());
+            if result.is_err() {
+                status.failures += 1;
+            }
+            status.last_message = Some(match result {
+                Ok(message) | Err(message) => message,
+            });
+        });
+    }
+}
+
+fn update(name: &'static str, change: impl FnOnce(&mut TaskStatus)) {
+    if let Some(status) = STATUS.lock().unwrap().get_mut(name) {
+        change(status);
+    }
+}
+
+// Removes sessions unused for longer than the session TTL unless they are
+// admins or still own objects
+async fn expire_sessions() -> Result<String, String> {
+    let store = sessions::get();
+    let mut removed = 0;
+
+    for auth_context in store.expired(config::get().sessions.ttl_secs) {
+        if index::get().count_owned_by(&auth_context.access_key) > 0 {
+            continue;
+        }
+
+        store
+            .remove(&auth_context.access_key)
+            .map_err(|e| format!("{}: failed to delete auth context - {}", auth_context.access_key, e))?;
+        removed += 1;
+    }
+
+    if removed > 0 {
+        info!("Expired {} session(s)", removed);
+    }
+
+    Ok(format!("removed {} session(s)", removed))
+}
+
+async fn remove_expired_objects(shutdown: watch::Receiver<bool>) -> Result<String, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // A lock outlasts the expiry, the object goes once it is lifted
+    let expired = index::get().expired(now).into_iter().filter(|metadata| !metadata.is_locked(now));
+
+    let mut removed = 0;
+    for metadata in expired {
+        if *shutdown.borrow() {
+            break;
+        }
+
+        let key = metadata.key.clone();
+
+        if Storage::remove_object(metadata).await {
+            removed += 1;
+        } else {
+            error!("{}: failed to remove expired object", key);
+        }
+    }
+
+    Ok(format!("removed {} expired object(s)", removed))
+}
+
+async fn remove_orphaned_blobs() -> Result<String, String> {
+    let removed = Storage::remove_orphaned_blobs(ORPHAN_MIN_AGE)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Compressed variants are keyed by content, keep those of any object
+    let etags = index::get().etags();
+    let variants = compression::remove_stale_variants(&etags, ORPHAN_MIN_AGE)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if removed > 0 || variants > 0 {
+        info!("Removed {} orphaned blob(s) and {} stale variant(s)", removed, variants);
+    }
+
+    Ok(format!("removed {} orphaned blob(s) and {} stale variant(s)", removed, variants))
+}
+
+async fn purge_trash(shutdown: watch::Receiver<bool>) -> Result<String, String> {
+    let (purged, skipped) = trash::purge_expired(shutdown).await.map_err(|e| e.to_string())?;
+
+    if purged > 0 {
+        info!("Purged {} object(s) from the trash", purged);
+    }
+
+    Ok(format!("purged {} object(s), skipped {} corrupt record(s)", purged, skipped))
+}
+
+async fn abort_stale_uploads(shutdown: watch::Receiver<bool>) -> Result<String, String> {
+    let max_age_secs = config::get().s3.upload_expiry_secs;
+    let (removed, skipped) = multipart::remove_stale(max_age_secs, shutdown)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if removed > 0 {
+        info!("Aborted {} stale multipart upload(s)", removed);
+    }
+
+    Ok(format!("aborted {} upload(s), skipped {} corrupt record(s)", removed, skipped))
+}
+
+// The full report is written next to the data, see fsck::report_path. The
+// server keeps writing objects meanwhile, so this only reports: repairs are
+// left to `a-bucket fsck --repair` while it is stopped
+async fn check_consistency() -> Result<String, String> {
+    let report = fsck::run(false)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let message = format!(
+        "found {} issue(s), {} unresolved",
+        report.issues.len(),
+        report.unresolved()
+    );
+
+    match report.unresolved() {
+        0 => Ok(message),
+        _ => Err(message),
+    }
+}
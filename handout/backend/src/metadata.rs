@@ -1,10 +1,12 @@
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 
+use a_http_parser::response::Response;
 use serde::{Deserialize, Serialize};
 
-use crate::{storable::{StorableBase, StorableJson}, authentication::AuthLevel, TMP_PATH};
+use crate::{storable::{StorableBase, StorableJson}, authentication::AuthLevel, checksum::Checksums, config, encryption::EncryptionInfo};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Metadata {
     pub name: String,
     pub key: String,
@@ -14,11 +16,143 @@ pub struct Metadata {
     pub mime_type: String,
     pub owner_id: String,
     pub readable_by: AuthLevel,
+    // Unix time after which the object is removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    // Set when the blob is stored compressed, size and etag always describe
+    // the uncompressed contents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_encoding: Option<String>,
+    // Set when the blob is encrypted, after any compression
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionInfo>,
+    // Digests of the contents besides the etag, missing on older objects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksums: Option<Checksums>,
+    // Set by the client with X-Meta-* headers, names are lowercase
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub user_metadata: BTreeMap<String, String>,
+    // Unix time before which the object can't be overwritten or deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retain_until: Option<u64>,
+    // Blocks overwrites and deletes until an admin lifts it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub legal_hold: bool,
+}
+
+impl Metadata {
+    // Whether the blob on disk differs from the contents of the object
+    pub fn is_transformed(&self) -> bool {
+        self.stored_encoding.is_some() || self.encryption.is_some()
+    }
+
+    // Objects sealed with a customer key keep nothing derived from their
+    // contents, their etag is the digest of the blob on disk
+    pub fn is_customer_encrypted(&self) -> bool {
+        self.encryption.as_ref().is_some_and(|info| info.is_customer_key())
+    }
+}
+
+impl Metadata {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_retained(&self, now: u64) -> bool {
+        self.retain_until.is_some_and(|retain_until| retain_until > now)
+    }
+
+    // Whether a retention or a legal hold protects the object
+    pub fn is_locked(&self, now: u64) -> bool {
+        self.legal_hold || self.is_retained(now)
+    }
+}
+
+pub const USER_METADATA_PREFIX: &str = "x-meta-";
+// Combined size of the names and values a client may attach to an object
+const MAX_USER_METADATA_SIZE: usize = 2048;
+
+// Collects the headers of an upload named `prefix` and the metadata name,
+// X-Meta-* for the native API
+pub fn user_metadata_from_headers(
+    headers: &HashMap<String, String>,
+    prefix: &str,
+) -> Result<BTreeMap<String, String>, &'static str> {
+    let user_metadata: BTreeMap<_, _> = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.strip_prefix(prefix)?;
+            Some((name.to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+
+    if user_metadata.keys().any(String::is_empty) {
+        return Err("Invalid user metadata");
+    }
+
+    let size: usize = user_metadata.iter().map(|(name, value)| name.len() + value.len()).sum();
+    if size > MAX_USER_METADATA_SIZE {
+        return Err("User metadata too large");
+    }
+
+    Ok(user_metadata)
+}
+
+// Returns the user metadata of an object as headers named `prefix` and the
+// metadata name
+pub fn set_user_metadata_headers(res: &mut Response, metadata: &Metadata, prefix: &str) {
+    for (name, value) in &metadata.user_metadata {
+        res.set_header(&format!("{}{}", prefix, name), value);
+    }
+}
+
+// Lock settings sent with X-Lock-Retain-Until and X-Legal-Hold, None leaves
+// a setting as it is. A retention of 0 removes it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockRequest {
+    pub retain_until: Option<u64>,
+    pub legal_hold: Option<bool>,
+}
+
+impl LockRequest {
+    pub fn from_headers(headers: &HashMap<String, String>) -> Result<Self, &'static str> {
+        let retain_until = match headers.get("x-lock-retain-until") {
+            Some(value) => Some(value.trim().parse::<u64>().map_err(|_| "Invalid X-Lock-Retain-Until")?),
+            None => None,
+        };
+
+        let legal_hold = match headers.get("x-legal-hold").map(|value| value.trim().to_ascii_uppercase()) {
+            Some(value) if value == "ON" => Some(true),
+            Some(value) if value == "OFF" => Some(false),
+            Some(_) => return Err("X-Legal-Hold must be ON or OFF"),
+            None => None,
+        };
+
+        Ok(Self {
+            retain_until,
+            legal_hold,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.retain_until.is_none() && self.legal_hold.is_none()
+    }
+}
+
+// Returns the lock of an object as X-Lock-Retain-Until and X-Legal-Hold
+pub fn set_lock_headers(res: &mut Response, metadata: &Metadata) {
+    if let Some(retain_until) = metadata.retain_until {
+        res.set_header("x-lock-retain-until", &retain_until.to_string());
+    }
+
+    if metadata.legal_hold {
+        res.set_header("x-legal-hold", "ON");
+    }
 }
 
 impl StorableBase for Metadata {
     fn base_dir() -> PathBuf {
-        format!("{}/metadata", TMP_PATH).into()
+        config::get().data_dir.join("metadata")
     }
 
     fn id(&self) -> &str {
@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::index;
+use crate::maintenance::{self, TaskStatus};
+use crate::sessions;
+
+// Upper bounds (in seconds) of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    active_connections: AtomicI64,
+    rejected_connections: AtomicU64,
+    rate_limited: Mutex<BTreeMap<&'static str, u64>>,
+    rate_limit_evictions: AtomicU64,
+    compressed: Mutex<BTreeMap<&'static str, u64>>,
+    variant_cache_hits: AtomicU64,
+    variant_cache_misses: AtomicU64,
+    corrupt_records: AtomicU64,
+}
+
+// Values owned by other parts of the server, collected when the metrics are
+// scraped
+#[derive(Default)]
+pub struct StorageStats {
+    pub objects: u64,
+    pub bytes: u64,
+    pub objects_by_mime_type: BTreeMap<String, u64>,
+    pub auth_contexts: u64,
+    pub maintenance: Vec<(&'static str, TaskStatus)>,
+}
+
+impl StorageStats {
+    pub async fn collect() -> Self {
+        let mut stats = Self::default();
+
+        (stats.objects, stats.bytes) = index::get().totals();
+        stats.objects_by_mime_type = index::get().mime_type_counts();
+
+        stats.auth_contexts = sessions::get().len() as u64;
+        stats.maintenance = maintenance::statuses();
+
+        stats
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            latency: Mutex::new(BTreeMap::new()),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            active_connections: AtomicI64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            rate_limited: Mutex::new(BTreeMap::new()),
+            rate_limit_evictions: AtomicU64::new(0),
+            compressed: Mutex::new(BTreeMap::new()),
+            variant_cache_hits: AtomicU64::new(0),
+            variant_cache_misses: AtomicU64::new(0),
+            corrupt_records: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_request(&self, method: &'static str, status_code: u16, duration: Duration) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method, status_code))
+            .or_insert(0) += 1;
+
+        self.latency
+            .lock()
+            .unwrap()
+            .entry(method)
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn add_bytes_received(&self, count: usize) {
+        self.bytes_received.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_sent(&self, count: usize) {
+        self.bytes_sent.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request_rate_limited(&self, class: &'static str) {
+        *self.rate_limited.lock().unwrap().entry(class).or_insert(0) += 1;
+    }
+
+    pub fn rate_limit_buckets_evicted(&self, count: usize) {
+        self.rate_limit_evictions.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn response_compressed(&self, encoding: &'static str) {
+        *self.compressed.lock().unwrap().entry(encoding).or_insert(0) += 1;
+    }
+
+    pub fn variant_cache_hit(&self) {
+        self.variant_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn variant_cache_miss(&self) {
+        self.variant_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn corrupt_record(&self) {
+        self.corrupt_records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self, stats: &StorageStats) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP a_bucket_http_requests_total Total number of HTTP requests handled.\n");
+        out.push_str("# TYPE a_bucket_http_requests_total counter\n");
+        for ((method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "a_bucket_http_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, count
+            );
+        }
+
+        out.push_str("# HELP a_bucket_http_request_duration_seconds Time spent handling HTTP requests.\n");
+        out.push_str("# TYPE a_bucket_http_request_duration_seconds histogram\n");
+        for (method, histogram) in self.latency.lock().unwrap().iter() {
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "a_bucket_http_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    method, bound, count
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "a_bucket_http_request_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+                method, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "a_bucket_http_request_duration_seconds_sum{{method=\"{}\"}} {}",
+                method, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "a_bucket_http_request_duration_seconds_count{{method=\"{}\"}} {}",
+                method, histogram.count
+            );
+        }
+
+        out.push_str("# HELP a_bucket_rate_limited_requests_total Requests refused because a rate limit was exhausted.\n");
+        out.push_str("# TYPE a_bucket_rate_limited_requests_total counter\n");
+        for (class, count) in self.rate_limited.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "a_bucket_rate_limited_requests_total{{class=\"{}\"}} {}",
+                class, count
+            );
+        }
+
+        out.push_str("# HELP a_bucket_compressed_responses_total Responses sent compressed.\n");
+        out.push_str("# TYPE a_bucket_compressed_responses_total counter\n");
+        for (encoding, count) in self.compressed.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "a_bucket_compressed_responses_total{{encoding=\"{}\"}} {}",
+                encoding, count
+            );
+        }
+
+        out.push_str("# HELP a_bucket_storage_objects_by_type Number of objects in storage by mime type.\n");
+        out.push_str("# TYPE a_bucket_storage_objects_by_type gauge\n");
+        for (mime_type, count) in &stats.objects_by_mime_type {
+            let _ = writeln!(
+                out,
+                "a_bucket_storage_objects_by_type{{mime_type=\"{}\"}} {}",
+                mime_type, count
+            );
+        }
+
+        type StatusValue = fn(&TaskStatus) -> u64;
+        let maintenance: [(&str, &str, &str, StatusValue); 4] = [
+            (
+                "a_bucket_maintenance_runs_total",
+                "counter",
+                "Completed runs of a maintenance task.",
+                |status| status.runs,
+            ),
+            (
+                "a_bucket_maintenance_failures_total",
+                "counter",
+                "Failed runs of a maintenance task.",
+                |status| status.failures,
+            ),
+            (
+                "a_bucket_maintenance_last_run_timestamp_seconds",
+                "gauge",
+                "Unix time the last run of a maintenance task started.",
+                |status| status.last_run.unwrap_or(0),
+            ),
+            (
+                "a_bucket_maintenance_last_success",
+                "gauge",
+                "Whether the last run of a maintenance task succeeded.",
+                |status| status.last_success.unwrap_or(false) as u64,
+            ),
+        ];
+
+        for (name, kind, help, value) in maintenance {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (task, status) in &stats.maintenance {
+                let _ = writeln!(out, "{}{{task=\"{}\"}} {}", name, task, value(status));
+            }
+        }
+
+        let scalars: [(&str, &str, &str, i64); 11] = [
+            (
+                "a_bucket_http_received_bytes_total",
+                "counter",
+                "Total number of bytes read from clients.",
+                self.bytes_received.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "a_bucket_http_sent_bytes_total",
+                "counter",
+                "Total number of bytes written to clients.",
+                self.bytes_sent.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "a_bucket_active_connections",
+                "gauge",
+                "Number of connections currently being served.",
+                self.active_connections.load(Ordering::Relaxed),
+            ),
+            (
+                "a_bucket_rejected_connections_total",
+                "counter",
+                "Connections refused because their address hit the connection limit.",
+                self.rejected_connections.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "a_bucket_rate_limit_evictions_total",
+                "counter",
+                "Rate limit buckets forgotten before they refilled because too many were tracked.",
+                self.rate_limit_evictions.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "a_bucket_variant_cache_hits_total",
+                "counter",
+                "Compressed responses served from a cached variant.",
+                self.variant_cache_hits.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "a_bucket_variant_cache_misses_total",
+                "counter",
+                "Compressed responses that had to be compressed on the fly.",
+                self.variant_cache_misses.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "a_bucket_corrupt_records_total",
+                "counter",
+                "Unreadable or invalid records skipped while listing.",
+                self.corrupt_records.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "a_bucket_storage_objects",
+                "gauge",
+                "Number of objects in storage.",
+                stats.objects as i64,
+            ),
+            (
+                "a_bucket_storage_bytes",
+                "gauge",
+                "Total size of all objects in storage.",
+                stats.bytes as i64,
+            ),
+            (
+                "a_bucket_auth_contexts",
+                "gauge",
+                "Number of stored authentication contexts.",
+                stats.auth_contexts as i64,
+            ),
+        ];
+
+        for (name, kind, help, value) in scalars {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_exposition_format() {
+        let metrics = Metrics::new();
+        metrics.record_request("GET", 200, Duration::from_millis(20));
+        metrics.record_request("GET", 200, Duration::from_millis(300));
+        metrics.record_request("PUT", 403, Duration::from_millis(1));
+        metrics.add_bytes_sent(512);
+        metrics.request_rate_limited("list");
+        metrics.response_compressed("br");
+        metrics.rate_limit_buckets_evicted(2);
+        metrics.variant_cache_hit();
+        metrics.corrupt_record();
+
+        let stats = StorageStats {
+            objects: 3,
+            bytes: 1024,
+            objects_by_mime_type: BTreeMap::from([("text/plain".to_string(), 3)]),
+            auth_contexts: 2,
+            maintenance: vec![(
+                "sessions",
+                TaskStatus {
+                    runs: 4,
+                    last_success: Some(true),
+                    ..Default::default()
+                },
+            )],
+        };
+
+        let output = metrics.render(&stats);
+
+        assert!(output.contains("a_bucket_http_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(output.contains("a_bucket_http_requests_total{method=\"PUT\",status=\"403\"} 1\n"));
+        assert!(output.contains(
+            "a_bucket_http_request_duration_seconds_bucket{method=\"GET\",le=\"0.025\"} 1\n"
+        ));
+        assert!(output.contains(
+            "a_bucket_http_request_duration_seconds_bucket{method=\"GET\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(output.contains("a_bucket_http_sent_bytes_total 512\n"));
+        assert!(output.contains("a_bucket_rate_limited_requests_total{class=\"list\"} 1\n"));
+        assert!(output.contains("a_bucket_compressed_responses_total{encoding=\"br\"} 1\n"));
+        assert!(output.contains("a_bucket_rate_limit_evictions_total 2\n"));
+        assert!(output.contains("a_bucket_variant_cache_hits_total 1\n"));
+        assert!(output.contains("a_bucket_corrupt_records_total 1\n"));
+        assert!(output.contains("a_bucket_storage_objects 3\n"));
+        assert!(output.contains("a_bucket_storage_objects_by_type{mime_type=\"text/plain\"} 3\n"));
+        assert!(output.contains("a_bucket_auth_contexts 2\n"));
+        assert!(output.contains("a_bucket_maintenance_runs_total{task=\"sessions\"} 4\n"));
+        assert!(output.contains("a_bucket_maintenance_last_success{task=\"sessions\"} 1\n"));
+    }
+}
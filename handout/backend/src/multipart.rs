@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::error;
+use md5::{Digest, Md5};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::authentication::AuthLevel;
+use crate::config;
+use crate::storable::{StorableBase, StorableJson};
+
+// Part numbers S3 clients may use
+pub const PART_NUMBERS: std::ops::RangeInclusive<u32> = 1..=10000;
+
+// A multipart upload of the S3 API. Parts are kept aside until the upload is
+// completed, which stores the object like any other upload, or aborted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upload {
+    pub id: String,
+    pub bucket: String,
+    // Key of the object within the bucket
+    pub key: String,
+    // Access key of whoever started the upload, only they may add parts
+    pub owner_id: String,
+    pub initiated: u64,
+    pub mime_type: String,
+    pub readable_by: AuthLevel,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub user_metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub number: u32,
+    // Hex MD5 digest of the part, the S3 etag of a part
+    pub etag: String,
+    pub size: u64,
+    pub last_modified: u64,
+}
+
+// Kept beside each part as `<number>.json` so listing parts doesn't read them
+#[derive(Debug, Serialize, Deserialize)]
+struct PartInfo {
+    etag: String,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompleteError {
+    // A listed part was never uploaded or has another etag
+    InvalidPart,
+    // Parts must be listed in ascending order
+    InvalidPartOrder,
+    TooLarge,
+    Failed,
+}
+
+impl StorableBase for Upload {
+    fn base_dir() -> PathBuf {
+        config::get().data_dir.join("multipart").join("uploads")
+    }
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl StorableJson for Upload {}
+
+impl Upload {
+    pub async fn create(
+        bucket: &str,
+        key: &str,
+        owner_id: &str,
+        mime_type: &str,
+        readable_by: AuthLevel,
+        user_metadata: BTreeMap<String, String>,
+    ) -> std::io::Result<Self> {
+        let upload = Self {
+            id: hex::encode(rand::thread_rng().gen::<[u8; 16]>()),
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            owner_id: owner_id.to_string(),
+            initiated: now(),
+            mime_type: mime_type.to_string(),
+            readable_by,
+            user_metadata,
+        };
+
+        Self::ensure_base_dir_exists()?;
+        upload.save().await?;
+        Ok(upload)
+    }
+
+    // The upload of `bucket` and `key` with `id`, clients name both again
+    pub async fn find(id: &str, bucket: &str, key: &str) -> Option<Self> {
+        // Ids are generated by `create`, anything else can't name an upload
+        if id.len() != 32 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let path = Self::base_dir().join(format!("{}.json", hex::encode(sha2::Sha256::digest(id.as_bytes()))));
+        let contents = tokio::fs::read(path).await.ok()?;
+        let upload: Self = serde_json::from_slice(&contents).ok()?;
+
+        (upload.bucket == bucket && upload.key == key).then_some(upload)
+    }
+
+    // Uploads in progress, oldest first
+    pub async fn in_progress() -> std::io::Result<Vec<Self>> {
+        Self::ensure_base_dir_exists()?;
+        let mut list = Self::list().await?;
+        let mut uploads = Vec::new();
+
+        while let Some(upload) = list.next().await {
+            uploads.push(upload);
+        }
+
+        uploads.sort_by(|a, b| a.initiated.cmp(&b.initiated).then_with(|| a.key.cmp(&b.key)));
+        Ok(uploads)
+    }
+
+    fn parts_dir(&self) -> PathBuf {
+        config::get().data_dir.join("multipart").join("parts").join(&self.id)
+    }
+
+    // Stores a part, replacing one uploaded before under the same number,
+    // and returns its etag
+    pub async fn put_part(&self, number: u32, data: &[u8]) -> std::io::Result<String> {
+        let dir = self.parts_dir();
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let etag = hex::encode(Md5::digest(data));
+        let info = serde_json::to_vec(&PartInfo {
+            etag: etag.clone(),
+            size: data.len() as u64,
+        })?;
+
+        // Written aside first so a part is never seen half written
+        let path = dir.join(number.to_string());
+        let partial = dir.join(format!("{}.partial", number));
+        let info_partial = dir.join(format!("{}.json.partial", number));
+        tokio::fs::write(&partial, data).await?;
+        tokio::fs::write(&info_partial, info).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        tokio::fs::rename(&info_partial, dir.join(format!("{}.json", number))).await?;
+
+        Ok(etag)
+    }
+
+    // Uploaded parts ordered by number
+    pub async fn parts(&self) -> std::io::Result<Vec<Part>> {
+        let mut entries = match tokio::fs::read_dir(self.parts_dir()).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut parts = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(number) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+                continue;
+            };
+
+            let info = self.part_info(number).await?;
+            let last_modified = entry
+                .metadata()
+                .await?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            parts.push(Part {
+                number,
+                etag: info.etag,
+                size: info.size,
+                last_modified,
+            });
+        }
+
+        parts.sort_by_key(|part| part.number);
+        Ok(parts)
+    }
+
+    // Etag and size of a part as stored beside it. Parts stored without them
+    // are read to work them out
+    async fn part_info(&self, number: u32) -> std::io::Result<PartInfo> {
+        let dir = self.parts_dir();
+        match tokio::fs::read(dir.join(format!("{}.json", number))).await {
+            Ok(contents) => return Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let data = tokio::fs::read(dir.join(number.to_string())).await?;
+        Ok(PartInfo {
+            etag: hex::encode(Md5::digest(&data)),
+            size: data.len() as u64,
+        })
+    }
+
+    // Size of the part uploaded under `number`, 0 without one
+    pub async fn part_size(&self, number: u32) -> u64 {
+        match tokio::fs::metadata(self.parts_dir().join(number.to_string())).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        }
+    }
+
+    // Bytes taken by the uploaded parts, without reading them
+    async fn stored_bytes(&self) -> std::io::Result<u64> {
+        let mut entries = match tokio::fs::read_dir(self.parts_dir()).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let mut bytes = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name().to_str().is_some_and(|name| name.parse::<u32>().is_ok()) {
+                bytes += entry.metadata().await?.len();
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    // Joins the listed parts into the contents of the object. Every part
+    // has to be named with the etag it was uploaded with
+    pub async fn assemble(&self, listed: &[(u32, String)], max_size: u64) -> Result<Vec<u8>, CompleteError> {
+        if listed.is_empty() || listed.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(match listed.is_empty() {
+                true => CompleteError::InvalidPart,
+                false => CompleteError::InvalidPartOrder,
+            });
+        }
+
+        let mut data = Vec::new();
+        for (number, etag) in listed {
+            let part = match tokio::fs::read(self.parts_dir().join(number.to_string())).await {
+                Ok(part) => part,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(CompleteError::InvalidPart),
+                Err(e) => {
+                    error!("{}: failed to read part {} - {}", self.id, number, e);
+                    return Err(CompleteError::Failed);
+                }
+            };
+
+            if hex::encode(Md5::digest(&part)) != etag.trim_matches('"') {
+                return Err(CompleteError::InvalidPart);
+            }
+
+            if (data.len() + part.len()) as u64 > max_size {
+                return Err(CompleteError::TooLarge);
+            }
+
+            data.extend(part);
+        }
+
+        Ok(data)
+    }
+
+    // Removes the upload with all of its parts
+    pub async fn remove(&self) -> std::io::Result<()> {
+        match tokio::fs::remove_dir_all(self.parts_dir()).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        self.delete().await
+    }
+}
+
+// Bytes taken by the parts of uploads to `bucket`, they count toward its
+// quota until the upload is completed or aborted. Leaves out the upload with
+// id `except`
+pub async fn pending_bytes(bucket: &str, except: Option<&str>) -> std::io::Result<u64> {
+    let mut bytes = 0;
+
+    for upload in Upload::in_progress().await? {
+        if upload.bucket == bucket && Some(upload.id.as_str()) != except {
+            bytes += upload.stored_bytes().await?;
+        }
+    }
+
+    Ok(bytes)
+}
+
+// Aborts uploads started more than `max_age_secs` ago, returning how many
+// were removed and how many corrupt records were skipped
+pub async fn remove_stale(max_age_secs: u64, shutdown: watch::Receiver<bool>) -> std::io::Result<(usize, usize)> {
+    let cutoff = now().saturating_sub(max_age_secs);
+
+    Upload::ensure_base_dir_exists()?;
+    let mut list = Upload::list().await?.cancel_on(shutdown);
+    let mut removed = 0;
+
+    while let Some(upload) = list.next().await {
+        if upload.initiated > cutoff {
+            continue;
+        }
+
+        match upload.remove().await {
+            Ok(_) => removed += 1,
+            Err(e) => error!("{}: failed to remove stale upload of {} - {}", upload.id, upload.key, e),
+        }
+    }
+
+    Ok((removed, list.skipped()))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
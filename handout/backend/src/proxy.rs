@@ -0,0 +1,403 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use a_http_parser::request::Request;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// Longest possible v1 header including the trailing CRLF
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+// An address range written as `10.0.0.0/8`, a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network: IpAddr = address.trim().parse().ok()?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok()?,
+            None => max_prefix,
+        };
+
+        if prefix > max_prefix {
+            return None;
+        }
+
+        Some(Self { network, prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients on a dual stack socket show up as mapped addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            v4 => v4,
+        };
+
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    ranges: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new(ranges: Vec<Cidr>) -> Self {
+        Self { ranges }
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(address))
+    }
+}
+
+// The client as seen through any trusted proxies in front of us
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    // Address of the connecting peer, usually the proxy
+    pub peer: SocketAddr,
+    // Address of the original client
+    pub address: IpAddr,
+    // Scheme the client used to reach the first proxy
+    pub scheme: String,
+}
+
+impl ClientInfo {
+    // A client connected without a proxy in between
+    pub fn direct(peer: SocketAddr, tls: bool) -> Self {
+        Self {
+            peer,
+            address: peer.ip(),
+            scheme: if tls { "https" } else { "http" }.to_string(),
+        }
+    }
+
+    // Resolves the client from the forwarding headers, which are only
+    // honoured when they were added by a trusted proxy. The chain is walked
+    // from the nearest hop outwards and stops at the first untrusted one
+    pub fn resolve(peer: SocketAddr, tls: bool, request: &Request, trusted: &TrustedProxies) -> Self {
+        let mut client = Self::direct(peer, tls);
+
+        if !trusted.contains(peer.ip()) {
+            return client;
+        }
+
+        // Forwarded supersedes the X-Forwarded-* headers when both are sent
+        let hops = match request.headers.get("forwarded") {
+            Some(forwarded) => parse_forwarded(forwarded),
+            None => {
+                let proto = request
+                    .headers
+                    .get("x-forwarded-proto")
+                    .and_then(|proto| proto.split(',').next())
+                    .map(|proto| proto.trim().to_ascii_lowercase());
+
+                request
+                    .headers
+                    .get("x-forwarded-for")
+                    .map(|header| {
+                        header
+                            .split(',')
+                            .map(|hop| (parse_node(hop), proto.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            }
+        };
+
+        for (address, proto) in hops.into_iter().rev() {
+            if !trusted.contains(client.address) {
+                break;
+            }
+
+            // Obfuscated or unknown hops end the chain
+            let Some(address) = address else {
+                break;
+            };
+
+            client.address = address;
+            if let Some(proto) = proto {
+                client.scheme = proto;
+            }
+        }
+
+        client
+    }
+}
+
+// Parses the elements of a Forwarded header (RFC 7239) into their `for` and
+// `proto` parameters
+fn parse_forwarded(header: &str) -> Vec<(Option<IpAddr>, Option<String>)> {
+    header
+        .split(',')
+        .map(|element| {
+            let mut address = None;
+            let mut proto = None;
+
+            for pair in element.split(';') {
+                let Some((name, value)) = pair.split_once('=') else {
+                    continue;
+                };
+
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "for" => address = parse_node(value),
+                    "proto" => proto = Some(value.trim().trim_matches('"').to_ascii_lowercase()),
+                    _ => {}
+                }
+            }
+
+            (address, proto)
+        })
+        .collect()
+}
+
+// Accepts `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` and `[2001:db8::1]:80`,
+// optionally quoted
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    if let Ok(address) = value.parse::<IpAddr>() {
+        return Some(address);
+    }
+
+    if let Ok(address) = value.parse::<SocketAddr>() {
+        return Some(address.ip());
+    }
+
+    value
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once(']'))
+        .and_then(|(address, _)| address.parse().ok())
+}
+
+// Parses a PROXY protocol v1 line without its CRLF. Returns the source
+// address, or None for `UNKNOWN` connections
+pub fn parse_proxy_v1(line: &str) -> Result<Option<SocketAddr>, String> {
+    let mut parts = line.split(' ');
+
+    if parts.next() != Some("PROXY") {
+        return Err("missing PROXY prefix".to_string());
+    }
+
+    match parts.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4") | Some("TCP6") => {}
+        _ => return Err("unsupported protocol".to_string()),
+    }
+
+    let source: IpAddr = parts
+        .next()
+        .and_then(|address| address.parse().ok())
+        .ok_or("invalid source address")?;
+    let _destination = parts.next().ok_or("missing destination address")?;
+    let port: u16 = parts
+        .next()
+        .and_then(|port| port.parse().ok())
+        .ok_or("invalid source port")?;
+
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+// Parses the address block of a PROXY protocol v2 header given the 16 byte
+// fixed part. Returns the source address, or None for LOCAL connections and
+// unsupported address families
+pub fn parse_proxy_v2(header: &[u8; 16], addresses: &[u8]) -> Result<Option<SocketAddr>, String> {
+    if header[..12] != PROXY_V2_SIGNATURE {
+        return Err("invalid signature".to_string());
+    }
+
+    if header[12] >> 4 != 2 {
+        return Err("unsupported version".to_string());
+    }
+
+    // LOCAL connections are health checks by the proxy itself
+    if header[12] & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match header[13] >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let source = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(source), port)))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        1 | 2 => Err("truncated address block".to_string()),
+        _ => Ok(None),
+    }
+}
+
+// Reads a PROXY protocol header (v1 or v2) from the start of the connection.
+// Exactly the header is consumed so the rest of the stream is untouched.
+// Returns the address of the original client, None when the proxy did not
+// forward one
+pub async fn read_proxy_header(
+    socket: &mut TcpStream,
+    read_timeout: Duration,
+) -> Result<Option<SocketAddr>, String> {
+    let read = async {
+        let first = socket.read_u8().await.map_err(|e| e.to_string())?;
+
+        if first != b'P' && first != PROXY_V2_SIGNATURE[0] {
+            return Err("missing header".to_string());
+        }
+
+        if first == b'P' {
+            let mut line = vec![first];
+
+            while !line.ends_with(b"\r\n") {
+                if line.len() >= PROXY_V1_MAX_LENGTH {
+                    return Err("header too long".to_string());
+                }
+                line.push(socket.read_u8().await.map_err(|e| e.to_string())?);
+            }
+
+            let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|e| e.to_string())?;
+            parse_proxy_v1(line)
+        } else {
+            let mut header = [0; 16];
+            header[0] = first;
+            socket
+                .read_exact(&mut header[1..])
+                .await
+                .map_err(|e| e.to_string())?;
+
+            if header[..12] != PROXY_V2_SIGNATURE {
+                return Err("invalid signature".to_string());
+            }
+
+            let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+            let mut addresses = vec![0; length];
+            socket
+                .read_exact(&mut addresses)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            parse_proxy_v2(&header, &addresses)
+        }
+    };
+
+    match timeout(read_timeout, read).await {
+        Ok(result) => result,
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use a_http_parser::parser::Parser;
+
+    fn request(headers: &str) -> Request {
+        let mut parser = Parser::new();
+        parser.update(format!("GET / HTTP/1.1\r\n{}\r\n", headers).as_bytes());
+        parser.consume_request().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let range = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(range.contains("10.1.200.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let host = Cidr::parse("2001:db8::1").unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("nonsense").is_none());
+    }
+
+    #[test]
+    fn test_resolve_forwarded_headers() {
+        let trusted = TrustedProxies::new(vec![Cidr::parse("10.0.0.0/8").unwrap()]);
+        let proxy: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let stranger: SocketAddr = "203.0.113.9:4000".parse().unwrap();
+
+        // Spoofed entries left of the first untrusted hop are ignored
+        let req = request("X-Forwarded-For: 1.1.1.1, 198.51.100.7, 10.0.0.5\r\nX-Forwarded-Proto: https\r\n");
+        let client = ClientInfo::resolve(proxy, false, &req, &trusted);
+        assert_eq!(client.address, "198.51.100.7".parse::<IpAddr>().unwrap());
+        assert_eq!(client.scheme, "https");
+
+        // Headers from untrusted peers are not honoured
+        let client = ClientInfo::resolve(stranger, false, &req, &trusted);
+        assert_eq!(client.address, stranger.ip());
+        assert_eq!(client.scheme, "http");
+
+        let req = request("Forwarded: for=\"[2001:db8::7]:1234\";proto=https, for=10.0.0.5\r\nX-Forwarded-For: 1.1.1.1\r\n");
+        let client = ClientInfo::resolve(proxy, false, &req, &trusted);
+        assert_eq!(client.address, "2001:db8::7".parse::<IpAddr>().unwrap());
+        assert_eq!(client.scheme, "https");
+    }
+
+    #[test]
+    fn test_parse_proxy_v1() {
+        assert_eq!(
+            parse_proxy_v1("PROXY TCP4 192.0.2.1 192.0.2.2 56324 443").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_proxy_v1("PROXY TCP6 2001:db8::1 2001:db8::2 8080 80").unwrap(),
+            Some("[2001:db8::1]:8080".parse().unwrap())
+        );
+        assert_eq!(parse_proxy_v1("PROXY UNKNOWN").unwrap(), None);
+        assert!(parse_proxy_v1("GET / HTTP/1.1").is_err());
+    }
+
+    #[test]
+    fn test_parse_proxy_v2() {
+        let mut header = [0; 16];
+        header[..12].copy_from_slice(&PROXY_V2_SIGNATURE);
+        // Version 2, PROXY command, AF_INET over STREAM
+        header[12] = 0x21;
+        header[13] = 0x11;
+        header[15] = 12;
+        let addresses = [192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb];
+
+        assert_eq!(
+            parse_proxy_v2(&header, &addresses).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+
+        // LOCAL command
+        header[12] = 0x20;
+        assert_eq!(parse_proxy_v2(&header, &addresses).unwrap(), None);
+
+        header[0] = b'X';
+        assert!(parse_proxy_v2(&header, &addresses).is_err());
+    }
+}
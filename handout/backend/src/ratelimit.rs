@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use a_http_parser::http::Method;
+
+use crate::config::{BucketLimit, ClassLimits};
+use crate::metrics::METRICS;
+
+// How often buckets that refilled completely are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// Buckets tracked at most, beyond it the ones closest to full are evicted
+const MAX_BUCKETS: usize = 100_000;
+
+pub static RATE_LIMITER: RateLimiter = RateLimiter::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestClass {
+    Read,
+    Write,
+    List,
+    Session,
+}
+
+impl RequestClass {
+    pub fn of(method: &Method) -> Self {
+        match method {
+            Method::GET | Method::HEAD | Method::OPTIONS => RequestClass::Read,
+            Method::LIST | Method::TRACE => RequestClass::List,
+            _ => RequestClass::Write,
+        }
+    }
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            RequestClass::Read => "read",
+            RequestClass::Write => "write",
+            RequestClass::List => "list",
+            RequestClass::Session => "session",
+        }
+    }
+
+    pub fn limit(self, limits: &ClassLimits) -> BucketLimit {
+        match self {
+            RequestClass::Read => limits.read,
+            RequestClass::Write => limits.write,
+            RequestClass::List => limits.list,
+            RequestClass::Session => limits.session,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RateKey {
+    Address(IpAddr),
+    AccessKey(String),
+}
+
+impl RateKey {
+    // IPv6 clients usually get a whole /64, its addresses share one budget
+    pub fn address(address: IpAddr) -> Self {
+        match address.to_canonical() {
+            IpAddr::V6(address) => {
+                let network = u128::from(address) & !(u64::MAX as u128);
+                RateKey::Address(IpAddr::V6(Ipv6Addr::from(network)))
+            }
+            address => RateKey::Address(address),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // When the bucket will be full again under its own limit, from then on
+    // it behaves like a new one and can be dropped
+    full_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: BucketLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let rate = limit.per_minute as f64 / 60.0;
+
+        self.tokens = (self.tokens + elapsed * rate).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+struct LimiterState {
+    buckets: BTreeMap<(RateKey, RequestClass), Bucket>,
+    last_prune: Option<Instant>,
+}
+
+impl LimiterState {
+    fn prune(&mut self, now: Instant) {
+        if self
+            .last_prune
+            .is_some_and(|last_prune| now.saturating_duration_since(last_prune) < PRUNE_INTERVAL)
+        {
+            return;
+        }
+
+        self.prune_full(now);
+    }
+
+    fn prune_full(&mut self, now: Instant) {
+        self.buckets.retain(|_, bucket| bucket.full_at > now);
+        self.last_prune = Some(now);
+    }
+
+    // Makes room for a new bucket. Full buckets go first, dropping them
+    // changes nothing. Without any, the tenth closest to full is evicted so
+    // clients lose as little of their drained budget as possible and a
+    // burst of new clients doesn't pay for a scan each. Returns how many
+    // were evicted
+    fn make_room(&mut self, max_buckets: usize, now: Instant) -> usize {
+        self.prune_full(now);
+        if self.buckets.len() < max_buckets {
+            return 0;
+        }
+
+        let mut by_full_at: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|(key, bucket)| (bucket.full_at, key.clone()))
+            .collect();
+        let count = (max_buckets / 10).clamp(1, by_full_at.len());
+
+        by_full_at.select_nth_unstable(count - 1);
+        for (_, key) in by_full_at.drain(..count) {
+            self.buckets.remove(&key);
+        }
+
+        count
+    }
+}
+
+pub struct RateLimiter {
+    max_buckets: usize,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    const fn new() -> Self {
+        Self::with_capacity(MAX_BUCKETS)
+    }
+
+    const fn with_capacity(max_buckets: usize) -> Self {
+        Self {
+            max_buckets,
+            state: Mutex::new(LimiterState {
+                buckets: BTreeMap::new(),
+                last_prune: None,
+            }),
+        }
+    }
+
+    // Takes a token from the bucket of `key`, or returns how long the client
+    // has to wait until one is available
+    pub fn check(&self, key: RateKey, class: RequestClass, limit: BucketLimit) -> Result<(), Duration> {
+        self.check_at(key, class, limit, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        key: RateKey,
+        class: RequestClass,
+        limit: BucketLimit,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if limit.per_minute == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+
+        let key = (key, class);
+        if !state.buckets.contains_key(&key) && state.buckets.len() >= self.max_buckets {
+            let evicted = state.make_room(self.max_buckets, now);
+            if evicted > 0 {
+                METRICS.rate_limit_buckets_evicted(evicted);
+            }
+        }
+
+        let bucket = state.buckets.entry(key).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+            full_at: now,
+        });
+        bucket.refill(limit, now);
+
+        let rate = limit.per_minute as f64 / 60.0;
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(Duration::from_secs_f64(missing / rate))
+        };
+
+        let missing = (limit.burst as f64 - bucket.tokens).max(0.0);
+        bucket.full_at = now + Duration::from_secs_f64(missing / rate);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_burst_and_refill() {
+        let limiter = RateLimiter::new();
+        let limit = BucketLimit {
+            per_minute: 60,
+            burst: 2,
+        };
+        let key = RateKey::Address("10.0.0.1".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.check_at(key.clone(), RequestClass::List, limit, start).is_ok());
+        assert!(limiter.check_at(key.clone(), RequestClass::List, limit, start).is_ok());
+
+        let retry_after = limiter
+            .check_at(key.clone(), RequestClass::List, limit, start)
+            .unwrap_err();
+        assert_eq!(retry_after.as_secs(), 1);
+
+        // Other classes and keys have their own budget
+        assert!(limiter.check_at(key.clone(), RequestClass::Read, limit, start).is_ok());
+        let other = RateKey::AccessKey("other".to_string());
+        assert!(limiter.check_at(other, RequestClass::List, limit, start).is_ok());
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check_at(key, RequestClass::List, limit, later).is_ok());
+    }
+
+    #[test]
+    fn test_prune_uses_each_bucket_limit() {
+        let limiter = RateLimiter::new();
+        let slow = BucketLimit {
+            per_minute: 1,
+            burst: 10,
+        };
+        let fast = BucketLimit {
+            per_minute: 60,
+            burst: 1,
+        };
+        let drained = RateKey::Address("10.0.0.1".parse().unwrap());
+        let refilled = RateKey::Address("10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            assert!(limiter.check_at(drained.clone(), RequestClass::Read, slow, start).is_ok());
+        }
+        assert!(limiter.check_at(refilled.clone(), RequestClass::Read, fast, start).is_ok());
+
+        // The next request after the interval prunes, judging every bucket
+        // by its own limit rather than the one of the current request
+        let later = start + PRUNE_INTERVAL;
+        let other = RateKey::AccessKey("other".to_string());
+        assert!(limiter.check_at(other, RequestClass::Read, fast, later).is_ok());
+
+        let state = limiter.state.lock().unwrap();
+        assert!(state.buckets.contains_key(&(drained, RequestClass::Read)));
+        assert!(!state.buckets.contains_key(&(refilled, RequestClass::Read)));
+        assert_eq!(state.buckets.len(), 2);
+    }
+
+    #[test]
+    fn test_makes_room_beyond_capacity() {
+        let limiter = RateLimiter::with_capacity(3);
+        let limit = BucketLimit {
+            per_minute: 60,
+            burst: 5,
+        };
+        let start = Instant::now();
+        let key = |index: u8| RateKey::Address(format!("10.0.0.{}", index).parse().unwrap());
+
+        // Drained the most by the first client, so its bucket is full last
+        for _ in 0..4 {
+            assert!(limiter.check_at(key(0), RequestClass::Write, limit, start).is_ok());
+        }
+        for index in 1..4 {
+            assert!(limiter.check_at(key(index), RequestClass::Write, limit, start).is_ok());
+        }
+
+        {
+            let state = limiter.state.lock().unwrap();
+            assert_eq!(state.buckets.len(), 3);
+            assert!(state.buckets.contains_key(&(key(0), RequestClass::Write)));
+            assert!(!state.buckets.contains_key(&(key(1), RequestClass::Write)));
+        }
+
+        // Buckets that refilled make room before any drained one is evicted
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check_at(key(4), RequestClass::Write, limit, later).is_ok());
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.buckets.len(), 2);
+        assert!(state.buckets.contains_key(&(key(0), RequestClass::Write)));
+        assert!(state.buckets.contains_key(&(key(4), RequestClass::Write)));
+    }
+
+    #[test]
+    fn test_ipv6_networks_share_a_key() {
+        let key = |address: &str| RateKey::address(address.parse().unwrap());
+
+        assert_eq!(key("2001:db8:1:2::1"), key("2001:db8:1:2:ffff::9"));
+        assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+        assert_eq!(key("::ffff:10.0.0.1"), key("10.0.0.1"));
+        assert_ne!(key("10.0.0.1"), key("10.0.0.2"));
+    }
+}
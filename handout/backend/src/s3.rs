@@ -0,0 +1,1383 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use a_http_parser::http::{Method, MimeType};
+use a_http_parser::request::Request;
+use a_http_parser::response::Response;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use log::{debug, error};
+use md5::{Digest, Md5};
+use rand::Rng;
+
+use crate::authentication::{AuthContext, AuthLevel};
+use crate::batch::{BatchDelete, Outcome};
+use crate::buckets::{self, Bucket, BucketError, BucketSettings};
+use crate::checksum;
+use crate::compression;
+use crate::config;
+use crate::metadata::{self, Metadata};
+use crate::multipart::{self, CompleteError, Upload};
+use crate::ratelimit::RequestClass;
+use crate::search;
+use crate::sigv4::{self, AuthError, Signed};
+use crate::storable::StorableBlob;
+use crate::storage::{self, PutOptions, Storage, StorageError};
+use crate::transfer::{self, Transfer};
+
+// Served by the native API, returns the key pair S3 clients sign with
+pub const CREDENTIALS_PATH: &str = ".credentials";
+
+// Namespace of every document the API answers with
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+// S3 clients send user metadata as x-amz-meta-* instead of X-Meta-*
+const USER_METADATA_PREFIX: &str = "x-amz-meta-";
+// Entries returned per listing page unless the client asks for fewer
+const MAX_KEYS: usize = 1000;
+
+// Query parameters the API understands. Requests naming any other
+// subresource, such as ?acl or ?tagging, are answered as not implemented
+const KNOWN_PARAMS: [&str; 20] = [
+    "location",
+    "uploads",
+    "uploadId",
+    "partNumber",
+    "delete",
+    "list-type",
+    "prefix",
+    "delimiter",
+    "max-keys",
+    "marker",
+    "continuation-token",
+    "start-after",
+    "encoding-type",
+    "fetch-owner",
+    "key-marker",
+    "upload-id-marker",
+    "max-uploads",
+    "max-parts",
+    "part-number-marker",
+    // Added by some SDKs to name the operation, it carries no meaning
+    "x-id",
+];
+
+// An error in the shape S3 clients expect, answered as an XML document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct S3Error {
+    pub status: u16,
+    pub code: &'static str,
+    pub message: &'static str,
+}
+
+const ACCESS_DENIED: S3Error = S3Error::new(403, "AccessDenied", "Access Denied");
+const BUCKET_OWNED: S3Error = S3Error::new(409, "BucketAlreadyOwnedByYou", "You already own this bucket");
+const ENTITY_TOO_LARGE: S3Error = S3Error::new(400, "EntityTooLarge", "Object exceeds the maximum allowed size");
+const INTERNAL_ERROR: S3Error = S3Error::new(500, "InternalError", "We encountered an internal error");
+const INVALID_ARGUMENT: S3Error = S3Error::new(400, "InvalidArgument", "Invalid argument");
+const INVALID_RANGE: S3Error = S3Error::new(416, "InvalidRange", "The requested range is not satisfiable");
+const INVALID_URI: S3Error = S3Error::new(400, "InvalidURI", "Couldn't parse the specified URI");
+const MALFORMED_XML: S3Error = S3Error::new(400, "MalformedXML", "The XML you provided was not well-formed");
+const METHOD_NOT_ALLOWED: S3Error = S3Error::new(405, "MethodNotAllowed", "The specified method is not allowed");
+const NO_SUCH_BUCKET: S3Error = S3Error::new(404, "NoSuchBucket", "The specified bucket does not exist");
+const NO_SUCH_UPLOAD: S3Error = S3Error::new(404, "NoSuchUpload", "The specified upload does not exist");
+const NOT_IMPLEMENTED: S3Error = S3Error::new(501, "NotImplemented", "Not implemented");
+const PRECONDITION_FAILED: S3Error = S3Error::new(412, "PreconditionFailed", "At least one precondition failed");
+
+impl S3Error {
+    const fn new(status: u16, code: &'static str, message: &'static str) -> Self {
+        Self { status, code, message }
+    }
+
+    fn response(self, resource: &str, request_id: &str) -> Response {
+        // Error documents are the only ones without the namespace
+        let mut xml = Xml::with_root("<Error>", "Error");
+        xml.text("Code", self.code)
+            .text("Message", self.message)
+            .text("Resource", resource)
+            .text("RequestId", request_id);
+
+        xml.finish(self.status)
+    }
+}
+
+impl From<StorageError> for S3Error {
+    fn from(error: StorageError) -> Self {
+        let code = match error {
+            StorageError::NotFound => "NoSuchKey",
+            StorageError::NoSuchBucket => "NoSuchBucket",
+            StorageError::CustomerKeyRequired | StorageError::CustomerKeyMismatch | StorageError::CustomerKeyUnexpected => {
+                "InvalidRequest"
+            }
+            // Saving fails when the caller can't overwrite the object
+            StorageError::Forbidden | StorageError::Locked | StorageError::SaveFailed => "AccessDenied",
+            StorageError::QuotaExceeded => "QuotaExceeded",
+            StorageError::Corrupt | StorageError::DeleteFailed | StorageError::AuditFailed => "InternalError",
+        };
+
+        let status = match error {
+            StorageError::SaveFailed => 403,
+            _ => error.status_code(),
+        };
+
+        S3Error::new(status, code, error.message())
+    }
+}
+
+impl From<BucketError> for S3Error {
+    fn from(error: BucketError) -> Self {
+        let code = match error {
+            BucketError::InvalidName => "InvalidBucketName",
+            BucketError::InvalidCors => "InvalidArgument",
+            BucketError::NotFound => "NoSuchBucket",
+            BucketError::NameInUse => "BucketAlreadyExists",
+            BucketError::NotEmpty => "BucketNotEmpty",
+            BucketError::Failed => "InternalError",
+        };
+
+        S3Error::new(error.status_code(), code, error.message())
+    }
+}
+
+impl From<AuthError> for S3Error {
+    fn from(error: AuthError) -> Self {
+        S3Error::new(error.status_code(), error.code(), error.message())
+    }
+}
+
+impl From<CompleteError> for S3Error {
+    fn from(error: CompleteError) -> Self {
+        match error {
+            CompleteError::InvalidPart => S3Error::new(400, "InvalidPart", "One or more of the specified parts could not be found"),
+            CompleteError::InvalidPartOrder => S3Error::new(400, "InvalidPartOrder", "The list of parts was not in ascending order"),
+            CompleteError::TooLarge => ENTITY_TOO_LARGE,
+            CompleteError::Failed => INTERNAL_ERROR,
+        }
+    }
+}
+
+// Answers an S3 request. `signed` is None for anonymous requests, which are
+// served with a throwaway public context like anonymous reads elsewhere
+pub async fn handle(req: Request, auth_context: AuthContext, signed: Option<Signed>) -> (Response, Option<Transfer>) {
+    let request_id = request_id();
+
+    let (mut res, transfer) = match route(&req, &auth_context, signed.as_ref()).await {
+        Ok(reply) => reply,
+        Err(error) => {
+            debug!("S3 {} {} - {}", req.method.to_str(), req.uri, error.code);
+            (error_response(error, &req, &request_id), None)
+        }
+    };
+
+    res.set_header("x-amz-request-id", &request_id);
+    if req.method == Method::HEAD {
+        res.body.clear();
+    }
+
+    (res, transfer)
+}
+
+// The rate limit budget a request is charged to. Listings of buckets,
+// objects, uploads and parts take the list budget, like LIST requests of
+// the native API
+pub fn request_class(req: &Request) -> RequestClass {
+    if req.method != Method::GET {
+        return RequestClass::of(&req.method);
+    }
+
+    let (path, query) = search::split_uri(&req.uri);
+    if sigv4::query_params(query.unwrap_or_default()).iter().any(|(name, _)| name == "uploadId") {
+        return RequestClass::List;
+    }
+
+    let path = path.trim_start_matches('/');
+    let key = match req.headers.get("host").and_then(|host| buckets::host_bucket(host)) {
+        Some(_) => path,
+        None => path.split_once('/').map_or("", |(_, key)| key),
+    };
+
+    match key.is_empty() {
+        true => RequestClass::List,
+        false => RequestClass::Read,
+    }
+}
+
+// Identifies a response in x-amz-request-id and error documents
+pub fn request_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+}
+
+// The error document answered for `req`, which names the requested path
+pub fn error_response(error: S3Error, req: &Request, request_id: &str) -> Response {
+    let resource = search::split_uri(&req.uri).0;
+    let mut res = error.response(resource, request_id);
+    res.set_header("x-amz-request-id", request_id);
+    res
+}
+
+// Decoded query parameters of a request
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // A page size, capped to what one response may hold
+    fn max(&self, name: &str) -> Result<usize, S3Error> {
+        match self.get(name) {
+            Some(value) => value.parse::<usize>().map(|max| max.min(MAX_KEYS)).map_err(|_| INVALID_ARGUMENT),
+            None => Ok(MAX_KEYS),
+        }
+    }
+}
+
+type Reply = Result<(Response, Option<Transfer>), S3Error>;
+
+// Virtual-host requests name the bucket in Host, path-style ones in the
+// first path segment. Unlike the native API there is no global namespace,
+// every object is in a bucket
+async fn route(req: &Request, auth_context: &AuthContext, signed: Option<&Signed>) -> Reply {
+    let (path, query) = search::split_uri(&req.uri);
+    let params = Params(sigv4::query_params(query.unwrap_or_default()));
+
+    if params
+        .0
+        .iter()
+        .any(|(name, _)| !KNOWN_PARAMS.contains(&name.as_str()) && !name.starts_with("X-Amz-"))
+    {
+        return Err(NOT_IMPLEMENTED);
+    }
+
+    let path = sigv4::uri_decode(path).ok_or(INVALID_URI)?;
+    let path = path.trim_start_matches('/');
+
+    let (name, key) = match req.headers.get("host").and_then(|host| buckets::host_bucket(host)) {
+        Some(name) => (name, path),
+        None if path.is_empty() => {
+            return match req.method {
+                Method::GET => list_buckets(auth_context, signed).map(|res| (res, None)),
+                _ => Err(METHOD_NOT_ALLOWED),
+            };
+        }
+        None => {
+            let (name, key) = path.split_once('/').unwrap_or((path, ""));
+            (name.to_string(), key)
+        }
+    };
+
+    if key.is_empty() {
+        return bucket_request(req, &params, &name, auth_context, signed).await.map(|res| (res, None));
+    }
+
+    let bucket = buckets::get().get(&name).ok_or(NO_SUCH_BUCKET)?;
+    let storage = Storage::new(auth_context.clone()).in_bucket(Some(bucket.clone()));
+
+    match req.method {
+        Method::GET if params.has("uploadId") => list_parts(&params, &bucket, key, auth_context).await,
+        Method::GET | Method::HEAD => return get_object(req, &storage, key).await,
+        Method::PUT if params.has("uploadId") => upload_part(req, &params, &bucket, key, auth_context, signed).await,
+        Method::PUT if req.headers.contains_key("x-amz-copy-source") => {
+            copy_object(req, &storage, &bucket, key, auth_context).await
+        }
+        Method::PUT => put_object(req, &storage, &bucket, key, auth_context, signed).await,
+        Method::POST if params.has("uploads") => create_upload(req, &bucket, key, auth_context).await,
+        Method::POST if params.has("uploadId") => {
+            complete_upload(req, &params, &storage, &bucket, key, auth_context, signed).await
+        }
+        Method::DELETE if params.has("uploadId") => abort_upload(&params, &bucket, key, auth_context).await,
+        Method::DELETE => delete_object(&storage, key, auth_context).await,
+        _ => Err(METHOD_NOT_ALLOWED),
+    }
+    .map(|res| (res, None))
+}
+
+async fn bucket_request(
+    req: &Request,
+    params: &Params,
+    name: &str,
+    auth_context: &AuthContext,
+    signed: Option<&Signed>,
+) -> Result<Response, S3Error> {
+    let store = buckets::get();
+
+    // Creating a bucket is the only request for one that doesn't exist yet
+    if req.method == Method::PUT {
+        if signed.is_none() || auth_context.access_level < AuthLevel::ReadWrite {
+            return Err(ACCESS_DENIED);
+        }
+
+        if store.get(name).is_some_and(|bucket| bucket.is_managed_by(auth_context)) {
+            return Err(BUCKET_OWNED);
+        }
+
+        store.put(name, BucketSettings::default(), auth_context).await?;
+
+        let mut res = Response::new(200);
+        res.set_header("location", &format!("/{}", name));
+        return Ok(res);
+    }
+
+    let bucket = store.get(name).ok_or(NO_SUCH_BUCKET)?;
+    let storage = Storage::new(auth_context.clone()).in_bucket(Some(bucket.clone()));
+
+    match req.method {
+        Method::DELETE => {
+            if auth_context.access_level < AuthLevel::ReadWrite {
+                return Err(ACCESS_DENIED);
+            }
+
+            store.remove(name, auth_context).await?;
+            Ok(Response::new(204))
+        }
+        Method::HEAD => Ok(Response::new(200)),
+        // Every bucket is in the one region the server is, which S3 names
+        // with an empty constraint
+        Method::GET if params.has("location") => Ok(Xml::new("LocationConstraint").finish(200)),
+        Method::GET if params.has("uploads") => list_uploads(params, &bucket, auth_context).await,
+        Method::GET => list_objects(params, &storage, &bucket).await,
+        Method::POST if params.has("delete") => delete_objects(req, &storage, auth_context, signed).await,
+        _ => Err(METHOD_NOT_ALLOWED),
+    }
+}
+
+fn list_buckets(auth_context: &AuthContext, signed: Option<&Signed>) -> Result<Response, S3Error> {
+    if signed.is_none() {
+        return Err(ACCESS_DENIED);
+    }
+
+    let mut xml = Xml::new("ListAllMyBucketsResult");
+    xml.start("Owner")
+        .text("ID", &auth_context.access_key)
+        .text("DisplayName", &auth_context.access_key)
+        .end();
+
+    xml.start("Buckets");
+    for bucket in buckets::get().list(auth_context) {
+        xml.start("Bucket")
+            .text("Name", &bucket.name)
+            .text("CreationDate", iso8601(bucket.created_at))
+            .end();
+    }
+    xml.end();
+
+    Ok(xml.finish(200))
+}
+
+// ListObjects and ListObjectsV2, told apart by list-type. Version 2 pages
+// with an opaque token, version 1 with the last key returned
+async fn list_objects(params: &Params, storage: &Storage, bucket: &Bucket) -> Result<Response, S3Error> {
+    let v2 = params.get("list-type") == Some("2");
+    let prefix = params.get("prefix").unwrap_or_default();
+    let delimiter = params.get("delimiter").filter(|delimiter| !delimiter.is_empty());
+    let max_keys = params.max("max-keys")?;
+
+    // Keys may hold characters XML can't, clients ask for them encoded
+    let url_encoded = match params.get("encoding-type") {
+        None => false,
+        Some("url") => true,
+        Some(_) => return Err(INVALID_ARGUMENT),
+    };
+    let encode = |value: &str| match url_encoded {
+        true => sigv4::uri_encode(value, false),
+        false => value.to_string(),
+    };
+
+    let start_after = match (v2, params.get("continuation-token")) {
+        (true, Some(token)) => URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .and_then(|key| String::from_utf8(key).ok())
+            .ok_or(INVALID_ARGUMENT)?,
+        (true, None) => params.get("start-after").unwrap_or_default().to_string(),
+        (false, _) => params.get("marker").unwrap_or_default().to_string(),
+    };
+
+    // Keys are read from the index in key order, starting after the last
+    // one listed, until the page holds one entry more than asked for
+    let mut page = Page::default();
+    let mut after = start_after.clone();
+    loop {
+        let wanted = max_keys + 1 - page.len();
+        let objects = storage.list_after(prefix, &after, wanted).await;
+        let exhausted = objects.len() < wanted;
+
+        for metadata in objects {
+            let key = storage.relative_key(&metadata.key).to_string();
+            if !page.offer(&key, metadata, prefix, delimiter, &start_after, max_keys) {
+                break;
+            }
+            after = key;
+        }
+
+        if exhausted || page.truncated {
+            break;
+        }
+
+        // Keys rolled up into a common prefix are skipped without reading them
+        if let Some(common_prefix) = page.common_prefixes.last().filter(|common_prefix| after.starts_with(common_prefix.as_str())) {
+            after = format!("{}{}", common_prefix, char::MAX);
+        }
+    }
+    let next = page.last.as_deref().filter(|_| page.truncated);
+
+    let mut xml = Xml::new("ListBucketResult");
+    xml.text("Name", &bucket.name).text("Prefix", encode(prefix));
+
+    if v2 {
+        xml.text("KeyCount", page.contents.len() + page.common_prefixes.len());
+        if let Some(token) = params.get("continuation-token") {
+            xml.text("ContinuationToken", token);
+        }
+        if let Some(start_after) = params.get("start-after") {
+            xml.text("StartAfter", encode(start_after));
+        }
+        if let Some(next) = next {
+            xml.text("NextContinuationToken", URL_SAFE_NO_PAD.encode(next));
+        }
+    } else {
+        xml.text("Marker", encode(&start_after));
+        if let Some(next) = next {
+            xml.text("NextMarker", encode(next));
+        }
+    }
+
+    xml.text("MaxKeys", max_keys);
+    if let Some(delimiter) = delimiter {
+        xml.text("Delimiter", encode(delimiter));
+    }
+    xml.text("IsTruncated", page.truncated);
+    if url_encoded {
+        xml.text("EncodingType", "url");
+    }
+
+    for (key, metadata) in &page.contents {
+        xml.start("Contents")
+            .text("Key", encode(key))
+            .text("LastModified", iso8601(metadata.last_modified))
+            .text("ETag", etag(metadata))
+            .text("Size", metadata.size)
+            .text("StorageClass", "STANDARD")
+            .end();
+    }
+
+    for common_prefix in &page.common_prefixes {
+        xml.start("CommonPrefixes").text("Prefix", encode(common_prefix)).end();
+    }
+
+    Ok(xml.finish(200))
+}
+
+// One page of a listing, built from keys offered in order
+#[derive(Debug, PartialEq, Eq)]
+struct Page<T> {
+    // Keys listed on their own, with their entries
+    contents: Vec<(String, T)>,
+    common_prefixes: Vec<String>,
+    truncated: bool,
+    // Last key or common prefix of the page, the next page starts after it
+    last: Option<String>,
+}
+
+impl<T> Default for Page<T> {
+    fn default() -> Self {
+        Self {
+            contents: Vec::new(),
+            common_prefixes: Vec::new(),
+            truncated: false,
+            last: None,
+        }
+    }
+}
+
+impl<T> Page<T> {
+    fn len(&self) -> usize {
+        self.contents.len() + self.common_prefixes.len()
+    }
+
+    // Adds the entry of `key` unless it comes before the page. Keys
+    // continuing past the prefix up to a delimiter are rolled up into a
+    // common prefix, which counts as a single entry. False once the page is
+    // full, it is then truncated
+    fn offer(&mut self, key: &str, entry: T, prefix: &str, delimiter: Option<&str>, start_after: &str, max_keys: usize) -> bool {
+        let Some(rest) = key.strip_prefix(prefix) else {
+            return true;
+        };
+
+        let common_prefix = delimiter
+            .and_then(|delimiter| rest.find(delimiter).map(|at| &key[..prefix.len() + at + delimiter.len()]));
+
+        // Rolled up keys are next to each other, their prefix is listed once
+        if key <= start_after || common_prefix == Some(start_after) || (common_prefix.is_some() && common_prefix == self.last.as_deref()) {
+            return true;
+        }
+
+        if self.len() == max_keys {
+            self.truncated = true;
+            return false;
+        }
+
+        match common_prefix {
+            Some(common_prefix) => self.common_prefixes.push(common_prefix.to_string()),
+            None => self.contents.push((key.to_string(), entry)),
+        }
+        self.last = Some(common_prefix.unwrap_or(key).to_string());
+        true
+    }
+}
+
+async fn get_object(req: &Request, storage: &Storage, key: &str) -> Reply {
+    let object = storage.get_object(key, false, None).await?;
+    let etag = etag(&object.metadata);
+
+    if req.headers.get("if-match").is_some_and(|expected| !matches_etag(expected, &etag)) {
+        return Err(PRECONDITION_FAILED);
+    }
+
+    let mut res = Response::new(200);
+    set_object_headers(&mut res, &object.metadata);
+
+    if req.headers.get("if-none-match").is_some_and(|unexpected| matches_etag(unexpected, &etag)) {
+        res.set_status_code(304);
+        return Ok((res, None));
+    }
+
+    let size = object.metadata.size;
+    let range = match req.headers.get("range").and_then(|range| transfer::parse_range(range, size)) {
+        Some(Ok(range)) => Some(range),
+        Some(Err(())) => return Err(INVALID_RANGE),
+        None => None,
+    };
+
+    let transfer = match range {
+        Some(range) => {
+            res.set_status_code(206);
+            res.set_header("content-range", &range.content_range(size));
+            res.set_header("content-length", &range.len().to_string());
+            Transfer::object(object).with_range(range)
+        }
+        None => {
+            res.set_header("content-length", &size.to_string());
+            Transfer::object(object)
+        }
+    };
+
+    match req.method {
+        Method::HEAD => Ok((res, None)),
+        _ => Ok((res, Some(transfer))),
+    }
+}
+
+async fn put_object(
+    req: &Request,
+    storage: &Storage,
+    bucket: &Bucket,
+    key: &str,
+    auth_context: &AuthContext,
+    signed: Option<&Signed>,
+) -> Result<Response, S3Error> {
+    reject_object_lock(req)?;
+
+    let body = upload_body(req, signed)?;
+    let readable_by = readable_by(req, bucket, auth_context)?;
+    let user_metadata = user_metadata(req)?;
+
+    let options = PutOptions {
+        mime_type: mime_type(&req.headers),
+        readable_by,
+        expires_at: None,
+        customer_key: None,
+        user_metadata,
+        retain_until: None,
+        legal_hold: false,
+        completes: None,
+    };
+    storage.put_object(key, &body, options).await?;
+
+    let mut res = Response::new(200);
+    res.set_header("etag", &format!("\"{}\"", hex::encode(Md5::digest(&body))));
+    Ok(res)
+}
+
+// CopyObject, a PUT naming the source in x-amz-copy-source. The metadata is
+// copied along unless x-amz-metadata-directive replaces it
+async fn copy_object(
+    req: &Request,
+    storage: &Storage,
+    bucket: &Bucket,
+    key: &str,
+    auth_context: &AuthContext,
+) -> Result<Response, S3Error> {
+    reject_object_lock(req)?;
+
+    let source = req.headers.get("x-amz-copy-source").map(String::as_str).unwrap_or_default();
+    let source = sigv4::uri_decode(source.split('?').next().unwrap_or_default()).ok_or(INVALID_ARGUMENT)?;
+    let (source_bucket, source_key) = source.trim_start_matches('/').split_once('/').ok_or(INVALID_ARGUMENT)?;
+
+    let source_bucket = buckets::get().get(source_bucket).ok_or(NO_SUCH_BUCKET)?;
+    let object = Storage::new(auth_context.clone())
+        .in_bucket(Some(source_bucket))
+        .get_object(source_key, true, None)
+        .await?;
+
+    let (mime_type, user_metadata) = match req.headers.get("x-amz-metadata-directive").map(String::as_str) {
+        None | Some("COPY") => (
+            MimeType::from_str(&object.metadata.mime_type),
+            object.metadata.user_metadata.clone(),
+        ),
+        Some("REPLACE") => (mime_type(&req.headers), user_metadata(req)?),
+        Some(_) => return Err(INVALID_ARGUMENT),
+    };
+
+    let data = object.get_data();
+    let options = PutOptions {
+        mime_type,
+        readable_by: readable_by(req, bucket, auth_context)?,
+        expires_at: None,
+        customer_key: None,
+        user_metadata,
+        retain_until: None,
+        legal_hold: false,
+        completes: None,
+    };
+    storage.put_object(key, &data, options).await?;
+
+    let mut xml = Xml::new("CopyObjectResult");
+    xml.text("LastModified", iso8601(now()))
+        .text("ETag", format!("\"{}\"", hex::encode(Md5::digest(&data))));
+
+    Ok(xml.finish(200))
+}
+
+// Deleting an object that isn't there succeeds, as it does in S3
+async fn delete_object(storage: &Storage, key: &str, auth_context: &AuthContext) -> Result<Response, S3Error> {
+    if auth_context.access_level < AuthLevel::ReadWrite {
+        return Err(ACCESS_DENIED);
+    }
+
+    match storage.delete_object(key).await {
+        Ok(()) | Err(StorageError::NotFound) => Ok(Response::new(204)),
+        Err(error) => Err(error.into()),
+    }
+}
+
+// DeleteObjects, a POST ?delete listing the keys to delete
+async fn delete_objects(
+    req: &Request,
+    storage: &Storage,
+    auth_context: &AuthContext,
+    signed: Option<&Signed>,
+) -> Result<Response, S3Error> {
+    if auth_context.access_level < AuthLevel::ReadWrite {
+        return Err(ACCESS_DENIED);
+    }
+
+    let body = upload_body(req, signed)?;
+    let body = std::str::from_utf8(&body).map_err(|_| MALFORMED_XML)?;
+
+    let keys: Vec<String> = elements(body, "Key").into_iter().map(unescape).collect();
+    let quiet = elements(body, "Quiet").first().is_some_and(|quiet| quiet.trim() == "true");
+
+    let max_batch_size = config::get().limits.max_batch_size;
+    if keys.is_empty() || keys.len() > max_batch_size {
+        return Err(MALFORMED_XML);
+    }
+
+    let batch = BatchDelete {
+        keys,
+        prefix: None,
+        quiet: false,
+        dry_run: false,
+    };
+    let report = batch.run(storage, max_batch_size).await;
+
+    let mut xml = Xml::new("DeleteResult");
+    for result in report.results {
+        match result.result {
+            Outcome::Deleted | Outcome::NotFound if quiet => {}
+            Outcome::Deleted | Outcome::NotFound => {
+                xml.start("Deleted").text("Key", &result.key).end();
+            }
+            outcome => {
+                let error = match outcome {
+                    Outcome::Forbidden => ACCESS_DENIED,
+                    Outcome::Locked => S3Error::from(StorageError::Locked),
+                    _ => INTERNAL_ERROR,
+                };
+
+                xml.start("Error")
+                    .text("Key", &result.key)
+                    .text("Code", error.code)
+                    .text("Message", error.message)
+                    .end();
+            }
+        }
+    }
+
+    Ok(xml.finish(200))
+}
+
+// CreateMultipartUpload. The settings of the object are taken now, the
+// request completing the upload only lists its parts
+async fn create_upload(req: &Request, bucket: &Bucket, key: &str, auth_context: &AuthContext) -> Result<Response, S3Error> {
+    reject_object_lock(req)?;
+
+    // Checked again on completion, but parts shouldn't pile up for nothing
+    if !bucket.is_managed_by(auth_context) {
+        return Err(ACCESS_DENIED);
+    }
+
+    let readable_by = readable_by(req, bucket, auth_context)?;
+    let user_metadata = user_metadata(req)?;
+    let mime_type = mime_type(&req.headers);
+
+    let upload = Upload::create(&bucket.name, key, &auth_context.access_key, mime_type.to_str(), readable_by, user_metadata)
+        .await
+        .map_err(|e| {
+            error!("{}: failed to create upload - {}", key, e);
+            INTERNAL_ERROR
+        })?;
+
+    let mut xml = Xml::new("InitiateMultipartUploadResult");
+    xml.text("Bucket", &bucket.name).text("Key", key).text("UploadId", &upload.id);
+
+    Ok(xml.finish(200))
+}
+
+// The upload named by the query, only whoever started it can see it
+async fn find_upload(params: &Params, bucket: &Bucket, key: &str, auth_context: &AuthContext) -> Result<Upload, S3Error> {
+    let id = params.get("uploadId").unwrap_or_default();
+
+    Upload::find(id, &bucket.name, key)
+        .await
+        .filter(|upload| upload.owner_id == auth_context.access_key)
+        .ok_or(NO_SUCH_UPLOAD)
+}
+
+async fn upload_part(
+    req: &Request,
+    params: &Params,
+    bucket: &Bucket,
+    key: &str,
+    auth_context: &AuthContext,
+    signed: Option<&Signed>,
+) -> Result<Response, S3Error> {
+    let number = params
+        .get("partNumber")
+        .and_then(|number| number.parse::<u32>().ok())
+        .filter(|number| multipart::PART_NUMBERS.contains(number))
+        .ok_or(INVALID_ARGUMENT)?;
+
+    let upload = find_upload(params, bucket, key, auth_context).await?;
+
+    let body = upload_body(req, signed)?;
+    if body.len() as u64 > config::get().s3.max_object_size {
+        return Err(ENTITY_TOO_LARGE);
+    }
+
+    let _quota = storage::reserve_part_quota(bucket, &upload, number, body.len() as u64).await?;
+    let etag = upload.put_part(number, &body).await.map_err(|e| {
+        error!("{}: failed to store part {} - {}", upload.id, number, e);
+        INTERNAL_ERROR
+    })?;
+
+    let mut res = Response::new(200);
+    res.set_header("etag", &format!("\"{}\"", etag));
+    Ok(res)
+}
+
+// Stores the listed parts as one object and removes the upload
+async fn complete_upload(
+    req: &Request,
+    params: &Params,
+    storage: &Storage,
+    bucket: &Bucket,
+    key: &str,
+    auth_context: &AuthContext,
+    signed: Option<&Signed>,
+) -> Result<Response, S3Error> {
+    let upload = find_upload(params, bucket, key, auth_context).await?;
+
+    let (body, _) = sigv4::decode_body(req, signed)?;
+    let body = std::str::from_utf8(&body).map_err(|_| MALFORMED_XML)?;
+
+    let mut listed = Vec::new();
+    for part in elements(body, "Part") {
+        let number = elements(part, "PartNumber").first().and_then(|number| number.trim().parse::<u32>().ok());
+        let etag = elements(part, "ETag").first().map(|etag| unescape(etag));
+
+        match (number, etag) {
+            (Some(number), Some(etag)) => listed.push((number, etag)),
+            _ => return Err(MALFORMED_XML),
+        }
+    }
+
+    let data = upload.assemble(&listed, config::get().s3.max_object_size).await?;
+
+    let options = PutOptions {
+        mime_type: MimeType::from_str(&upload.mime_type),
+        readable_by: upload.readable_by.clone(),
+        expires_at: None,
+        customer_key: None,
+        user_metadata: upload.user_metadata.clone(),
+        retain_until: None,
+        legal_hold: false,
+        completes: Some(&upload.id),
+    };
+    storage.put_object(key, &data, options).await?;
+
+    if let Err(e) = upload.remove().await {
+        error!("{}: failed to remove completed upload - {}", upload.id, e);
+    }
+
+    let mut xml = Xml::new("CompleteMultipartUploadResult");
+    xml.text("Location", format!("/{}/{}", bucket.name, sigv4::uri_encode(key, false)))
+        .text("Bucket", &bucket.name)
+        .text("Key", key)
+        .text("ETag", format!("\"{}\"", hex::encode(Md5::digest(&data))));
+
+    Ok(xml.finish(200))
+}
+
+async fn abort_upload(params: &Params, bucket: &Bucket, key: &str, auth_context: &AuthContext) -> Result<Response, S3Error> {
+    let upload = find_upload(params, bucket, key, auth_context).await?;
+
+    upload.remove().await.map_err(|e| {
+        error!("{}: failed to abort upload - {}", upload.id, e);
+        INTERNAL_ERROR
+    })?;
+
+    Ok(Response::new(204))
+}
+
+async fn list_parts(params: &Params, bucket: &Bucket, key: &str, auth_context: &AuthContext) -> Result<Response, S3Error> {
+    let upload = find_upload(params, bucket, key, auth_context).await?;
+    let max_parts = params.max("max-parts")?;
+    let marker = match params.get("part-number-marker") {
+        Some(marker) => marker.parse::<u32>().map_err(|_| INVALID_ARGUMENT)?,
+        None => 0,
+    };
+
+    let parts = upload.parts().await.map_err(|e| {
+        error!("{}: failed to list parts - {}", upload.id, e);
+        INTERNAL_ERROR
+    })?;
+    let parts: Vec<_> = parts.into_iter().filter(|part| part.number > marker).collect();
+    let truncated = parts.len() > max_parts;
+    let parts = &parts[..parts.len().min(max_parts)];
+
+    let mut xml = Xml::new("ListPartsResult");
+    xml.text("Bucket", &bucket.name)
+        .text("Key", key)
+        .text("UploadId", &upload.id)
+        .text("PartNumberMarker", marker)
+        .text("MaxParts", max_parts)
+        .text("IsTruncated", truncated);
+    if let Some(last) = parts.last().filter(|_| truncated) {
+        xml.text("NextPartNumberMarker", last.number);
+    }
+
+    for part in parts {
+        xml.start("Part")
+            .text("PartNumber", part.number)
+            .text("LastModified", iso8601(part.last_modified))
+            .text("ETag", format!("\"{}\"", part.etag))
+            .text("Size", part.size)
+            .end();
+    }
+
+    Ok(xml.finish(200))
+}
+
+// Uploads in progress in the bucket, ordered by key
+async fn list_uploads(params: &Params, bucket: &Bucket, auth_context: &AuthContext) -> Result<Response, S3Error> {
+    if !bucket.is_managed_by(auth_context) {
+        return Err(ACCESS_DENIED);
+    }
+
+    let prefix = params.get("prefix").unwrap_or_default();
+    let key_marker = params.get("key-marker").unwrap_or_default();
+    let max_uploads = params.max("max-uploads")?;
+
+    let mut uploads: Vec<Upload> = Upload::in_progress()
+        .await
+        .map_err(|e| {
+            error!("Failed to list uploads - {}", e);
+            INTERNAL_ERROR
+        })?
+        .into_iter()
+        .filter(|upload| upload.bucket == bucket.name && upload.key.starts_with(prefix) && upload.key.as_str() > key_marker)
+        .collect();
+    uploads.sort_by(|a, b| a.key.cmp(&b.key).then_with(|| a.initiated.cmp(&b.initiated)));
+
+    let truncated = uploads.len() > max_uploads;
+    uploads.truncate(max_uploads);
+
+    let mut xml = Xml::new("ListMultipartUploadsResult");
+    xml.text("Bucket", &bucket.name)
+        .text("KeyMarker", key_marker)
+        .text("Prefix", prefix)
+        .text("MaxUploads", max_uploads)
+        .text("IsTruncated", truncated);
+    if let Some(last) = uploads.last().filter(|_| truncated) {
+        xml.text("NextKeyMarker", &last.key);
+    }
+
+    for upload in &uploads {
+        xml.start("Upload")
+            .text("Key", &upload.key)
+            .text("UploadId", &upload.id)
+            .text("Initiated", iso8601(upload.initiated))
+            .end();
+    }
+
+    Ok(xml.finish(200))
+}
+
+// The contents of an upload, with aws-chunked framing and any remaining
+// Content-Encoding undone and every checksum the client sent verified
+fn upload_body<'a>(req: &'a Request, signed: Option<&Signed>) -> Result<Cow<'a, [u8]>, S3Error> {
+    let (body, trailers) = sigv4::decode_body(req, signed)?;
+
+    let encoding = req
+        .headers
+        .get("content-encoding")
+        .map(|encoding| {
+            encoding
+                .split(',')
+                .map(str::trim)
+                .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("aws-chunked"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+
+    let contents = match encoding.is_empty() {
+        true => None,
+        false => compression::decode(&encoding, &body, config::get().limits.max_body_size)
+            .map(Some)
+            .map_err(|(status, message)| S3Error::new(status, "InvalidArgument", message))?,
+    };
+
+    checksum::verify(&req.headers, &trailers, &body, contents.as_deref().unwrap_or(&body))
+        .map_err(|message| S3Error::new(400, "BadDigest", message))?;
+
+    Ok(match contents {
+        Some(contents) => Cow::Owned(contents),
+        None => body,
+    })
+}
+
+// Objects are private or public, chosen with a canned ACL, and otherwise
+// readable like native uploads without X-Readable-By
+fn readable_by(req: &Request, bucket: &Bucket, auth_context: &AuthContext) -> Result<AuthLevel, S3Error> {
+    let readable_by = match req.headers.get("x-amz-acl").map(String::as_str) {
+        None => bucket
+            .settings
+            .default_readable_by
+            .clone()
+            .unwrap_or_else(|| config::get().uploads.default_readable_by.clone()),
+        Some("private") => AuthLevel::Owner,
+        Some("public-read") => AuthLevel::Public,
+        Some(_) => return Err(NOT_IMPLEMENTED),
+    };
+
+    if readable_by != AuthLevel::Public && auth_context.access_level < AuthLevel::ReadWrite {
+        return Err(ACCESS_DENIED);
+    }
+
+    Ok(readable_by)
+}
+
+// Locks are set with the native API, S3 object lock is not supported
+fn reject_object_lock(req: &Request) -> Result<(), S3Error> {
+    match req.headers.keys().any(|name| name.starts_with("x-amz-object-lock-")) {
+        true => Err(NOT_IMPLEMENTED),
+        false => Ok(()),
+    }
+}
+
+fn user_metadata(req: &Request) -> Result<std::collections::BTreeMap<String, String>, S3Error> {
+    metadata::user_metadata_from_headers(&req.headers, USER_METADATA_PREFIX)
+        .map_err(|message| S3Error::new(400, "InvalidArgument", message))
+}
+
+fn mime_type(headers: &HashMap<String, String>) -> MimeType {
+    let content_type = headers.get("content-type").map(String::as_str).unwrap_or_default();
+    MimeType::from_str(content_type.split(';').next().unwrap_or_default().trim())
+}
+
+fn set_object_headers(res: &mut Response, metadata: &Metadata) {
+    res.set_header("etag", &etag(metadata));
+    res.set_header("last-modified", &http_date(metadata.last_modified));
+    res.set_header("content-type", &metadata.mime_type);
+    res.set_header("accept-ranges", "bytes");
+    metadata::set_user_metadata_headers(res, metadata, USER_METADATA_PREFIX);
+}
+
+// S3 clients expect the quoted hex MD5 digest of the contents, older objects
+// without one keep their native etag
+fn etag(metadata: &Metadata) -> String {
+    let md5 = metadata.checksums.as_ref().and_then(|checksums| STANDARD.decode(&checksums.md5).ok());
+
+    match md5 {
+        Some(md5) => format!("\"{}\"", hex::encode(md5)),
+        None => format!("\"{}\"", metadata.etag),
+    }
+}
+
+// Whether an If-Match or If-None-Match list names the etag
+fn matches_etag(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_matches('"') == etag.trim_matches('"'))
+}
+
+// Builds a response document, elements left open are closed by `finish`
+struct Xml {
+    out: String,
+    open: Vec<&'static str>,
+}
+
+impl Xml {
+    fn new(root: &'static str) -> Self {
+        Self::with_root(&format!("<{} xmlns=\"{}\">", root, XMLNS), root)
+    }
+
+    fn with_root(start: &str, root: &'static str) -> Self {
+        let out = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", start);
+        Self { out, open: vec![root] }
+    }
+
+    fn start(&mut self, name: &'static str) -> &mut Self {
+        self.out.push_str(&format!("<{}>", name));
+        self.open.push(name);
+        self
+    }
+
+    fn end(&mut self) -> &mut Self {
+        if let Some(name) = self.open.pop() {
+            self.out.push_str(&format!("</{}>", name));
+        }
+        self
+    }
+
+    fn text(&mut self, name: &str, value: impl Display) -> &mut Self {
+        self.out.push_str(&format!("<{0}>{1}</{0}>", name, escape(&value.to_string())));
+        self
+    }
+
+    fn finish(mut self, status_code: u16) -> Response {
+        while !self.open.is_empty() {
+            self.end();
+        }
+
+        let mut res = Response::new(status_code);
+        res.set_body(self.out.into_bytes(), MimeType::ApplicationXml);
+        res
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Contents of every `name` element of a request document, in order. The
+// documents clients send are small and flat, elements below the root carry
+// no attributes
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let mut found = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        let Some(end) = after.find(&close) else {
+            break;
+        };
+
+        found.push(&after[..end]);
+        rest = &after[end + close.len()..];
+    }
+
+    found
+}
+
+// Year, month and day of a count of days since 1970-01-01
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let (era, day_of_era) = (days / 146097, days % 146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+
+    (era * 400 + year_of_era + (month <= 2) as u64, month, day)
+}
+
+// `2023-11-14T22:13:20.000Z`, how S3 documents give times
+fn iso8601(time: u64) -> String {
+    let (year, month, day) = civil_from_days(time / 86400);
+    let seconds = time % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// `Tue, 14 Nov 2023 22:13:20 GMT`, how S3 headers give times
+fn http_date(time: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let days = time / 86400;
+    let (year, month, day) = civil_from_days(days);
+    let seconds = time % 86400;
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn list_page(keys: &[&str], prefix: &str, delimiter: Option<&str>, start_after: &str, max_keys: usize) -> Page<()> {
+        let mut page = Page::default();
+        for key in keys {
+            if !page.offer(key, (), prefix, delimiter, start_after, max_keys) {
+                break;
+            }
+        }
+        page
+    }
+
+    #[test]
+    fn test_list_page_and_formats() {
+        let keys = ["a.txt", "photos/1.jpg", "photos/2.jpg", "videos/x.mp4", "z"];
+        let listed = |page: &Page<()>| page.contents.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+
+        let page = list_page(&keys, "", Some("/"), "", 2);
+        assert_eq!(listed(&page), ["a.txt"]);
+        assert_eq!(page.common_prefixes, ["photos/"]);
+        assert!(page.truncated);
+        assert_eq!(page.last.as_deref(), Some("photos/"));
+
+        let page = list_page(&keys, "", Some("/"), "photos/", 2);
+        assert_eq!(listed(&page), ["z"]);
+        assert_eq!(page.common_prefixes, ["videos/"]);
+        assert!(!page.truncated);
+
+        let page = list_page(&keys, "photos/", None, "photos/1.jpg", 10);
+        assert_eq!(listed(&page), ["photos/2.jpg"]);
+
+        let body = "<Delete><Object><Key>a&amp;b</Key></Object><Object><Key>c</Key></Object></Delete>";
+        let keys: Vec<String> = elements(body, "Key").into_iter().map(unescape).collect();
+        assert_eq!(keys, ["a&b", "c"]);
+        assert_eq!(escape("<a & 'b'>"), "&lt;a &amp; &apos;b&apos;&gt;");
+
+        assert_eq!(iso8601(1700000000), "2023-11-14T22:13:20.000Z");
+        assert_eq!(http_date(1700000000), "Tue, 14 Nov 2023 22:13:20 GMT");
+        assert_eq!(iso8601(951782400), "2000-02-29T00:00:00.000Z");
+    }
+
+    // Sends a request through the API as a client signing with `auth_context`
+    // would, anonymously without one
+    async fn send(method: Method, uri: &str, body: &[u8], auth_context: Option<&AuthContext>) -> Response {
+        let mut req = Request::new(method, uri.to_string(), "HTTP/1.1".to_string());
+        req.headers.insert("host".to_string(), "localhost:8000".to_string());
+        req.raw_body = body.to_vec();
+
+        let (auth_context, signed) = match auth_context {
+            Some(auth_context) => {
+                testing::sign(&mut req, auth_context, "host;x-amz-content-sha256;x-amz-date");
+                let signed = sigv4::authenticate(&req, testing::SIGNED_AT).unwrap().unwrap();
+                (signed.auth_context.clone(), Some(signed))
+            }
+            None => (AuthContext::random(), None),
+        };
+
+        // Objects are streamed after the response, tests want them in the body
+        let (mut res, transfer) = handle(req, auth_context, signed).await;
+        if let Some(transfer) = transfer {
+            transfer.send(&mut res.body, std::time::Duration::from_secs(5)).await.unwrap();
+        }
+
+        res
+    }
+
+    fn text(res: &Response) -> &str {
+        std::str::from_utf8(&res.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let _guard = testing::setup().await;
+        let owner = testing::session(AuthLevel::ReadWrite);
+        let other = testing::session(AuthLevel::ReadWrite);
+
+        assert_eq!(send(Method::PUT, "/s3-routing", b"", Some(&owner)).await.status_code, 200);
+        assert_eq!(send(Method::PUT, "/s3-routing", b"", Some(&owner)).await.status_code, 409);
+        assert_eq!(send(Method::PUT, "/s3-routing", b"", None).await.status_code, 403);
+
+        let res = send(Method::PUT, "/s3-routing/dir/a.txt", b"hello", Some(&owner)).await;
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.headers.get("etag").unwrap(), &format!("\"{}\"", hex::encode(Md5::digest(b"hello"))));
+
+        let res = send(Method::GET, "/s3-routing/dir/a.txt", b"", Some(&owner)).await;
+        assert_eq!((res.status_code, &res.body[..]), (200, &b"hello"[..]));
+        let res = send(Method::HEAD, "/s3-routing/dir/a.txt", b"", Some(&owner)).await;
+        assert_eq!(res.status_code, 200);
+        assert!(res.body.is_empty());
+
+        // Only whoever manages the bucket stores objects in it
+        let res = send(Method::PUT, "/s3-routing/b.txt", b"no", Some(&other)).await;
+        assert_eq!(res.status_code, 403);
+
+        let res = send(Method::GET, "/s3-routing?list-type=2&delimiter=%2F", b"", Some(&owner)).await;
+        assert_eq!(res.status_code, 200);
+        assert_eq!(elements(text(&res), "Prefix"), ["", "dir/"]);
+
+        // Pages seek past rolled up keys and stop once they are full
+        for key in ["dir/b.txt", "e.txt", "f.txt"] {
+            send(Method::PUT, &format!("/s3-routing/{}", key), b"x", Some(&owner)).await;
+        }
+        let res = send(Method::GET, "/s3-routing?list-type=2&delimiter=%2F&max-keys=2", b"", Some(&owner)).await;
+        assert_eq!(elements(text(&res), "Prefix"), ["", "dir/"]);
+        assert_eq!(elements(text(&res), "Key"), ["e.txt"]);
+        assert_eq!(elements(text(&res), "IsTruncated"), ["true"]);
+        let res = send(Method::GET, "/s3-routing?list-type=2&delimiter=%2F&start-after=e.txt", b"", Some(&owner)).await;
+        assert_eq!(elements(text(&res), "Key"), ["f.txt"]);
+        assert_eq!(elements(text(&res), "IsTruncated"), ["false"]);
+        for key in ["dir/b.txt", "e.txt", "f.txt"] {
+            send(Method::DELETE, &format!("/s3-routing/{}", key), b"", Some(&owner)).await;
+        }
+
+        let classify = |method, uri: &str| {
+            let mut req = Request::new(method, uri.to_string(), "HTTP/1.1".to_string());
+            req.headers.insert("host".to_string(), "localhost:8000".to_string());
+            request_class(&req)
+        };
+        assert_eq!(classify(Method::GET, "/s3-routing?list-type=2"), RequestClass::List);
+        assert_eq!(classify(Method::GET, "/"), RequestClass::List);
+        assert_eq!(classify(Method::GET, "/s3-routing/big.bin?uploadId=1"), RequestClass::List);
+        assert_eq!(classify(Method::GET, "/s3-routing/dir/a.txt"), RequestClass::Read);
+        assert_eq!(classify(Method::PUT, "/s3-routing/dir/a.txt"), RequestClass::Write);
+
+        let res = send(Method::GET, "/", b"", Some(&owner)).await;
+        assert!(elements(text(&res), "Name").contains(&"s3-routing"));
+        assert_eq!(send(Method::GET, "/", b"", None).await.status_code, 403);
+
+        let res = send(Method::GET, "/s3-routing?acl", b"", Some(&owner)).await;
+        assert_eq!(res.status_code, 501);
+        let res = send(Method::GET, "/s3-missing/a.txt", b"", Some(&owner)).await;
+        assert_eq!((res.status_code, elements(text(&res), "Code")), (404, vec!["NoSuchBucket"]));
+        let res = send(Method::GET, "/s3-routing/missing.txt", b"", Some(&owner)).await;
+        assert_eq!(res.status_code, 404);
+
+        assert_eq!(send(Method::DELETE, "/s3-routing", b"", Some(&owner)).await.status_code, 409);
+        assert_eq!(send(Method::DELETE, "/s3-routing/dir/a.txt", b"", Some(&owner)).await.status_code, 204);
+        assert_eq!(send(Method::DELETE, "/s3-routing", b"", Some(&owner)).await.status_code, 204);
+    }
+
+    #[tokio::test]
+    async fn test_multipart() {
+        let _guard = testing::setup().await;
+        let owner = testing::session(AuthLevel::ReadWrite);
+        send(Method::PUT, "/s3-multipart", b"", Some(&owner)).await;
+
+        let res = send(Method::POST, "/s3-multipart/big.bin?uploads", b"", Some(&owner)).await;
+        assert_eq!(res.status_code, 200);
+        let id = elements(text(&res), "UploadId")[0].to_string();
+
+        let mut parts = String::new();
+        for (number, data) in [(1, &b"first "[..]), (2, &b"second"[..])] {
+            let uri = format!("/s3-multipart/big.bin?partNumber={}&uploadId={}", number, id);
+            let res = send(Method::PUT, &uri, data, Some(&owner)).await;
+            assert_eq!(res.status_code, 200);
+            let etag = res.headers.get("etag").unwrap();
+            parts.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", number, etag));
+        }
+
+        // Another client can't see or add to the upload
+        let other = testing::session(AuthLevel::ReadWrite);
+        let uri = format!("/s3-multipart/big.bin?partNumber=3&uploadId={}", id);
+        assert_eq!(send(Method::PUT, &uri, b"x", Some(&other)).await.status_code, 404);
+
+        let res = send(Method::GET, &format!("/s3-multipart/big.bin?uploadId={}", id), b"", Some(&owner)).await;
+        assert_eq!(elements(text(&res), "PartNumber"), ["1", "2"]);
+        assert_eq!(elements(text(&res), "Size"), ["6", "6"]);
+        assert_eq!(elements(text(&res), "ETag")[1], format!("&quot;{}&quot;", hex::encode(Md5::digest(b"second"))));
+
+        let uri = format!("/s3-multipart/big.bin?uploadId={}", id);
+        let unordered = "<CompleteMultipartUpload><Part><PartNumber>2</PartNumber><ETag>x</ETag></Part>\
+            <Part><PartNumber>1</PartNumber><ETag>x</ETag></Part></CompleteMultipartUpload>";
+        let res = send(Method::POST, &uri, unordered.as_bytes(), Some(&owner)).await;
+        assert_eq!((res.status_code, elements(text(&res), "Code")), (400, vec!["InvalidPartOrder"]));
+
+        let complete = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts);
+        let res = send(Method::POST, &uri, complete.as_bytes(), Some(&owner)).await;
+        assert_eq!(res.status_code, 200);
+
+        let res = send(Method::GET, "/s3-multipart/big.bin", b"", Some(&owner)).await;
+        assert_eq!(&res.body[..], b"first second");
+        // The upload is gone once completed
+        assert_eq!(send(Method::POST, &uri, complete.as_bytes(), Some(&owner)).await.status_code, 404);
+
+        let res = send(Method::POST, "/s3-multipart/aborted.bin?uploads", b"", Some(&owner)).await;
+        let uri = format!("/s3-multipart/aborted.bin?uploadId={}", elements(text(&res), "UploadId")[0]);
+        assert_eq!(send(Method::DELETE, &uri, b"", Some(&owner)).await.status_code, 204);
+        assert_eq!(send(Method::GET, &uri, b"", Some(&owner)).await.status_code, 404);
+    }
+
+}
@@ -0,0 +1,263 @@
+use std::cmp::Ordering;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use crate::metadata::Metadata;
+
+// Results returned per page unless the query asks for fewer
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    #[default]
+    Key,
+    Size,
+    LastModified,
+}
+
+// Filters of a LIST request, every one that is set has to match
+#[derive(Debug, Default)]
+pub struct Query {
+    pub prefix: Option<String>,
+    // A full type such as `image/png` or all subtypes with `image/*`
+    pub mime_type: Option<String>,
+    pub owner: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    // Unix times, `modified_after` is inclusive and `modified_before` not
+    pub modified_after: Option<u64>,
+    pub modified_before: Option<u64>,
+    // Name and value pairs of user metadata, names are lowercase
+    pub user_metadata: Vec<(String, String)>,
+    pub sort: SortField,
+    pub descending: bool,
+    pub limit: usize,
+    // Sort value and key of the last result of the previous page
+    cursor: Option<(u64, String)>,
+}
+
+// Splits a request target into its path and query string
+pub fn split_uri(uri: &str) -> (&str, Option<&str>) {
+    match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    }
+}
+
+// Decodes a query string component, `+` stands for a space
+pub fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = value.get(i + 1..i + 3)?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+impl Query {
+    // Parses the query string of a LIST request, `owner=me` stands for `caller`
+    pub fn parse(query: &str, caller: &str) -> Result<Self, String> {
+        let mut parsed = Self {
+            limit: MAX_LIMIT,
+            ..Default::default()
+        };
+
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = percent_decode(name).ok_or("Invalid query string")?;
+            let value = percent_decode(value).ok_or("Invalid query string")?;
+            let number = || value.parse::<u64>().map_err(|_| format!("Invalid value for {}", name));
+
+            match name.as_str() {
+                "prefix" => parsed.prefix = Some(value),
+                "mime_type" => parsed.mime_type = Some(value.to_ascii_lowercase()),
+                "owner" if value == "me" => parsed.owner = Some(caller.to_string()),
+                "owner" => parsed.owner = Some(value),
+                "min_size" => parsed.min_size = Some(number()?),
+                "max_size" => parsed.max_size = Some(number()?),
+                "modified_after" => parsed.modified_after = Some(number()?),
+                "modified_before" => parsed.modified_before = Some(number()?),
+                "sort" => {
+                    let (descending, field) = match value.strip_prefix('-') {
+                        Some(field) => (true, field),
+                        None => (false, value.as_str()),
+                    };
+                    parsed.descending = descending;
+                    parsed.sort = match field {
+                        "key" => SortField::Key,
+                        "size" => SortField::Size,
+                        "last_modified" => SortField::LastModified,
+                        _ => return Err("Invalid value for sort".to_string()),
+                    };
+                }
+                "limit" => match number()? {
+                    limit @ 1.. => parsed.limit = (limit as usize).min(MAX_LIMIT),
+                    0 => return Err("Invalid value for limit".to_string()),
+                },
+                "cursor" => parsed.cursor = Some(decode_cursor(&value).ok_or("Invalid value for cursor")?),
+                name => match name.strip_prefix("meta.") {
+                    Some(field) if !field.is_empty() => {
+                        parsed.user_metadata.push((field.to_ascii_lowercase(), value));
+                    }
+                    _ => return Err(format!("Unknown query parameter {}", name)),
+                },
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    // Objects under `prefix` in key order, `limit` at a time
+    pub fn prefix(prefix: &str, limit: usize) -> Self {
+        Self {
+            prefix: Some(prefix.to_string()),
+            limit,
+            ..Default::default()
+        }
+    }
+
+    // Whether `metadata` passes every filter, access checks are up to the caller
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        let mime_type = match self.mime_type.as_deref().map(|mime_type| mime_type.strip_suffix("/*")) {
+            None => true,
+            Some(Some(kind)) => metadata
+                .mime_type
+                .split_once('/')
+                .is_some_and(|(actual, _)| actual.eq_ignore_ascii_case(kind)),
+            Some(None) => self.mime_type.as_deref() == Some(metadata.mime_type.to_ascii_lowercase().as_str()),
+        };
+
+        mime_type
+            && self.prefix.as_ref().is_none_or(|prefix| metadata.key.starts_with(prefix))
+            && self.owner.as_ref().is_none_or(|owner| metadata.owner_id == *owner)
+            && self.min_size.is_none_or(|size| metadata.size >= size)
+            && self.max_size.is_none_or(|size| metadata.size <= size)
+            && self.modified_after.is_none_or(|time| metadata.last_modified >= time)
+            && self.modified_before.is_none_or(|time| metadata.last_modified < time)
+            && self
+                .user_metadata
+                .iter()
+                .all(|(name, value)| metadata.user_metadata.get(name) == Some(value))
+    }
+
+    fn sort_value(&self, metadata: &Metadata) -> u64 {
+        match self.sort {
+            SortField::Key => 0,
+            SortField::Size => metadata.size,
+            SortField::LastModified => metadata.last_modified,
+        }
+    }
+
+    // Order of results, ties are broken by key so pages never overlap
+    fn compare(&self, a: (u64, &str), b: (u64, &str)) -> Ordering {
+        let ordering = a.cmp(&b);
+        match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    }
+
+    // Sorts the matching objects and cuts out the requested page, returning
+    // it with the cursor of the next page if there is one
+    pub fn paginate(&self, mut results: Vec<Metadata>) -> (Vec<Metadata>, Option<String>) {
+        results.sort_by(|a, b| self.compare((self.sort_value(a), &a.key), (self.sort_value(b), &b.key)));
+
+        if let Some((value, key)) = &self.cursor {
+            results.retain(|metadata| {
+                self.compare((self.sort_value(metadata), &metadata.key), (*value, key)) == Ordering::Greater
+            });
+        }
+
+        if results.len() <= self.limit {
+            return (results, None);
+        }
+
+        results.truncate(self.limit);
+        let cursor = results
+            .last()
+            .map(|last| encode_cursor(self.sort_value(last), &last.key));
+
+        (results, cursor)
+    }
+}
+
+fn encode_cursor(value: u64, key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}:{}", value, key))
+}
+
+fn decode_cursor(cursor: &str) -> Option<(u64, String)> {
+    let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (value, key) = decoded.split_once(':')?;
+    Some((value.parse().ok()?, key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::AuthLevel;
+    use std::collections::BTreeMap;
+
+    fn metadata(key: &str, mime_type: &str, size: u64, album: &str) -> Metadata {
+        Metadata {
+            name: key.to_string(),
+            key: key.to_string(),
+            size,
+            last_modified: 100,
+            etag: String::new(),
+            mime_type: mime_type.to_string(),
+            owner_id: "alice".to_string(),
+            readable_by: AuthLevel::Public,
+            expires_at: None,
+            stored_encoding: None,
+            encryption: None,
+            checksums: None,
+            user_metadata: BTreeMap::from([("album".to_string(), album.to_string())]),
+            retain_until: None,
+            legal_hold: false,
+        }
+    }
+
+    #[test]
+    fn test_filters_and_pages() {
+        let objects = vec![
+            metadata("a.png", "image/png", 3_000_000, "trip"),
+            metadata("b.png", "image/png", 10, "trip"),
+            metadata("c.jpg", "image/jpeg", 2_000_000, "trip"),
+            metadata("d.png", "image/png", 5_000_000, "home"),
+            metadata("e.txt", "text/plain", 4_000_000, "trip"),
+        ];
+
+        let query = Query::parse("mime_type=image%2F*&owner=me&min_size=1000000&meta.Album=trip", "alice").unwrap();
+        let keys: Vec<_> = objects.iter().filter(|m| query.matches(m)).map(|m| m.key.as_str()).collect();
+        assert_eq!(keys, ["a.png", "c.jpg"]);
+
+        let query = Query::parse("sort=-size&limit=2", "alice").unwrap();
+        let (page, cursor) = query.paginate(objects.clone());
+        assert_eq!(page.iter().map(|m| m.key.as_str()).collect::<Vec<_>>(), ["d.png", "e.txt"]);
+
+        let query = Query::parse(&format!("sort=-size&limit=2&cursor={}", cursor.unwrap()), "alice").unwrap();
+        let (page, _) = query.paginate(objects.clone());
+        assert_eq!(page.iter().map(|m| m.key.as_str()).collect::<Vec<_>>(), ["a.png", "c.jpg"]);
+
+        assert!(Query::parse("color=red", "alice").is_err());
+        assert!(Query::parse("min_size=big", "alice").is_err());
+        assert_eq!(percent_decode("a+b%2Fc").unwrap(), "a b/c");
+    }
+}
@@ -2,30 +2,73 @@ use a_http_parser::http::{Method, MimeType};
 use a_http_parser::parser::Parser;
 use a_http_parser::request::Request;
 use a_http_parser::response::Response;
-use tokio_stream::StreamExt;
+use log::{debug, error, info, warn};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
 
 use crate::authentication::{AuthContext, AuthLevel};
-use crate::metadata::Metadata;
-use crate::storable::{StorableBlob, StorableJson};
-use crate::storage::{Storage, Object};
+use crate::buckets::{self, BucketError, BucketSettings};
+use crate::batch::BatchDelete;
+use crate::checksum;
+use crate::compression;
+use crate::config::{self, ClassLimits};
+use crate::connections::{ConnRegistry, ConnectionLimits};
+use crate::cors;
+use crate::encryption::CustomerKey;
+use crate::health;
+use crate::maintenance;
+use crate::metadata::{self, LockRequest};
+use crate::metrics::{StorageStats, METRICS};
+use crate::proxy::{self, ClientInfo, TrustedProxies};
+use crate::ratelimit::{RateKey, RequestClass, RATE_LIMITER};
+use crate::s3;
+use crate::search::{self, Query};
+use crate::sessions;
+use crate::sigv4;
+use crate::storable::StorableBlob;
+use crate::storage::{PutOptions, Storage, StorageError};
+use crate::transfer::{self, Transfer};
+use crate::trash::{self, RestoreError};
+
+// Storage listeners serve the CDN itself, admin listeners serve operational
+// endpoints such as metrics and should not be exposed publicly. S3 listeners
+// serve the same objects to S3 clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerKind {
+    Storage,
+    Admin,
+    S3,
+}
+
+// A client connection, either a plain TCP socket or a TLS session on top of one
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
 
 pub struct Server {
     address: String,
     listener: TcpListener,
+    kind: ListenerKind,
+    limits: ConnectionLimits,
+    registry: Arc<ConnRegistry>,
+    tls: Option<TlsAcceptor>,
+    trusted: Arc<TrustedProxies>,
 }
 
 impl Server {
-    pub async fn new(address: &str) -> Self {
+    pub async fn new(address: &str, kind: ListenerKind, limits: ConnectionLimits) -> Self {
         let listener = match TcpListener::bind(address).await {
             Ok(listener) => {
-                println!("Listening on {}", address);
+                info!("Listening on {}", address);
                 listener
             }
             Err(error) => {
@@ -36,139 +79,316 @@ impl Server {
         Self {
             address: address.to_string(),
             listener,
+            kind,
+            registry: ConnRegistry::new(&limits),
+            limits,
+            tls: None,
+            trusted: Arc::new(config::get().trusted_proxies()),
         }
     }
 
-    pub async fn run(self) {
-        let (tx, rx) = mpsc::channel::<Arc<Mutex<Conn>>>(100);
+    // Terminates TLS on every accepted connection before reading requests
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        info!("{}: serving TLS", self.address);
+        self.tls = Some(acceptor);
+        self
+    }
 
-        let manager_handle = tokio::spawn(Self::manager_loop(rx));
-        let accept_handle = tokio::spawn(self.accept_loop(tx));
+    // Performs the TLS handshake if this listener has TLS enabled, it
+    // shares the header budget since no request can be read before it
+    async fn open_stream(
+        tls: Option<TlsAcceptor>,
+        socket: TcpStream,
+        address: SocketAddr,
+        handshake_timeout: Duration,
+    ) -> Option<Box<dyn Stream>> {
+        let Some(acceptor) = tls else {
+            return Some(Box::new(socket));
+        };
 
-        let _ = tokio::try_join!(manager_handle, accept_handle);
+        match timeout(handshake_timeout, acceptor.accept(socket)).await {
+            Ok(Ok(stream)) => Some(Box::new(stream)),
+            Ok(Err(error)) => {
+                debug!("{}: TLS handshake failed - {}", address, error);
+                None
+            }
+            Err(_) => {
+                debug!("{}: TLS handshake timed out", address);
+                None
+            }
+        }
     }
 
-    pub async fn manager_loop(mut rx: Receiver<Arc<Mutex<Conn>>>) {
-        let mut connections: Vec<Arc<Mutex<Conn>>> = Vec::new();
-
-        while let Some(conn) = rx.recv().await {
-            connections.push(conn);
-
-            // Here you can manage connections, send broadcast messages, etc.
-            // every time a client connects cleanup authentication sessions
-            // that are older than 30 minutes
-
-            if let Ok(mut list) = AuthContext::list().await {
-                'outer: while let Some(auth_context) = list.next().await.unwrap() {
-                    // Older than 30 minutes
-                    if SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs() as i64
-                        - auth_context.last_used as i64
-                        > 1800 as i64
-                    // Is not an admin
-                    && auth_context.access_level < AuthLevel::Admin
-                    // Is not the owner of any metadata
-                    {
-                        let mut list = Metadata::list().await.unwrap();
+    // Serves connections until `shutdown` flips to true, then stops accepting
+    // and gives in-flight connections `grace_period` to finish
+    pub async fn run(self, shutdown: watch::Receiver<bool>, grace_period: Duration) {
+        self.accept_loop(shutdown, grace_period).await;
+    }
 
-                        while let Some(metadata) = list.next().await.unwrap() {
-                            if metadata.owner_id == auth_context.access_key {
-                                continue 'outer;
-                            }
-                        }
+    async fn accept_loop(
+        self,
+        mut shutdown: watch::Receiver<bool>,
+        grace_period: Duration,
+    ) {
+        let mut in_flight: JoinSet<()> = JoinSet::new();
+        // A connection slot is reserved before accepting, so once the limit
+        // is reached new clients wait in the listen backlog
+        let mut slot = None;
 
-                        match auth_context.delete().await {
-                            Ok(_) => println!("{}: deleted auth context", auth_context.access_key),
-                            Err(_) => println!(
-                                "{}: failed to delete auth context",
-                                auth_context.access_key
-                            ),
-                        };
+        loop {
+            tokio::select! {
+                changed = shutdown.changed() => {
+                    // A dropped sender can never signal again, treat it as shutdown
+                    if changed.is_err() || *shutdown.borrow() {
+                        break;
                     }
                 }
+                // Reap finished connections so the set only holds live ones
+                Some(_) = in_flight.join_next(), if !in_flight.is_empty() => {}
+                reserved = self.registry.reserve(), if slot.is_none() => {
+                    slot = Some(reserved);
+                }
+                accepted = self.listener.accept(), if slot.is_some() => match accepted {
+                    Err(error) => {
+                        error!("{}: something bad happened - {}", self.address, error);
+                    }
+                    Ok((socket, address)) => {
+                        let permit = slot.take().unwrap();
+                        let tls = self.tls.clone();
+                        let limits = self.limits.clone();
+                        let trusted_peer = self.trusted.contains(address.ip());
+
+                        let guard = match self.registry.register(address, permit, trusted_peer) {
+                            Ok(guard) => guard,
+                            Err(permit) => {
+                                warn!("{}: too many connections from {}", self.address, address.ip());
+                                METRICS.connection_rejected();
+                                // Answering over TLS would take a handshake per
+                                // rejected connection, those are just closed
+                                if tls.is_none() {
+                                    in_flight.spawn(async move {
+                                        Conn::reject(Box::new(socket), limits.write_timeout).await;
+                                        drop(permit);
+                                    });
+                                }
+                                continue;
+                            }
+                        };
+
+                        let kind = self.kind;
+                        let trusted = Arc::clone(&self.trusted);
+                        let expect_proxy_header = kind == ListenerKind::Storage
+                            && trusted_peer
+                            && config::get().proxy.proxy_protocol;
+
+                        in_flight.spawn(async move {
+                            let mut socket = socket;
+                            let mut peer = address;
+
+                            // The PROXY header precedes everything else, TLS included
+                            if expect_proxy_header {
+                                match proxy::read_proxy_header(&mut socket, limits.header_timeout).await {
+                                    Ok(Some(source)) => peer = source,
+                                    Ok(None) => {}
+                                    Err(error) => {
+                                        debug!("{}: invalid PROXY protocol header - {}", address, error);
+                                        return;
+                                    }
+                                }
+                            }
+
+                            let is_tls = tls.is_some();
+                            if let Some(stream) = Self::open_stream(tls, socket, peer, limits.header_timeout).await {
+                                let mut conn = Conn::new(stream, peer, kind, limits, is_tls, trusted).await;
+                                conn.run().await;
+                            }
+                            drop(guard);
+                        });
+                    }
+                },
             }
         }
-    }
 
-    pub async fn accept_loop(self, tx: Sender<Arc<Mutex<Conn>>>) {
-        loop {
-            match self.listener.accept().await {
-                Err(error) => {
-                    println!("{}: something bad happened - {}", self.address, error);
-                }
-                Ok((socket, address)) => {
-                    let conn = Arc::new(Mutex::new(Conn::new(socket, address).await));
-                    let conn_clone = Arc::clone(&conn);
-
-                    tokio::spawn(async move {
-                        conn_clone.lock().await.run().await;
-                        // If you want to remove finished connections from the list,
-                        // you could send a message here using another channel.
-                    });
+        // Stop accepting before draining so new clients are refused right away
+        drop(self.listener);
 
-                    tx.send(conn).await.unwrap();
-                }
+        info!(
+            "{}: draining {} connection(s) for up to {}s",
+            self.address,
+            self.registry.len(),
+            grace_period.as_secs()
+        );
+
+        let drained = timeout(grace_period, async {
+            while in_flight.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            if let Some((address, age)) = self.registry.oldest() {
+                warn!(
+                    "{}: aborting {} connection(s) that outlived the grace period, oldest from {} open for {}s",
+                    self.address,
+                    self.registry.len(),
+                    address,
+                    age.as_secs()
+                );
             }
+            in_flight.shutdown().await;
         }
     }
 }
 
 pub struct Conn {
     address: SocketAddr,
-    socket: TcpStream,
+    socket: Box<dyn Stream>,
+    kind: ListenerKind,
+    limits: ConnectionLimits,
+    tls: bool,
+    trusted: Arc<TrustedProxies>,
 }
 
 impl Conn {
-    async fn new(socket: TcpStream, address: SocketAddr) -> Self {
-        Self { address, socket }
+    async fn new(
+        socket: Box<dyn Stream>,
+        address: SocketAddr,
+        kind: ListenerKind,
+        limits: ConnectionLimits,
+        tls: bool,
+        trusted: Arc<TrustedProxies>,
+    ) -> Self {
+        Self {
+            address,
+            socket,
+            kind,
+            limits,
+            tls,
+            trusted,
+        }
+    }
+
+    // Answers a connection that is over its per address limit without
+    // reading the request
+    async fn reject(mut socket: Box<dyn Stream>, write_timeout: Duration) {
+        let mut res = Response::new(429);
+        res.set_header("retry-after", "1");
+        res.set_body(
+            "Too many connections".as_bytes().to_vec(),
+            MimeType::TextPlain,
+        );
+
+        let _ = timeout(write_timeout, socket.write_all(&res.as_bytes())).await;
+        let _ = socket.shutdown().await;
     }
 
     async fn close(&mut self) {
         if let Err(e) = self.socket.shutdown().await {
-            println!("Failed to shutdown socket: {}", e);
+            debug!("Failed to shutdown socket: {}", e);
         }
     }
 
     async fn handle_storage(
         req: Request,
         res: &mut Response,
-        obj: &mut Option<Object>,
+        obj: &mut Option<Transfer>,
         auth_context: AuthContext,
     ) -> () {
         let storage = Storage::new(auth_context.clone());
-        let key = req.uri.trim_start_matches('/');
+        let path = req.uri.trim_start_matches('/');
+
+        if let Some(id) = sub_path(path, trash::PATH) {
+            Self::handle_trash(&req, res, &storage, &auth_context, id).await;
+            return;
+        }
+
+        if let Some(name) = sub_path(path, buckets::PATH) {
+            Self::handle_buckets(&req, res, &auth_context, name).await;
+            return;
+        }
 
-        if key.len() == 0 && req.method != Method::LIST && req.method != Method::TRACE {
+        if path == s3::CREDENTIALS_PATH {
+            Self::handle_credentials(&req, res, &auth_context);
+            return;
+        }
+
+        // Requests to a bucket address keys within it. Listings take a query
+        // string, so only their path can name the bucket
+        let path = match req.method {
+            Method::LIST | Method::TRACE => search::split_uri(&req.uri).0,
+            _ => req.uri.as_str(),
+        };
+        let (bucket, key) = match buckets::get().resolve(req.headers.get("host").map(String::as_str), path) {
+            Ok(resolved) => resolved,
+            Err(error) => {
+                res.set_status_code(error.status_code());
+                res.set_body(error.message().as_bytes().to_vec(), MimeType::TextPlain);
+                return;
+            }
+        };
+        let storage = storage.in_bucket(bucket.clone());
+
+        // A DELETE on the root deletes a batch of objects
+        if key.is_empty() && ![Method::LIST, Method::TRACE, Method::DELETE].contains(&req.method) {
             res.set_status_code(400);
             res.set_body("Bad request".as_bytes().to_vec(), MimeType::TextPlain);
             return;
         }
 
+        // Key for objects encrypted with a key only the client knows
+        let customer_key = match CustomerKey::from_headers(&req.headers) {
+            Ok(customer_key) => customer_key,
+            Err(message) => {
+                res.set_status_code(400);
+                res.set_body(message.as_bytes().to_vec(), MimeType::TextPlain);
+                return;
+            }
+        };
+
+        // Admins can override retentions in governance mode, every use is audited
+        let storage = match req.headers.get("x-bypass-governance").map(|value| value.trim()) {
+            Some(value) if value.eq_ignore_ascii_case("true") => match storage.bypass_governance() {
+                Ok(storage) => storage,
+                Err(_) => {
+                    res.set_status_code(403);
+                    res.set_body(
+                        "Governance bypass is not allowed".as_bytes().to_vec(),
+                        MimeType::TextPlain,
+                    );
+                    return;
+                }
+            },
+            _ => storage,
+        };
+
         match req.method {
             Method::GET => {
-                if let Some(object) = storage.get_object(key, false).await {
+                let object = match storage.get_object(key, false, customer_key.as_ref()).await {
+                    Ok(object) => Some(object),
+                    Err(StorageError::NotFound) => None,
+                    Err(error) => {
+                        res.set_status_code(error.status_code());
+                        res.set_body(error.message().as_bytes().to_vec(), MimeType::TextPlain);
+                        return;
+                    }
+                };
+
+                if let Some(object) = object {
                     if object.metadata.readable_by != AuthLevel::Public {
                         res.mark_required_authentication();
                     }
 
-                    if req.headers.get("if-none-match").is_some() {
-                        if req.headers.get("if-none-match").unwrap() == &object.metadata.etag {
-                            res.set_status_code(304);
-                            res.set_body("Not modified".as_bytes().to_vec(), MimeType::TextPlain);
-                            return;
-                        }
+                    if req.headers.get("if-none-match") == Some(&object.metadata.etag) {
+                        res.set_status_code(304);
+                        res.set_body("Not modified".as_bytes().to_vec(), MimeType::TextPlain);
+                        return;
                     }
 
-                    if req.headers.get("if-modified-since").is_some() {
-                        if req.headers.get("if-modified-since").unwrap()
-                            == &object.metadata.last_modified.to_string()
-                        {
-                            res.set_status_code(304);
-                            res.set_body("Not modified".as_bytes().to_vec(), MimeType::TextPlain);
-                            return;
-                        }
+                    if req.headers.get("if-modified-since")
+                        == Some(&object.metadata.last_modified.to_string())
+                    {
+                        res.set_status_code(304);
+                        res.set_body("Not modified".as_bytes().to_vec(), MimeType::TextPlain);
+                        return;
                     }
 
                     if let Some(accept) = req.headers.get("accept") {
@@ -185,10 +405,80 @@ impl Conn {
                     res.set_header("last-modified", &object.metadata.last_modified.to_string());
                     res.set_header("etag", &object.metadata.etag);
 
-                    res.set_header("content-length", &format!("{}", &object.get_file_size().await.unwrap()));
                     res.set_header("content-type", &object.metadata.mime_type);
 
-                    let _ = obj.insert(object);
+                    res.set_header("accept-ranges", "bytes");
+                    checksum::set_headers(res, &object.metadata, false);
+                    metadata::set_user_metadata_headers(res, &object.metadata, metadata::USER_METADATA_PREFIX);
+                    metadata::set_lock_headers(res, &object.metadata);
+                    set_customer_key_headers(res, customer_key.as_ref());
+
+                    // Stored sizes differ from the contents of compressed or encrypted objects
+                    let size = match object.metadata.is_transformed() {
+                        true => object.metadata.size,
+                        false => match object.get_file_size().await {
+                            Ok(size) => size,
+                            Err(_) => return set_blob_missing(res),
+                        },
+                    };
+
+                    let range = match req.headers.get("range").and_then(|range| transfer::parse_range(range, size)) {
+                        Some(Ok(range)) => Some(range),
+                        Some(Err(())) => {
+                            res.set_status_code(416);
+                            res.set_header("content-range", &format!("bytes */{}", size));
+                            res.set_body("Range Not Satisfiable".as_bytes().to_vec(), MimeType::TextPlain);
+                            return;
+                        }
+                        None => None,
+                    };
+
+                    let accept_encoding = req.headers.get("accept-encoding");
+                    // Objects only compressed at rest go out as stored when the client accepts that
+                    let stored_encoding = object
+                        .metadata
+                        .stored_encoding
+                        .clone()
+                        .filter(|_| object.metadata.encryption.is_none())
+                        .filter(|encoding| accept_encoding.is_some_and(|accept| compression::accepts(accept, encoding)));
+
+                    // Ranges always refer to the uncompressed contents
+                    if let Some(range) = range {
+                        res.set_status_code(206);
+                        res.set_header("content-range", &range.content_range(size));
+                        res.set_header("content-length", &range.len().to_string());
+                        let _ = obj.insert(Transfer::object(object).with_range(range));
+                    } else if let Some(stored_encoding) = stored_encoding {
+                        let stored_size = match object.get_file_size().await {
+                            Ok(stored_size) => stored_size,
+                            Err(_) => return set_blob_missing(res),
+                        };
+
+                        res.append_header("vary", "Accept-Encoding");
+                        res.set_header("content-encoding", &stored_encoding);
+                        res.set_header("content-length", &stored_size.to_string());
+                        let _ = obj.insert(Transfer::stored(object));
+                    } else {
+                        match compression::select(accept_encoding, &object.metadata.mime_type, size, res) {
+                            Some(encoding) => {
+                                METRICS.response_compressed(encoding.to_str());
+                                res.set_header("content-encoding", encoding.to_str());
+
+                                // Without a length the body ends when the connection is closed
+                                let (transfer, length) = Transfer::compressed(object, encoding).await;
+                                if let Some(length) = length {
+                                    res.set_header("content-length", &length.to_string());
+                                }
+
+                                let _ = obj.insert(transfer);
+                            }
+                            None => {
+                                res.set_header("content-length", &size.to_string());
+                                checksum::set_headers(res, &object.metadata, true);
+                                let _ = obj.insert(Transfer::object(object));
+                            }
+                        }
+                    }
                 } else {
                     res.set_status_code(404);
                     res.set_body(
@@ -200,8 +490,19 @@ impl Conn {
             Method::PUT | Method::POST => {
                 res.mark_required_authentication();
 
-                // This is a CDN so we should make it public by default
-                let mut readable_by = AuthLevel::Public;
+                if Storage::is_reserved_key(key) {
+                    res.set_status_code(400);
+                    res.set_body(
+                        "Key is reserved by the server".as_bytes().to_vec(),
+                        MimeType::TextPlain,
+                    );
+                    return;
+                }
+
+                let mut readable_by = bucket
+                    .as_ref()
+                    .and_then(|bucket| bucket.settings.default_readable_by.clone())
+                    .unwrap_or_else(|| config::get().uploads.default_readable_by.clone());
 
                 // Use header X-Readable-By to set read access for other users
                 if req.headers.contains_key("x-readable-by") {
@@ -224,16 +525,127 @@ impl Conn {
                     return;
                 }
 
-                if storage.put_object(
-                    key,
-                    &req.raw_body,
-                    req.mime_type.unwrap_or_default(),
-                    readable_by,
-                ).await {
-                    res.set_status_code(200);
-                } else {
+                // Use header X-Expires-After to remove the object after that many seconds
+                let mut expires_at = None;
+                if let Some(seconds) = req.headers.get("x-expires-after") {
+                    match seconds.trim().parse::<u64>() {
+                        Ok(seconds) => {
+                            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                            expires_at = Some(now.saturating_add(seconds));
+                        }
+                        Err(_) => {
+                            res.set_status_code(400);
+                            res.set_body(
+                                "Invalid X-Expires-After".as_bytes().to_vec(),
+                                MimeType::TextPlain,
+                            );
+                            return;
+                        }
+                    }
+                }
+
+                // Encoded uploads are stored by their decoded contents
+                let body = match req.headers.get("content-encoding") {
+                    Some(content_encoding) => {
+                        match compression::decode(content_encoding, &req.raw_body, config::get().limits.max_body_size) {
+                            Ok(body) => Cow::Owned(body),
+                            Err((status_code, message)) => {
+                                res.set_status_code(status_code);
+                                res.set_body(message.as_bytes().to_vec(), MimeType::TextPlain);
+                                return;
+                            }
+                        }
+                    }
+                    None => Cow::Borrowed(&req.raw_body[..]),
+                };
+
+                // Nothing is stored unless it matches the checksums the client sent
+                if let Err(message) = checksum::verify(&req.headers, &req.trailers, &req.raw_body, &body) {
                     res.set_status_code(400);
-                    res.set_body("Failed to save".as_bytes().to_vec(), MimeType::TextPlain);
+                    res.set_body(message.as_bytes().to_vec(), MimeType::TextPlain);
+                    return;
+                }
+
+                // Use headers X-Meta-<name> to attach searchable metadata
+                let user_metadata = match metadata::user_metadata_from_headers(&req.headers, metadata::USER_METADATA_PREFIX) {
+                    Ok(user_metadata) => user_metadata,
+                    Err(message) => {
+                        res.set_status_code(400);
+                        res.set_body(message.as_bytes().to_vec(), MimeType::TextPlain);
+                        return;
+                    }
+                };
+
+                // Use headers X-Lock-Retain-Until and X-Legal-Hold to protect the object
+                let lock = match LockRequest::from_headers(&req.headers) {
+                    Ok(lock) => lock,
+                    Err(message) => {
+                        res.set_status_code(400);
+                        res.set_body(message.as_bytes().to_vec(), MimeType::TextPlain);
+                        return;
+                    }
+                };
+
+                let options = PutOptions {
+                    mime_type: req.mime_type.unwrap_or_default(),
+                    readable_by,
+                    expires_at,
+                    customer_key: customer_key.as_ref(),
+                    user_metadata,
+                    retain_until: lock.retain_until,
+                    legal_hold: lock.legal_hold.unwrap_or(false),
+                    completes: None,
+                };
+
+                match storage.put_object(key, &body, options).await {
+                    Ok(()) => {
+                        res.set_status_code(200);
+                        set_customer_key_headers(res, customer_key.as_ref());
+                    }
+                    Err(error) => {
+                        res.set_status_code(error.status_code());
+                        res.set_body(error.message().as_bytes().to_vec(), MimeType::TextPlain);
+                    }
+                }
+            }
+            Method::PATCH => {
+                res.mark_required_authentication();
+
+                if auth_context.access_level < AuthLevel::ReadWrite {
+                    res.set_status_code(403);
+                    res.set_body("Forbidden".as_bytes().to_vec(), MimeType::TextPlain);
+                    return;
+                }
+
+                // Only the lock of an object can be changed in place
+                let lock = match LockRequest::from_headers(&req.headers) {
+                    Ok(lock) if !lock.is_empty() => lock,
+                    Ok(_) => {
+                        res.set_status_code(400);
+                        res.set_body(
+                            "Send X-Lock-Retain-Until or X-Legal-Hold".as_bytes().to_vec(),
+                            MimeType::TextPlain,
+                        );
+                        return;
+                    }
+                    Err(message) => {
+                        res.set_status_code(400);
+                        res.set_body(message.as_bytes().to_vec(), MimeType::TextPlain);
+                        return;
+                    }
+                };
+
+                match storage.update_lock(key, lock).await {
+                    Ok(metadata) => {
+                        let json = serde_json::to_string(&metadata).unwrap();
+                        res.set_status_code(200);
+                        res.set_body(json.into_bytes(), MimeType::ApplicationJson);
+                        metadata::set_lock_headers(res, &metadata);
+                    }
+                    Err(error) => {
+                        res.set_status_code(error.status_code());
+                        res.set_body(error.message().as_bytes().to_vec(), MimeType::TextPlain);
+                    }
                 }
             }
             Method::DELETE => {
@@ -245,15 +657,48 @@ impl Conn {
                     return;
                 }
 
-                if storage.delete_object(key).await {
+                if key.is_empty() {
+                    let max_batch_size = config::get().limits.max_batch_size;
+                    let batch = match BatchDelete::parse(&req.raw_body, max_batch_size) {
+                        Ok(batch) => batch,
+                        Err(message) => {
+                            res.set_status_code(400);
+                            res.set_body(message.into_bytes(), MimeType::TextPlain);
+                            return;
+                        }
+                    };
+
+                    let report = batch.run(&storage, max_batch_size).await;
+                    let json = serde_json::to_string(&report).unwrap();
+
                     res.set_status_code(200);
+                    res.set_body(json.as_bytes().to_vec(), MimeType::ApplicationJson);
                 } else {
-                    res.set_status_code(400);
-                    res.set_body("Failed to delete".as_bytes().to_vec(), MimeType::TextPlain);
+                    match storage.delete_object(key).await {
+                        Ok(()) => res.set_status_code(200),
+                        Err(error @ (StorageError::Locked | StorageError::AuditFailed)) => {
+                            res.set_status_code(error.status_code());
+                            res.set_body(error.message().as_bytes().to_vec(), MimeType::TextPlain);
+                        }
+                        Err(_) => {
+                            res.set_status_code(400);
+                            res.set_body("Failed to delete".as_bytes().to_vec(), MimeType::TextPlain);
+                        }
+                    }
                 }
             }
             Method::HEAD => {
-                if let Some(object) = storage.get_object(key, false).await {
+                let object = match storage.get_object(key, false, customer_key.as_ref()).await {
+                    Ok(object) => Some(object),
+                    Err(StorageError::NotFound) => None,
+                    Err(error) => {
+                        res.set_status_code(error.status_code());
+                        res.set_body(error.message().as_bytes().to_vec(), MimeType::TextPlain);
+                        return;
+                    }
+                };
+
+                if let Some(object) = object {
                     if object.metadata.readable_by != AuthLevel::Public {
                         res.mark_required_authentication();
                     }
@@ -267,6 +712,11 @@ impl Conn {
                     res.set_header("Etag", &object.metadata.etag);
                     res.set_header("Content-Type", &object.metadata.mime_type);
                     res.set_header("Content-Length", &object.metadata.size.to_string());
+                    res.set_header("Accept-Ranges", "bytes");
+                    checksum::set_headers(res, &object.metadata, true);
+                    metadata::set_user_metadata_headers(res, &object.metadata, metadata::USER_METADATA_PREFIX);
+                    metadata::set_lock_headers(res, &object.metadata);
+                    set_customer_key_headers(res, customer_key.as_ref());
                 } else {
                     res.set_status_code(404);
                     res.set_body("Not Found".as_bytes().to_vec(), MimeType::TextPlain);
@@ -275,28 +725,221 @@ impl Conn {
             Method::LIST | Method::TRACE => {
                 res.mark_required_authentication();
 
-                // list objects in JSON
-                let objects = storage.list_objects().await;
+                // A query string searches instead, see search::Query for the filters
+                let objects = match search::split_uri(&req.uri).1 {
+                    Some(query) => {
+                        let query = match Query::parse(query, &auth_context.access_key) {
+                            Ok(query) => query,
+                            Err(message) => {
+                                res.set_status_code(400);
+                                res.set_body(message.into_bytes(), MimeType::TextPlain);
+                                return;
+                            }
+                        };
+
+                        let (objects, cursor) = storage.search(query).await;
+                        if let Some(cursor) = cursor {
+                            res.set_header("x-next-cursor", &cursor);
+                        }
+                        objects
+                    }
+                    None => storage.list_objects().await,
+                };
                 // Use serde to convert the vector to a JSON string.
                 let json = serde_json::to_string(&objects).unwrap();
 
                 res.set_status_code(200);
                 res.set_body(json.as_bytes().to_vec(), MimeType::ApplicationJson);
+                compression::compress_body(req.headers.get("accept-encoding"), res);
             }
             _ => {}
         }
 
-        auth_context.to_owned().update_last_used();
-        match auth_context.save().await {
-            Ok(_) => {}
-            Err(_) => {}
+        let _ = sessions::get().touch(&auth_context.access_key);
+    }
+
+    // Lists, creates, updates and deletes buckets under /.buckets, callers
+    // only see the buckets they manage
+    async fn handle_buckets(req: &Request, res: &mut Response, auth_context: &AuthContext, name: Option<&str>) {
+        res.mark_required_authentication();
+
+        let store = buckets::get();
+        let result = match (&req.method, name) {
+            (Method::LIST | Method::GET, None) => {
+                let json = serde_json::to_string(&store.list(auth_context)).unwrap();
+                res.set_status_code(200);
+                res.set_body(json.into_bytes(), MimeType::ApplicationJson);
+                return;
+            }
+            (Method::GET, Some(name)) => store
+                .get(name)
+                .filter(|bucket| bucket.is_managed_by(auth_context))
+                .ok_or(BucketError::NotFound),
+            (Method::PUT | Method::DELETE, Some(_)) if auth_context.access_level < AuthLevel::ReadWrite => {
+                res.set_status_code(403);
+                res.set_body("Forbidden".as_bytes().to_vec(), MimeType::TextPlain);
+                return;
+            }
+            (Method::PUT, Some(name)) => {
+                // An empty body creates a bucket with the default settings
+                let settings = match req.raw_body.is_empty() {
+                    true => Ok(BucketSettings::default()),
+                    false => serde_json::from_slice::<BucketSettings>(&req.raw_body),
+                };
+
+                match settings {
+                    Ok(settings) => store.put(name, settings, auth_context).await,
+                    Err(e) => {
+                        res.set_status_code(400);
+                        res.set_body(format!("Invalid bucket settings - {}", e).into_bytes(), MimeType::TextPlain);
+                        return;
+                    }
+                }
+            }
+            (Method::DELETE, Some(name)) => match store.remove(name, auth_context).await {
+                Ok(()) => {
+                    res.set_status_code(200);
+                    return;
+                }
+                Err(error) => Err(error),
+            },
+            _ => Err(BucketError::NotFound),
+        };
+
+        match result {
+            Ok(bucket) => {
+                let json = serde_json::to_string(&bucket).unwrap();
+                res.set_status_code(200);
+                res.set_body(json.into_bytes(), MimeType::ApplicationJson);
+            }
+            Err(error) => {
+                res.set_status_code(error.status_code());
+                res.set_body(error.message().as_bytes().to_vec(), MimeType::TextPlain);
+            }
+        }
+    }
+
+    // Returns the key pair S3 clients sign requests with, the access key and
+    // secret key of the caller's session
+    fn handle_credentials(req: &Request, res: &mut Response, auth_context: &AuthContext) {
+        res.mark_required_authentication();
+
+        if req.method != Method::GET && req.method != Method::POST {
+            res.set_status_code(405);
+            res.set_body("Method Not Allowed".as_bytes().to_vec(), MimeType::TextPlain);
+            return;
+        }
+
+        // Reads without a session are served with a throwaway context
+        if sessions::get().get(&auth_context.access_key).is_none() {
+            res.set_status_code(403);
+            res.set_body("Forbidden".as_bytes().to_vec(), MimeType::TextPlain);
+            return;
+        }
+
+        let json = serde_json::json!({
+            "access_key_id": auth_context.access_key,
+            "secret_access_key": auth_context.secret_key(),
+        });
+
+        res.set_status_code(200);
+        res.set_body(json.to_string().into_bytes(), MimeType::ApplicationJson);
+        res.set_header("cache-control", "no-store");
+    }
+
+    // Lists, restores and purges deleted objects under /.trash. Everyone sees
+    // the objects they could have deleted, only admins purge them early
+    async fn handle_trash(
+        req: &Request,
+        res: &mut Response,
+        storage: &Storage,
+        auth_context: &AuthContext,
+        id: Option<&str>,
+    ) {
+        res.mark_required_authentication();
+
+        if auth_context.access_level < AuthLevel::ReadWrite {
+            res.set_status_code(403);
+            res.set_body("Forbidden".as_bytes().to_vec(), MimeType::TextPlain);
+            return;
+        }
+
+        let trashed = match id {
+            Some(id) => match trash::find(id).await {
+                Some(trashed) if storage.is_object_writable(&trashed.metadata).await => Some(trashed),
+                _ => {
+                    res.set_status_code(404);
+                    res.set_body("Not Found".as_bytes().to_vec(), MimeType::TextPlain);
+                    return;
+                }
+            },
+            None => None,
         };
 
+        match (&req.method, trashed) {
+            (Method::LIST | Method::GET, None) => {
+                let json = serde_json::to_string(&storage.trashed_objects().await).unwrap();
+                res.set_status_code(200);
+                res.set_body(json.into_bytes(), MimeType::ApplicationJson);
+                compression::compress_body(req.headers.get("accept-encoding"), res);
+            }
+            (Method::POST, Some(trashed)) => match storage.restore(trashed).await {
+                Ok(metadata) => {
+                    let json = serde_json::to_string(&metadata).unwrap();
+                    res.set_status_code(200);
+                    res.set_body(json.into_bytes(), MimeType::ApplicationJson);
+                }
+                Err(RestoreError::KeyInUse) => {
+                    res.set_status_code(409);
+                    res.set_body(
+                        "An object already exists under the key".as_bytes().to_vec(),
+                        MimeType::TextPlain,
+                    );
+                }
+                Err(RestoreError::Refused(error)) => {
+                    res.set_status_code(error.status_code());
+                    res.set_body(error.message().as_bytes().to_vec(), MimeType::TextPlain);
+                }
+                Err(RestoreError::Failed) => {
+                    res.set_status_code(500);
+                    res.set_body("Failed to restore".as_bytes().to_vec(), MimeType::TextPlain);
+                }
+            },
+            (Method::DELETE, trashed) => {
+                if auth_context.access_level < AuthLevel::Admin {
+                    res.set_status_code(403);
+                    res.set_body("Forbidden".as_bytes().to_vec(), MimeType::TextPlain);
+                    return;
+                }
+
+                // Without an id everything the admin can see is purged
+                let targets = match trashed {
+                    Some(trashed) => vec![trashed],
+                    None => storage.trashed_objects().await,
+                };
+
+                let mut purged = 0;
+                for trashed in &targets {
+                    match trash::purge(trashed).await {
+                        Ok(_) => purged += 1,
+                        Err(e) => error!("{}: failed to purge {} - {}", trashed.id, trashed.metadata.key, e),
+                    }
+                }
+                info!("{} purged {} object(s) from the trash", auth_context.access_key, purged);
+
+                res.set_status_code(if purged == targets.len() { 200 } else { 500 });
+                res.set_body(format!("{{\"purged\":{}}}", purged).into_bytes(), MimeType::ApplicationJson);
+            }
+            _ => {
+                res.set_status_code(404);
+                res.set_body("Not Found".as_bytes().to_vec(), MimeType::TextPlain);
+            }
+        }
     }
 
-    async fn handle_http_request(parser: Parser) -> (Response, Option<Object>) {
+    async fn handle_http_request(parser: Parser, client: &ClientInfo) -> (Response, Option<Transfer>) {
         let mut res = Response::new(200);
-        let mut obj: Option<Object> = None;
+        let mut obj: Option<Transfer> = None;
 
         if parser.is_invalid() {
             res.set_status_code(400);
@@ -309,36 +952,58 @@ impl Conn {
         }
 
         let request: Request = parser.consume_request().unwrap();
+
+        debug!(
+            "{} via {}: {} {}",
+            client.address,
+            client.peer,
+            request.method.to_str(),
+            request.uri
+        );
+
+        // Health checks are answered before any session handling so probes
+        // never create authentication contexts
+        if let Some(health_response) = Self::handle_health_request(&request).await {
+            return (health_response, obj);
+        }
+
+        // Preflights carry no credentials, answer them before any session
+        // handling or rate limiting
+        if request.method == Method::OPTIONS {
+            let cors = buckets::get().cors_for(&request);
+            return (cors::preflight(cors.as_ref().unwrap_or(&config::get().cors), &request), obj);
+        }
+
+        let rate_limits = &config::get().rate_limits;
+        let class = s3::request_class(&request);
+
+        if let Some(limited) =
+            Self::check_rate_limit(RateKey::address(client.address), class, &rate_limits.per_ip)
+        {
+            return (limited, obj);
+        }
+
         let cookies: std::collections::HashMap<String, String> =
             request.cookies.clone().unwrap_or_default();
 
         let mut auth_context: Option<AuthContext> = None;
         let token_str = cookies.get("authorization");
 
-        match token_str {
-            Some(token) => match AuthContext::id_from_jwt(token) {
+        if let Some(token) = token_str {
+            match AuthContext::id_from_jwt(token) {
                 (Some(id), Some(level)) => {
-                    auth_context = match AuthContext::load(&id).await {
-                        Ok(mut context) => {
-                            // Only update the access level if it's lower than the current one never downgrade
-                            if level > context.access_level {
-                                context.access_level = level;
-                                match context.save().await {
-                                    Ok(_) => {}
-                                    Err(_) => {}
-                                }
-                            }
-
-                            Some(context)
+                    auth_context = sessions::get().get(&id).map(|mut context| {
+                        // Only update the access level if it's lower than the current one never downgrade
+                        if level > context.access_level {
+                            context.access_level = level;
+                            let _ = sessions::get().save(&context);
                         }
-                        Err(_) => None,
-                    };
+
+                        context
+                    });
                 }
                 (Some(id), None) => {
-                    auth_context = match AuthContext::load(&id).await {
-                        Ok(context) => Some(context),
-                        Err(_) => None,
-                    };
+                    auth_context = sessions::get().get(&id);
                 }
                 _ => {
                     res.set_status_code(400);
@@ -349,76 +1014,425 @@ impl Conn {
 
                     return (res, obj);
                 }
-            },
-            None => {}
+            }
         }
 
-        if auth_context.is_none() {
-            auth_context = Some(AuthContext::random());
+        let context = match auth_context {
+            Some(context) => {
+                if let Some(limited) = Self::check_rate_limit(
+                    RateKey::AccessKey(context.access_key.clone()),
+                    class,
+                    rate_limits.per_key.for_level(&context.access_level),
+                ) {
+                    return (limited, obj);
+                }
+
+                context
+            }
+            // Only writes need an identity, everything else is served with a
+            // throwaway public context so reads never create sessions
+            None if class != RequestClass::Write => AuthContext::random(),
+            None => {
+                if let Some(limited) = Self::check_rate_limit(
+                    RateKey::address(client.address),
+                    RequestClass::Session,
+                    &rate_limits.per_ip,
+                ) {
+                    return (limited, obj);
+                }
+
+                match sessions::get().create(config::get().sessions.max_anonymous) {
+                    Ok(Some(context)) => {
+                        res.set_cookie("authorization", context.as_jwt().as_str(), true);
+                        context
+                    }
+                    Ok(None) => {
+                        warn!("{}: anonymous session limit reached", client.address);
+                        res.set_status_code(503);
+                        res.set_header("retry-after", "60");
+                        res.set_body(
+                            "Too many sessions, try again later".as_bytes().to_vec(),
+                            MimeType::TextPlain,
+                        );
 
-            let context = auth_context.unwrap();
+                        return (res, obj);
+                    }
+                    Err(_) => {
+                        res.set_status_code(503);
+                        res.set_body(
+                            "Internal Server Error".as_bytes().to_vec(),
+                            MimeType::TextPlain,
+                        );
 
-            match context.save().await {
-                Ok(_) => {
-                    res.set_cookie("authorization", context.to_owned().as_jwt().as_str(), true);
+                        return (res, obj);
+                    }
                 }
-                Err(_) => {
-                    res.set_status_code(503);
-                    res.set_body(
-                        "Internal Server Error".as_bytes().to_vec(),
-                        MimeType::TextPlain,
-                    );
+            }
+        };
 
-                    return (res, obj);
+        Self::handle_storage(request, &mut res, &mut obj, context).await;
+
+        (res, obj)
+    }
+
+    // Requests to the S3 listener are authenticated with SigV4 signatures
+    // made with the secret key of a session instead of the session cookie
+    async fn handle_s3_request(parser: Parser, client: &ClientInfo) -> (Response, Option<Transfer>) {
+        let Some(request) = parser.consume_request() else {
+            let mut res = Response::new(400);
+            res.set_body("Invalid HTTP Request".as_bytes().to_vec(), MimeType::TextPlain);
+            return (res, None);
+        };
+
+        debug!(
+            "{} via {}: S3 {} {}",
+            client.address,
+            client.peer,
+            request.method.to_str(),
+            request.uri
+        );
+
+        let rate_limits = &config::get().rate_limits;
+        let class = RequestClass::of(&request.method);
+
+        if let Some(limited) =
+            Self::check_rate_limit(RateKey::address(client.address), class, &rate_limits.per_ip)
+        {
+            return (limited, None);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let signed = match sigv4::authenticate(&request, now) {
+            Ok(signed) => signed,
+            Err(error) => {
+                debug!("{}: S3 authentication failed - {}", client.address, error.code());
+                return (s3::error_response(error.into(), &request, &s3::request_id()), None);
+            }
+        };
+
+        // Anonymous requests read public objects only, like reads without a session
+        let auth_context = match &signed {
+            Some(signed) => {
+                let context = signed.auth_context.clone();
+                if let Some(limited) = Self::check_rate_limit(
+                    RateKey::AccessKey(context.access_key.clone()),
+                    class,
+                    rate_limits.per_key.for_level(&context.access_level),
+                ) {
+                    return (limited, None);
                 }
-            };
-            Self::handle_storage(request, &mut res, &mut obj, context).await;
-        } else {
-            Self::handle_storage(request, &mut res, &mut obj, auth_context.unwrap()).await;
+
+                context
+            }
+            None => AuthContext::random(),
+        };
+
+        let access_key = signed.as_ref().map(|signed| signed.auth_context.access_key.clone());
+        let (res, obj) = s3::handle(request, auth_context, signed).await;
+
+        if let Some(access_key) = access_key {
+            let _ = sessions::get().touch(&access_key);
         }
 
         (res, obj)
     }
 
+    // Takes a token from the `class` budget of `key`, answering with 429 once
+    // the budget is exhausted
+    fn check_rate_limit(key: RateKey, class: RequestClass, limits: &ClassLimits) -> Option<Response> {
+        if !config::get().rate_limits.enabled {
+            return None;
+        }
+
+        let retry_after = RATE_LIMITER.check(key, class, class.limit(limits)).err()?;
+        METRICS.request_rate_limited(class.to_str());
+
+        let mut res = Response::new(429);
+        res.set_header("retry-after", &(retry_after.as_secs_f64().ceil() as u64).max(1).to_string());
+        res.set_body("Too Many Requests".as_bytes().to_vec(), MimeType::TextPlain);
+
+        Some(res)
+    }
+
+    async fn handle_health_request(request: &Request) -> Option<Response> {
+        if request.method != Method::GET && request.method != Method::HEAD {
+            return None;
+        }
+
+        let mut res = Response::new(200);
+
+        match request.uri.as_str() {
+            "/healthz" => {
+                res.set_body("OK".as_bytes().to_vec(), MimeType::TextPlain);
+            }
+            "/readyz" => {
+                let failures = health::check_readiness().await;
+
+                if failures.is_empty() {
+                    res.set_body("OK".as_bytes().to_vec(), MimeType::TextPlain);
+                } else {
+                    res.set_status_code(503);
+                    res.set_body(failures.join("\n").into_bytes(), MimeType::TextPlain);
+                }
+            }
+            _ => return None,
+        }
+
+        if request.method == Method::HEAD {
+            res.body.clear();
+        }
+
+        Some(res)
+    }
+
+    async fn handle_admin_request(parser: Parser) -> Response {
+        let mut res = Response::new(200);
+
+        let Some(request) = parser.consume_request() else {
+            res.set_status_code(400);
+            res.set_body(
+                "Invalid HTTP Request".as_bytes().to_vec(),
+                MimeType::TextPlain,
+            );
+
+            return res;
+        };
+
+        if let Some(health_response) = Self::handle_health_request(&request).await {
+            return health_response;
+        }
+
+        match (&request.method, request.uri.as_str()) {
+            (Method::GET, "/metrics") => {
+                let stats = StorageStats::collect().await;
+
+                res.set_body(METRICS.render(&stats).into_bytes(), MimeType::TextPlain);
+                res.set_header("content-type", "text/plain; version=0.0.4; charset=utf-8");
+            }
+            (Method::GET, "/maintenance") => {
+                let statuses: BTreeMap<_, _> = maintenance::statuses().into_iter().collect();
+                let json = serde_json::to_string(&statuses).unwrap();
+
+                res.set_body(json.into_bytes(), MimeType::ApplicationJson);
+            }
+            _ => {
+                res.set_status_code(404);
+                res.set_body("Not Found".as_bytes().to_vec(), MimeType::TextPlain);
+            }
+        }
+
+        res
+    }
+
     async fn run(&mut self) {
+        let started = Instant::now();
         let mut buffer = [0; 1024];
-        let (mut reader, mut writer) = self.socket.split();
 
-        let mut parser: Parser = Parser::new();
+        let mut parser: Parser = Parser::new().with_max_field_size(self.limits.max_header_size);
+        let mut received_any = false;
+        let mut header_bytes = 0;
+        let header_deadline = tokio::time::Instant::now() + self.limits.header_timeout;
+
+        // Keep scrapes of the admin listener out of the CDN traffic metrics
+        let record_metrics = self.kind != ListenerKind::Admin;
+        let mut sent_continue = false;
+
+        if record_metrics {
+            METRICS.connection_opened();
+        }
+
+        // Set when the request is refused before it has been read completely
+        let mut refused: Option<(u16, &str)> = None;
+        // Set when the client is gone, nobody is left to answer
+        let mut aborted = false;
 
         loop {
-            match reader.read(&mut buffer).await {
-                Err(error) => {
-                    println!("{}: something bad happened - {}", self.address, error);
+            // Each phase of the request gets its own budget so a client that
+            // trickles bytes can't hold the connection open forever
+            let read_timeout = if !received_any {
+                self.limits.idle_timeout
+            } else if !parser.headers_complete() {
+                header_deadline.saturating_duration_since(tokio::time::Instant::now())
+            } else {
+                self.limits.body_timeout
+            };
+
+            match timeout(read_timeout, self.socket.read(&mut buffer)).await {
+                Err(_) => {
+                    debug!("{}: timed out reading request", self.address);
+                    refused = Some((408, "Request Timeout"));
+                    break;
                 }
 
-                Ok(count) if count == 0 => {
-                    break println!("{0}: end of stream", self.address);
+                Ok(Err(error)) => {
+                    warn!("{}: something bad happened - {}", self.address, error);
+                    aborted = true;
+                    break;
                 }
 
-                Ok(count) => {
+                Ok(Ok(0)) => {
+                    debug!("{0}: end of stream", self.address);
+                    if !parser.is_done() {
+                        refused = Some((400, "Incomplete request"));
+                    }
+                    break;
+                }
+
+                Ok(Ok(count)) => {
+                    if record_metrics {
+                        METRICS.add_bytes_received(count);
+                    }
+                    received_any = true;
+                    if !parser.headers_complete() {
+                        header_bytes += count;
+                    }
                     parser.update(&buffer[..count]);
+
+                    // Chunk size lines and trailers are held on to like headers
+                    if parser.is_too_large() || (!parser.headers_complete() && header_bytes > self.limits.max_header_size) {
+                        debug!("{}: request headers too large", self.address);
+                        refused = Some((431, "Request Header Fields Too Large"));
+                        break;
+                    }
+
+                    let body_size = parser.request().map_or(0, |request| {
+                        request.content_length.unwrap_or(0).max(request.raw_body.len())
+                    });
+                    if body_size > self.limits.max_body_size {
+                        debug!("{}: request body too large", self.address);
+                        refused = Some((413, "Payload Too Large"));
+                        break;
+                    }
+
                     if parser.is_done() {
                         break;
                     }
+
+                    // Clients that ask wait for this before sending the body
+                    let expects_continue = parser.request().is_some_and(|request| {
+                        request.headers.get("expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+                    });
+                    if expects_continue && parser.headers_complete() && !sent_continue {
+                        sent_continue = true;
+                        let continue_line = b"HTTP/1.1 100 Continue\r\n\r\n";
+                        if let Err(error) = write_with_timeout(&mut self.socket, continue_line, self.limits.write_timeout).await {
+                            debug!("{}: failed to write 100 Continue - {}", self.address, error);
+                            aborted = true;
+                            break;
+                        }
+                    }
                 }
             }
         }
 
-        let (response, object) = Self::handle_http_request(parser).await;
+        // A partial request must never reach the handlers, a truncated
+        // upload would otherwise be stored as if it were complete
+        if aborted {
+            self.close().await;
+            if record_metrics {
+                METRICS.connection_closed();
+            }
+            return;
+        }
 
-        writer.write_all(&response.as_bytes()).await.unwrap();
-        writer.flush().await.unwrap();
+        let method = parser
+            .request()
+            .map(|request| request.method.to_str())
+            .unwrap_or("INVALID");
 
-        if let Some(obj) = object {
-            if let Ok(mut iterator) = obj.stream_file().await {
-                while let Some(Ok(chunk)) = iterator.next().await {
-                    writer.write_all(&chunk).await.unwrap();
-                    writer.flush().await.unwrap();
-                }
+        // Only resolved once the headers are known, proxies may forward the
+        // original client address in them
+        let client = match parser.request() {
+            Some(request) => ClientInfo::resolve(self.address, self.tls, request, &self.trusted),
+            None => ClientInfo::direct(self.address, self.tls),
+        };
+
+        let origin = parser
+            .request()
+            .and_then(|request| request.headers.get("origin").cloned());
+        let bucket_cors = match self.kind {
+            ListenerKind::Storage => parser.request().and_then(|request| buckets::get().cors_for(request)),
+            ListenerKind::Admin | ListenerKind::S3 => None,
+        };
+
+        let (mut response, object) = if let Some((status_code, message)) = refused {
+            let mut res = Response::new(status_code);
+            res.set_body(message.as_bytes().to_vec(), MimeType::TextPlain);
+            (res, None)
+        } else {
+            match self.kind {
+                ListenerKind::Storage => Self::handle_http_request(parser, &client).await,
+                ListenerKind::Admin => (Self::handle_admin_request(parser).await, None),
+                ListenerKind::S3 => Self::handle_s3_request(parser, &client).await,
+            }
+        };
+
+        if self.kind == ListenerKind::Storage {
+            cors::apply(bucket_cors.as_ref().unwrap_or(&config::get().cors), origin.as_deref(), &mut response);
+        }
+
+        let response_bytes = response.as_bytes();
+        let mut bytes_sent = response_bytes.len();
+        let write_timeout = self.limits.write_timeout;
+
+        if let Err(error) = write_with_timeout(&mut self.socket, &response_bytes, write_timeout).await {
+            debug!("{}: failed to write response - {}", self.address, error);
+        } else if let Some(transfer) = object {
+            match transfer.send(&mut self.socket, write_timeout).await {
+                Ok(sent) => bytes_sent += sent,
+                Err(error) => debug!("{}: failed to write response - {}", self.address, error),
             }
         }
 
+        let _ = timeout(write_timeout, self.socket.flush()).await;
+
         self.close().await;
+
+        if record_metrics {
+            METRICS.add_bytes_sent(bytes_sent);
+            METRICS.record_request(method, response.status_code, started.elapsed());
+            METRICS.connection_closed();
+        }
+    }
+}
+
+// The rest of `path` when it is `base` or below it, None for unrelated paths
+fn sub_path<'a>(path: &'a str, base: &str) -> Option<Option<&'a str>> {
+    let rest = path.strip_prefix(base)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+
+    Some(Some(rest.trim_start_matches('/')).filter(|rest| !rest.is_empty()))
+}
+
+// The blob of an object can vanish after its metadata was read, e.g. when it
+// is deleted or moved to the trash concurrently
+fn set_blob_missing(res: &mut Response) {
+    // Drop the object headers set so far, a new session still needs its cookie
+    res.headers.retain(|name, _| name == "set-cookie");
+    res.set_status_code(404);
+    res.set_body("Not found / Forbidden".as_bytes().to_vec(), MimeType::TextPlain);
+}
+
+// Confirms which customer key a request was served with
+fn set_customer_key_headers(res: &mut Response, customer_key: Option<&CustomerKey>) {
+    if let Some(customer_key) = customer_key {
+        res.set_header("x-encryption-algorithm", customer_key.algorithm.to_str());
+        res.set_header("x-encryption-key-md5", &customer_key.key_md5);
+    }
+}
+
+pub async fn write_with_timeout<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+    write_timeout: Duration,
+) -> std::io::Result<()> {
+    match timeout(write_timeout, writer.write_all(data)).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "write timed out",
+        )),
     }
 }
@@ -0,0 +1,389 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::authentication::{AuthContext, AuthLevel};
+use crate::storable::StorableBase;
+
+const LOG_FILE: &str = "sessions.log";
+// The log is rewritten once it holds this many more records than sessions
+const COMPACTION_SLACK: usize = 1024;
+// last_used is only persisted again once it is this many seconds stale
+const TOUCH_INTERVAL: u64 = 60;
+
+static SESSIONS: OnceLock<SessionStore> = OnceLock::new();
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Put { context: AuthContext },
+    Delete { access_key: String },
+}
+
+// Work for the log writer, handled in the order it was queued
+enum Command {
+    Append(String),
+    // Rewrites the log with these sessions, records queued later follow them
+    Compact(Vec<AuthContext>),
+    Flush(Sender<()>),
+}
+
+struct State {
+    sessions: HashMap<String, AuthContext>,
+    // Sessions ordered by last use, so expiry only visits stale entries
+    by_last_used: BTreeSet<(u64, String)>,
+    anonymous: usize,
+    records: usize,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Put { context } => {
+                self.remove(&context.access_key);
+
+                if context.access_level == AuthLevel::Public {
+                    self.anonymous += 1;
+                }
+                self.by_last_used
+                    .insert((context.last_used, context.access_key.clone()));
+                self.sessions.insert(context.access_key.clone(), context);
+            }
+            Record::Delete { access_key } => self.remove(&access_key),
+        }
+    }
+
+    fn remove(&mut self, access_key: &str) {
+        if let Some(previous) = self.sessions.remove(access_key) {
+            if previous.access_level == AuthLevel::Public {
+                self.anonymous -= 1;
+            }
+            self.by_last_used
+                .remove(&(previous.last_used, previous.access_key));
+        }
+    }
+}
+
+// Sessions kept in memory and persisted to an append-only log that is
+// replayed on startup and compacted once it grows stale. The log is written
+// by a dedicated thread so requests never wait on the disk while holding
+// the lock every session lookup needs
+pub struct SessionStore {
+    path: PathBuf,
+    state: Mutex<State>,
+    writer: Sender<Command>,
+}
+
+impl SessionStore {
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE);
+        let legacy = !path.exists();
+
+        let mut state = State {
+            sessions: HashMap::new(),
+            by_last_used: BTreeSet::new(),
+            anonymous: 0,
+            records: 0,
+        };
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        for (number, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+            let line = line?;
+            // A crash while appending leaves at most the last line truncated
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) => {
+                    state.apply(record);
+                    state.records += 1;
+                }
+                Err(e) => warn!("{}:{}: skipping invalid record - {}", path.display(), number + 1, e),
+            }
+        }
+
+        let (writer, commands) = mpsc::channel();
+        let log_path = path.clone();
+        thread::Builder::new()
+            .name("sessions-log".to_string())
+            .spawn(move || write_log(log_path, log, commands))?;
+
+        let store = Self {
+            path,
+            state: Mutex::new(state),
+            writer,
+        };
+
+        if legacy {
+            store.import_json_files(dir)?;
+        }
+
+        Ok(store)
+    }
+
+    // Moves sessions stored as one JSON file each into the log
+    fn import_json_files(&self, dir: &Path) -> std::io::Result<()> {
+        let mut imported = Vec::new();
+        let mut state = self.state.lock().unwrap();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            match fs::read_to_string(&path)
+                .ok()
+                .and_then(|contents| serde_json::from_str::<AuthContext>(&contents).ok())
+            {
+                Some(context) => {
+                    state.apply(Record::Put { context });
+                    imported.push(path);
+                }
+                None => warn!("{}: not a session, leaving it in place", path.display()),
+            }
+        }
+
+        if imported.is_empty() {
+            return Ok(());
+        }
+
+        self.compact(&mut state)?;
+        drop(state);
+
+        // The files are only removed once the log holding them is on disk
+        self.flush()?;
+        for path in &imported {
+            fs::remove_file(path)?;
+        }
+
+        info!("Imported {} session(s) into {}", imported.len(), self.path.display());
+        Ok(())
+    }
+
+    fn send(&self, command: Command) -> std::io::Result<()> {
+        self.writer
+            .send(command)
+            .map_err(|_| std::io::Error::other("session log writer stopped"))
+    }
+
+    fn append(&self, state: &mut State, record: Record) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(&record).map_err(std::io::Error::other)?;
+        line.push('\n');
+
+        // Queued while holding the lock so the log sees the same order as
+        // the in-memory state
+        self.send(Command::Append(line))?;
+        state.apply(record);
+        state.records += 1;
+
+        if state.records > state.sessions.len() * 2 + COMPACTION_SLACK {
+            self.compact(state)?;
+        }
+
+        Ok(())
+    }
+
+    // Queues a rewrite of the log with one record per live session
+    fn compact(&self, state: &mut State) -> std::io::Result<()> {
+        self.send(Command::Compact(state.sessions.values().cloned().collect()))?;
+        state.records = state.sessions.len();
+
+        Ok(())
+    }
+
+    // Blocks until everything queued so far has been written to the log
+    pub fn flush(&self) -> std::io::Result<()> {
+        let (done, wait) = mpsc::channel();
+        self.send(Command::Flush(done))?;
+
+        wait.recv()
+            .map_err(|_| std::io::Error::other("session log writer stopped"))
+    }
+
+    pub fn get(&self, access_key: &str) -> Option<AuthContext> {
+        self.state.lock().unwrap().sessions.get(access_key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+
+    // Creates and persists a new anonymous session, or returns None when
+    // `max_anonymous` of them already exist
+    pub fn create(&self, max_anonymous: usize) -> std::io::Result<Option<AuthContext>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.anonymous >= max_anonymous {
+            return Ok(None);
+        }
+
+        let context = AuthContext::random();
+        self.append(
+            &mut state,
+            Record::Put {
+                context: context.clone(),
+            },
+        )?;
+
+        Ok(Some(context))
+    }
+
+    pub fn save(&self, context: &AuthContext) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.append(
+            &mut state,
+            Record::Put {
+                context: context.clone(),
+            },
+        )
+    }
+
+    pub fn remove(&self, access_key: &str) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.sessions.contains_key(access_key) {
+            return Ok(());
+        }
+
+        self.append(
+            &mut state,
+            Record::Delete {
+                access_key: access_key.to_string(),
+            },
+        )
+    }
+
+    // Records that a session was used, unknown sessions are ignored
+    pub fn touch(&self, access_key: &str) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(mut context) = state.sessions.get(access_key).cloned() else {
+            return Ok(());
+        };
+
+        if now().saturating_sub(context.last_used) < TOUCH_INTERVAL {
+            return Ok(());
+        }
+
+        context.update_last_used();
+        self.append(&mut state, Record::Put { context })
+    }
+
+    // Non admin sessions unused for longer than `ttl` seconds, least
+    // recently used first
+    pub fn expired(&self, ttl: u64) -> Vec<AuthContext> {
+        let state = self.state.lock().unwrap();
+        let cutoff = now().saturating_sub(ttl);
+
+        state
+            .by_last_used
+            .iter()
+            .take_while(|(last_used, _)| *last_used < cutoff)
+            .filter_map(|(_, access_key)| state.sessions.get(access_key))
+            .filter(|context| context.access_level < AuthLevel::Admin)
+            .cloned()
+            .collect()
+    }
+}
+
+fn write_log(path: PathBuf, mut log: File, commands: Receiver<Command>) {
+    for command in commands {
+        match command {
+            Command::Append(line) => {
+                if let Err(e) = log.write_all(line.as_bytes()) {
+                    warn!("{}: failed to append session record - {}", path.display(), e);
+                }
+            }
+            Command::Compact(sessions) => match rewrite_log(&path, &sessions) {
+                Ok(file) => log = file,
+                Err(e) => warn!("{}: failed to compact - {}", path.display(), e),
+            },
+            Command::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+// Rewrites the log with one record per session and returns it reopened for
+// appending
+fn rewrite_log(path: &Path, sessions: &[AuthContext]) -> std::io::Result<File> {
+    let temporary = path.with_extension("log.tmp");
+    let mut file = File::create(&temporary)?;
+
+    for context in sessions {
+        let record = Record::Put {
+            context: context.clone(),
+        };
+        let mut line = serde_json::to_string(&record).map_err(std::io::Error::other)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+    }
+
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+
+    OpenOptions::new().append(true).open(path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Loads the session store, must be called once before serving requests
+pub fn init() -> std::io::Result<()> {
+    let store = SessionStore::open(&AuthContext::base_dir())?;
+    let _ = SESSIONS.set(store);
+    Ok(())
+}
+
+pub fn get() -> &'static SessionStore {
+    SESSIONS.get().expect("session store is initialized at startup")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_replay_cap_and_expiry() {
+        let dir = std::env::temp_dir().join(format!("a-bucket-sessions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = SessionStore::open(&dir).unwrap();
+        let first = store.create(2).unwrap().unwrap();
+        let mut second = store.create(2).unwrap().unwrap();
+        assert!(store.create(2).unwrap().is_none());
+
+        second.access_level = AuthLevel::ReadWrite;
+        second.last_used = 0;
+        store.save(&second).unwrap();
+        store.remove(&first.access_key).unwrap();
+
+        store.flush().unwrap();
+        drop(store);
+        let store = SessionStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.get(&first.access_key).is_none());
+        assert_eq!(store.get(&second.access_key).unwrap().access_level, AuthLevel::ReadWrite);
+
+        let expired = store.expired(60);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].access_key, second.access_key);
+
+        store.touch(&second.access_key).unwrap();
+        assert!(store.expired(60).is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,22 @@
+use log::info;
+use tokio::signal;
+
+// Resolves once SIGTERM or SIGINT has been received
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+            _ = signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+        info!("Received SIGINT, shutting down");
+    }
+}
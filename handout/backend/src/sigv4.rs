@@ -0,0 +1,566 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use a_http_parser::request::Request;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::authentication::AuthContext;
+use crate::search;
+use crate::sessions;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// AWS Signature Version 4, the only scheme S3 clients are expected to sign with
+pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+// Values of x-amz-content-sha256 besides the hex digest of the body
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const STREAMING_PAYLOAD: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+const STREAMING_PAYLOAD_TRAILER: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER";
+const STREAMING_UNSIGNED_TRAILER: &str = "STREAMING-UNSIGNED-PAYLOAD-TRAILER";
+
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+// Requests signed further from the server time than this are refused
+const MAX_SKEW_SECS: u64 = 15 * 60;
+// Longest a presigned URL can be valid for
+const MAX_EXPIRES_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    // The Authorization header or the presigned query is incomplete
+    Malformed,
+    UnknownAccessKey,
+    SignatureMismatch,
+    TimeSkewed,
+    // A presigned URL past its expiry
+    Expired,
+    // The body does not match x-amz-content-sha256
+    ContentMismatch,
+    InvalidChunk,
+}
+
+impl AuthError {
+    pub fn status_code(self) -> u16 {
+        match self {
+            AuthError::Malformed | AuthError::ContentMismatch | AuthError::InvalidChunk => 400,
+            _ => 403,
+        }
+    }
+
+    // The S3 error code clients expect
+    pub fn code(self) -> &'static str {
+        match self {
+            AuthError::Malformed => "AuthorizationHeaderMalformed",
+            AuthError::UnknownAccessKey => "InvalidAccessKeyId",
+            AuthError::SignatureMismatch => "SignatureDoesNotMatch",
+            AuthError::TimeSkewed => "RequestTimeTooSkewed",
+            AuthError::Expired => "AccessDenied",
+            AuthError::ContentMismatch => "XAmzContentSHA256Mismatch",
+            AuthError::InvalidChunk => "IncompleteBody",
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            AuthError::Malformed => "The request signature is malformed",
+            AuthError::UnknownAccessKey => "The access key does not exist",
+            AuthError::SignatureMismatch => "The request signature does not match",
+            AuthError::TimeSkewed => "The request time is too far from the server time",
+            AuthError::Expired => "Request has expired",
+            AuthError::ContentMismatch => "The body does not match x-amz-content-sha256",
+            AuthError::InvalidChunk => "The aws-chunked body is malformed",
+        }
+    }
+}
+
+// A request whose signature checked out. The signing key and the seed
+// signature are needed again to check the chunks of a streamed body
+pub struct Signed {
+    pub auth_context: AuthContext,
+    signing_key: Vec<u8>,
+    timestamp: String,
+    scope: String,
+    signature: String,
+    content_sha256: String,
+}
+
+// The parts of a signature, from the Authorization header or the query
+struct Signature<'a> {
+    credential: &'a str,
+    signed_headers: &'a str,
+    signature: &'a str,
+    timestamp: &'a str,
+    content_sha256: &'a str,
+    // Presigned URLs carry the signature in the query string
+    presigned: bool,
+}
+
+// Checks the signature of a request, None for anonymous requests. The
+// access key is the one of a session and its secret key signs
+pub fn authenticate(req: &Request, now: u64) -> Result<Option<Signed>, AuthError> {
+    let (path, query) = search::split_uri(&req.uri);
+    let params = query_params(query.unwrap_or_default());
+
+    let signature = match req.headers.get("authorization") {
+        Some(authorization) => from_header(req, authorization)?,
+        None if params.iter().any(|(name, _)| name == "X-Amz-Algorithm") => from_query(req, &params, now)?,
+        None => return Ok(None),
+    };
+
+    // Without host a request for one bucket domain could be replayed against
+    // another, without x-amz-date outside the allowed skew. Presigned URLs
+    // carry their date in the signed query
+    let signed_headers: Vec<&str> = signature.signed_headers.split(';').collect();
+    if !signed_headers.contains(&"host") || (!signature.presigned && !signed_headers.contains(&"x-amz-date")) {
+        return Err(AuthError::Malformed);
+    }
+
+    let (access_key, scope) = parse_credential(signature.credential).ok_or(AuthError::Malformed)?;
+    let date = scope.split('/').next().unwrap_or_default();
+    let signed_at = parse_timestamp(signature.timestamp).ok_or(AuthError::Malformed)?;
+    if !signature.timestamp.starts_with(date) {
+        return Err(AuthError::Malformed);
+    }
+
+    // Presigned URLs were checked against their own expiry
+    if !signature.presigned && signed_at.abs_diff(now) > MAX_SKEW_SECS {
+        return Err(AuthError::TimeSkewed);
+    }
+
+    let auth_context = sessions::get().get(access_key).ok_or(AuthError::UnknownAccessKey)?;
+
+    let mut canonical_headers = String::new();
+    for name in signature.signed_headers.split(';') {
+        let value = req.headers.get(name).map(String::as_str).unwrap_or_default();
+        canonical_headers.push_str(name);
+        canonical_headers.push(':');
+        canonical_headers.push_str(&value.split_whitespace().collect::<Vec<_>>().join(" "));
+        canonical_headers.push('\n');
+    }
+
+    let canonical_request = [
+        req.method.to_str(),
+        &uri_encode(&uri_decode(path).ok_or(AuthError::Malformed)?, false),
+        &canonical_query(&params, signature.presigned),
+        &canonical_headers,
+        signature.signed_headers,
+        signature.content_sha256,
+    ]
+    .join("\n");
+
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        signature.timestamp,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let signing_key = signing_key(auth_context.secret_key(), scope);
+    verify(&signing_key, &string_to_sign, signature.signature)?;
+
+    Ok(Some(Signed {
+        signing_key,
+        timestamp: signature.timestamp.to_string(),
+        scope: scope.to_string(),
+        signature: signature.signature.to_string(),
+        content_sha256: signature.content_sha256.to_string(),
+        auth_context,
+    }))
+}
+
+// `AWS4-HMAC-SHA256 Credential=..., SignedHeaders=..., Signature=...`
+fn from_header<'a>(req: &'a Request, authorization: &'a str) -> Result<Signature<'a>, AuthError> {
+    let fields = authorization.strip_prefix(ALGORITHM).ok_or(AuthError::Malformed)?;
+    let mut credential = None;
+    let mut signed_headers = None;
+    let mut signature = None;
+
+    for field in fields.split(',') {
+        match field.trim().split_once('=') {
+            Some(("Credential", value)) => credential = Some(value),
+            Some(("SignedHeaders", value)) => signed_headers = Some(value),
+            Some(("Signature", value)) => signature = Some(value),
+            _ => {}
+        }
+    }
+
+    Ok(Signature {
+        credential: credential.ok_or(AuthError::Malformed)?,
+        signed_headers: signed_headers.ok_or(AuthError::Malformed)?,
+        signature: signature.ok_or(AuthError::Malformed)?,
+        timestamp: req.headers.get("x-amz-date").ok_or(AuthError::Malformed)?,
+        content_sha256: req.headers.get("x-amz-content-sha256").ok_or(AuthError::Malformed)?,
+        presigned: false,
+    })
+}
+
+// Presigned URLs carry the signature in X-Amz-* query parameters
+fn from_query<'a>(req: &'a Request, params: &'a [(String, String)], now: u64) -> Result<Signature<'a>, AuthError> {
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+            .ok_or(AuthError::Malformed)
+    };
+
+    if param("X-Amz-Algorithm")? != ALGORITHM {
+        return Err(AuthError::Malformed);
+    }
+
+    let timestamp = param("X-Amz-Date")?;
+    let expires = param("X-Amz-Expires")?.parse::<u64>().map_err(|_| AuthError::Malformed)?;
+    let signed_at = parse_timestamp(timestamp).ok_or(AuthError::Malformed)?;
+    if expires > MAX_EXPIRES_SECS {
+        return Err(AuthError::Malformed);
+    }
+    if signed_at > now + MAX_SKEW_SECS {
+        return Err(AuthError::TimeSkewed);
+    }
+    if signed_at.saturating_add(expires) < now {
+        return Err(AuthError::Expired);
+    }
+
+    Ok(Signature {
+        credential: param("X-Amz-Credential")?,
+        signed_headers: param("X-Amz-SignedHeaders")?,
+        signature: param("X-Amz-Signature")?,
+        timestamp,
+        content_sha256: req
+            .headers
+            .get("x-amz-content-sha256")
+            .map_or(UNSIGNED_PAYLOAD, String::as_str),
+        presigned: true,
+    })
+}
+
+// Splits `<access key>/<date>/<region>/s3/aws4_request` into the access key
+// and the scope after it. Access keys may contain slashes themselves
+fn parse_credential(credential: &str) -> Option<(&str, &str)> {
+    let mut parts = credential.rsplitn(5, '/');
+    let terminator = parts.next()?;
+    let service = parts.next()?;
+    let _region = parts.next()?;
+    let date = parts.next()?;
+    let access_key = parts.next().filter(|access_key| !access_key.is_empty())?;
+
+    if terminator != "aws4_request" || service != "s3" || date.len() != 8 {
+        return None;
+    }
+
+    Some((access_key, &credential[access_key.len() + 1..]))
+}
+
+// Decoded name and value pairs of a query string, in the order sent
+pub fn query_params(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((uri_decode(name)?, uri_decode(value)?))
+        })
+        .collect()
+}
+
+// Sorted and uniformly encoded, without the signature of a presigned URL
+fn canonical_query(params: &[(String, String)], presigned: bool) -> String {
+    let mut pairs: Vec<_> = params
+        .iter()
+        .filter(|(name, _)| !(presigned && name == "X-Amz-Signature"))
+        .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+        .collect();
+    pairs.sort();
+
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// Percent-encodes everything but unreserved characters, and slashes unless
+// `encode_slash` is set, the way SigV4 canonicalizes paths and queries
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+// Undoes percent-encoding, unlike in forms a `+` is taken literally
+pub fn uri_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+// Unix time of an ISO 8601 basic timestamp such as `20130524T000000Z`
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    if timestamp.len() != 16 || !timestamp.is_ascii() || &timestamp[8..9] != "T" || !timestamp.ends_with('Z') {
+        return None;
+    }
+
+    let field = |range: std::ops::Range<usize>| timestamp[range].parse::<u64>().ok();
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(9..11)?, field(11..13)?, field(13..15)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era_year = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = era_year * 365 + era_year / 4 - era_year / 100 + day_of_year;
+    let days = (year / 400 * 146097 + day_of_era).checked_sub(719468)?;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Derives the key of `<date>/<region>/<service>/aws4_request` from a secret
+fn signing_key(secret_key: &str, scope: &str) -> Vec<u8> {
+    scope
+        .split('/')
+        .fold(format!("AWS4{}", secret_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()))
+}
+
+// Compares in constant time so signatures can't be guessed byte by byte
+fn verify(signing_key: &[u8], string_to_sign: &str, signature: &str) -> Result<(), AuthError> {
+    let signature = hex::decode(signature).map_err(|_| AuthError::SignatureMismatch)?;
+    let mut mac = HmacSha256::new_from_slice(signing_key).expect("HMAC takes keys of any size");
+    mac.update(string_to_sign.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| AuthError::SignatureMismatch)
+}
+
+// The contents of a request body and its trailers
+pub type Body<'a> = (Cow<'a, [u8]>, HashMap<String, String>);
+
+// Undoes aws-chunked encoding and checks every signature and digest that
+// covers the body
+pub fn decode_body<'a>(
+    req: &'a Request,
+    signed: Option<&Signed>,
+) -> Result<Body<'a>, AuthError> {
+    let content_sha256 = match signed {
+        Some(signed) => Some(signed.content_sha256.as_str()),
+        None => req.headers.get("x-amz-content-sha256").map(String::as_str),
+    };
+
+    match content_sha256 {
+        None | Some(UNSIGNED_PAYLOAD) => Ok((Cow::Borrowed(&req.raw_body), req.trailers.clone())),
+        Some(STREAMING_UNSIGNED_TRAILER) => decode_chunks(&req.raw_body, None, true),
+        Some(streaming @ (STREAMING_PAYLOAD | STREAMING_PAYLOAD_TRAILER)) => {
+            let signed = signed.ok_or(AuthError::Malformed)?;
+            decode_chunks(&req.raw_body, Some(signed), streaming == STREAMING_PAYLOAD_TRAILER)
+        }
+        Some(digest) => {
+            if !hex::encode(Sha256::digest(&req.raw_body)).eq_ignore_ascii_case(digest) {
+                return Err(AuthError::ContentMismatch);
+            }
+            Ok((Cow::Borrowed(&req.raw_body), req.trailers.clone()))
+        }
+    }
+}
+
+// Chunks are `<hex size>[;chunk-signature=<signature>]\r\n<data>\r\n` and
+// end with an empty one, which trailers may follow. Each signature covers
+// its chunk and the signature before it, starting from the request's
+fn decode_chunks(
+    mut body: &[u8],
+    signed: Option<&Signed>,
+    has_trailers: bool,
+) -> Result<Body<'static>, AuthError> {
+    let mut data = Vec::new();
+    let mut previous = signed.map(|signed| signed.signature.clone());
+
+    loop {
+        let line = take_line(&mut body)?;
+        let (size, extension) = line.split_once(';').unwrap_or((line, ""));
+        let size = usize::from_str_radix(size.trim(), 16).map_err(|_| AuthError::InvalidChunk)?;
+        if body.len() < size {
+            return Err(AuthError::InvalidChunk);
+        }
+        let (chunk, rest) = body.split_at(size);
+        body = rest;
+
+        if let (Some(signed), Some(previous)) = (signed, previous.as_mut()) {
+            let signature = extension
+                .trim()
+                .strip_prefix("chunk-signature=")
+                .ok_or(AuthError::InvalidChunk)?;
+            let string_to_sign = format!(
+                "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+                signed.timestamp,
+                signed.scope,
+                previous,
+                EMPTY_SHA256,
+                hex::encode(Sha256::digest(chunk))
+            );
+            verify(&signed.signing_key, &string_to_sign, signature)?;
+            *previous = signature.to_string();
+        }
+
+        data.extend_from_slice(chunk);
+        if size == 0 {
+            break;
+        }
+
+        body = body.strip_prefix(b"\r\n").ok_or(AuthError::InvalidChunk)?;
+    }
+
+    let mut trailers = HashMap::new();
+    let mut canonical_trailers = String::new();
+    let mut trailer_signature = None;
+
+    if has_trailers {
+        loop {
+            let line = take_line(&mut body)?;
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = line.split_once(':').ok_or(AuthError::InvalidChunk)?;
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim().to_string());
+
+            if name == "x-amz-trailer-signature" {
+                trailer_signature = Some(value);
+                continue;
+            }
+
+            canonical_trailers.push_str(&format!("{}:{}\n", name, value));
+            trailers.insert(name, value);
+        }
+    }
+
+    if let (Some(signed), Some(previous)) = (signed, previous) {
+        if has_trailers {
+            let signature = trailer_signature.ok_or(AuthError::InvalidChunk)?;
+            let string_to_sign = format!(
+                "AWS4-HMAC-SHA256-TRAILER\n{}\n{}\n{}\n{}",
+                signed.timestamp,
+                signed.scope,
+                previous,
+                hex::encode(Sha256::digest(canonical_trailers.as_bytes()))
+            );
+            verify(&signed.signing_key, &string_to_sign, &signature)?;
+        }
+    }
+
+    Ok((Cow::Owned(data), trailers))
+}
+
+fn take_line<'a>(body: &mut &'a [u8]) -> Result<&'a str, AuthError> {
+    let end = body
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .ok_or(AuthError::InvalidChunk)?;
+    let line = std::str::from_utf8(&body[..end]).map_err(|_| AuthError::InvalidChunk)?;
+    *body = &body[end + 2..];
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use a_http_parser::http::Method;
+
+    use super::*;
+    use crate::authentication::AuthLevel;
+    use crate::testing;
+
+    // The streaming upload example of the S3 documentation
+    #[test]
+    fn test_chunked_payload() {
+        let scope = "20130524/us-east-1/s3/aws4_request";
+        let signed = Signed {
+            auth_context: AuthContext::random(),
+            signing_key: signing_key("wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY", scope),
+            timestamp: "20130524T000000Z".to_string(),
+            scope: scope.to_string(),
+            signature: "4f232c4386841ef735655705268965c44a0e4690baa4adea153f7db9fa80a0a9".to_string(),
+            content_sha256: STREAMING_PAYLOAD.to_string(),
+        };
+
+        let mut body = Vec::new();
+        for (size, signature) in [
+            (65536, "ad80c730a21e5b8d04586a2213dd63b9a0e99e0e2307b0ade35a65485a288648"),
+            (1024, "0055627c9e194cb4542bae2aa5492e3c1575bbb81b612b7d234b86a503ef5497"),
+            (0, "b6c6ea8a5354eaf15b3cb7646744f4275b71ea724fed81ceb9323e279d449df9"),
+        ] {
+            body.extend(format!("{:x};chunk-signature={}\r\n", size, signature).into_bytes());
+            body.extend(vec![b'a'; size]);
+            body.extend(b"\r\n");
+        }
+
+        let (data, _) = decode_chunks(&body, Some(&signed), false).unwrap();
+        assert_eq!(data.len(), 66560);
+
+        body[100] ^= 1;
+        assert!(decode_chunks(&body, Some(&signed), false).is_err());
+
+        let (data, trailers) = decode_chunks(b"3\r\nabc\r\n0\r\nx-amz-checksum-crc32:NSRBwg==\r\n\r\n", None, true).unwrap();
+        assert_eq!(&data[..], b"abc");
+        assert_eq!(trailers.get("x-amz-checksum-crc32").unwrap(), "NSRBwg==");
+
+        assert_eq!(parse_timestamp("20130524T000000Z"), Some(1369353600));
+        assert_eq!(parse_credential("a/b/20130524/us-east-1/s3/aws4_request"), Some(("a/b", scope)));
+        assert_eq!(uri_encode("a b/c~", false), "a%20b/c~");
+    }
+
+    #[tokio::test]
+    async fn test_signed_headers() {
+        let _guard = testing::setup().await;
+        let auth_context = testing::session(AuthLevel::ReadWrite);
+        let request = |host: &str| {
+            let mut req = Request::new(Method::GET, "/photos/a.jpg?x-id=GetObject".to_string(), "HTTP/1.1".to_string());
+            req.headers.insert("host".to_string(), host.to_string());
+            req
+        };
+
+        let mut req = request("photos.cdn.example.com");
+        testing::sign(&mut req, &auth_context, "host;x-amz-content-sha256;x-amz-date");
+        let signed = authenticate(&req, testing::SIGNED_AT).unwrap().unwrap();
+        assert_eq!(signed.auth_context.access_key, auth_context.access_key);
+
+        // Replayed against another bucket domain
+        req.headers.insert("host".to_string(), "videos.cdn.example.com".to_string());
+        assert_eq!(authenticate(&req, testing::SIGNED_AT).err(), Some(AuthError::SignatureMismatch));
+
+        // Signatures leaving out the host or the date are refused, however valid
+        for signed_headers in ["x-amz-content-sha256;x-amz-date", "host;x-amz-content-sha256"] {
+            let mut req = request("photos.cdn.example.com");
+            testing::sign(&mut req, &auth_context, signed_headers);
+            assert_eq!(authenticate(&req, testing::SIGNED_AT).err(), Some(AuthError::Malformed));
+        }
+
+        assert_eq!(authenticate(&req, testing::SIGNED_AT + MAX_SKEW_SECS + 1).err(), Some(AuthError::TimeSkewed));
+    }
+}
@@ -1,15 +1,18 @@
 use async_trait::async_trait;
+use log::warn;
 use serde::{de::DeserializeOwned, Serialize};
 use sha2::{Digest, Sha256};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::io::{AsyncRead, AsyncSeekExt, ReadBuf};
+use tokio::sync::watch;
 use tokio_stream::Stream;
 
-use crate::TMP_PATH;
+use crate::config;
+use crate::metrics::METRICS;
 #[async_trait]
 pub trait StorableBase {
     fn base_dir() -> PathBuf;
@@ -24,9 +27,8 @@ pub trait StorableBase {
 
     async fn canonicalize_path(path: PathBuf) -> std::io::Result<PathBuf> {
         let path = fs::canonicalize(path).await?;
-        if !path.starts_with(TMP_PATH) {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
+        if !path.starts_with(&config::get().data_dir) {
+            Err(std::io::Error::other(
                 "Invalid path",
             ))
         } else {
@@ -35,23 +37,60 @@ pub trait StorableBase {
     }
 }
 
+// Walks the records of a directory in file name order. Files that can't be
+// read or parsed are logged, counted and skipped instead of ending the walk
 pub struct StorableIterator<S: StorableJson> {
-    read_dir: fs::ReadDir,
+    paths: std::vec::IntoIter<PathBuf>,
+    cancel: Option<watch::Receiver<bool>>,
+    skipped: usize,
     _marker: std::marker::PhantomData<S>,
 }
 
 impl<S: StorableJson> StorableIterator<S> {
-    pub async fn next(&mut self) -> std::io::Result<Option<S>> {
-        if let Some(entry) = self.read_dir.next_entry().await? {
-            let path = entry.path();
-            let contents = fs::read_to_string(&path).await?;
-            let result: S = serde_json::from_str(&contents)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
-            Ok(Some(result))
-        } else {
-            Ok(None)
+    // Stops yielding records once `cancel` flips to true
+    pub fn cancel_on(mut self, cancel: watch::Receiver<bool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| *cancel.borrow())
+    }
+
+    // Number of corrupt records skipped so far
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub async fn next(&mut self) -> Option<S> {
+        loop {
+            if self.is_cancelled() {
+                return None;
+            }
+
+            let path = self.paths.next()?;
+            let contents = match fs::read_to_string(&path).await {
+                Ok(contents) => contents,
+                // Removed since the directory was read
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    self.skip(&path, &e.to_string());
+                    continue;
+                }
+            };
+
+            match serde_json::from_str(&contents) {
+                Ok(record) => return Some(record),
+                Err(e) => self.skip(&path, &e.to_string()),
+            }
         }
     }
+
+    fn skip(&mut self, path: &Path, error: &str) {
+        warn!("{}: skipping corrupt record - {}", path.display(), error);
+        METRICS.corrupt_record();
+        self.skipped += 1;
+    }
 }
 
 #[async_trait]
@@ -73,31 +112,6 @@ pub trait StorableJson: StorableBase + Serialize + DeserializeOwned {
         fs::remove_file(path).await
     }
 
-    async fn load(id: &str) -> Result<Self, String> {
-        let _ = Self::ensure_base_dir_exists()
-            .map_err(|e| format!("Failed to create base dir: {}", e))?;
-
-        let path = Self::base_dir().join(format!(
-            "{}.json",
-            hex::encode(Sha256::digest(id.as_bytes()))
-        ));
-
-        let path = Self::canonicalize_path(path)
-            .await
-            .map_err(|e| format!("Invalid path: {}", e))?;
-        let mut file = fs::File::open(&path)
-            .await
-            .map_err(|e| format!("Failed to open file: {}", e))?;
-
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .await
-            .map_err(|e| format!("Failed to read file: {}", e))?;
-
-        // Loading the instance from the file
-        serde_json::from_str(&contents).map_err(|e| format!("Failed to deserialize: {}", e))
-    }
-
     // Saves the instance to the appropriate directory
     async fn save(&self) -> std::io::Result<()> {
         // Ensure base directory exists
@@ -108,16 +122,15 @@ pub trait StorableJson: StorableBase + Serialize + DeserializeOwned {
             hex::encode(Sha256::digest(self.id().as_bytes()))
         ));
 
-        // Normalize path to ensure it's in TMP_PATH
+        // Normalize path to ensure it's in the data directory
         let parent = path.parent().unwrap();
         let parent = Self::canonicalize_path(parent.to_path_buf())
             .await
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Invalid path"))?;
+            .map_err(|_| std::io::Error::other("Invalid path"))?;
 
-        // Ensure path root is in TMP_PATH
-        if !parent.starts_with(TMP_PATH) {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
+        // Ensure path root is in the data directory
+        if !parent.starts_with(&config::get().data_dir) {
+            return Err(std::io::Error::other(
                 "Invalid path",
             ));
         }
@@ -126,9 +139,21 @@ pub trait StorableJson: StorableBase + Serialize + DeserializeOwned {
     }
 
     async fn list() -> std::io::Result<StorableIterator<Self>> {
-        let read_dir = fs::read_dir(Self::base_dir()).await?;
+        let mut read_dir = fs::read_dir(Self::base_dir()).await?;
+        let mut paths = Vec::new();
+
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
         Ok(StorableIterator {
-            read_dir,
+            paths: paths.into_iter(),
+            cancel: None,
+            skipped: 0,
             _marker: std::marker::PhantomData,
         })
     }
@@ -202,11 +227,15 @@ pub trait StorableBlob: StorableBase {
         tokio::fs::read(path).await
     }
 
-    async fn stream_file(&self) -> Result<FileIterator<tokio::fs::File>, ()> {
+    // Streams the file starting `offset` bytes in
+    async fn stream_file_from(&self, offset: u64) -> Result<FileIterator<tokio::fs::File>, ()> {
         let _ = Self::ensure_base_dir_exists().map_err(|_| ())?;
         let path = Self::base_dir().join(self.id());
         let path = Self::canonicalize_path(path).await.map_err(|_| ())?;
-        let file = File::open(path).await.map_err(|_| ())?;
+        let mut file = File::open(path).await.map_err(|_| ())?;
+        if offset > 0 {
+            file.seek(std::io::SeekFrom::Start(offset)).await.map_err(|_| ())?;
+        }
         Ok(FileIterator::new(file))
     }
 
@@ -1,16 +1,32 @@
 use std::{
-    path::{self, PathBuf},
-    time::SystemTime,
+    borrow::Cow,
+    collections::BTreeMap,
+    path::{self, Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
 use a_http_parser::http::MimeType;
+use log::error;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use tokio::sync::OwnedMutexGuard;
 
 use crate::{
+    audit,
     authentication::{AuthContext, AuthLevel},
-    metadata::Metadata,
-    storable::{StorableBase, StorableBlob, StorableJson}, TMP_PATH,
+    buckets::{self, Bucket},
+    checksum::Checksums,
+    compression,
+    encryption::{self, CustomerKey},
+    index,
+    s3,
+    metadata::{LockRequest, Metadata},
+    multipart::{self, Upload},
+    search::Query,
+    sessions,
+    config::{self, LockMode},
+    storable::{StorableBase, StorableBlob, StorableJson},
+    trash::{self, RestoreError, TrashedObject},
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,11 +34,14 @@ pub struct Object {
     key: String,
     pub metadata: Metadata,
     data: Option<Vec<u8>>,
+    // The verified key of an object encrypted with a customer key
+    #[serde(skip)]
+    pub customer_key: Option<CustomerKey>,
 }
 
 impl StorableBase for Object {
     fn base_dir() -> PathBuf {
-        format!("{}/storage", TMP_PATH).into()
+        config::get().data_dir.join("storage")
     }
 
     fn id(&self) -> &str {
@@ -40,75 +59,288 @@ impl StorableBlob for Object {
     }
 }
 
+// Why an object could not be read or deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageError {
+    NotFound,
+    CustomerKeyRequired,
+    CustomerKeyMismatch,
+    CustomerKeyUnexpected,
+    Corrupt,
+    Forbidden,
+    DeleteFailed,
+    SaveFailed,
+    NoSuchBucket,
+    QuotaExceeded,
+    // A retention or legal hold protects the object
+    Locked,
+    AuditFailed,
+}
+
+impl StorageError {
+    pub fn status_code(self) -> u16 {
+        match self {
+            StorageError::NotFound | StorageError::NoSuchBucket => 404,
+            StorageError::QuotaExceeded => 507,
+            StorageError::CustomerKeyRequired | StorageError::CustomerKeyUnexpected => 400,
+            StorageError::CustomerKeyMismatch | StorageError::Forbidden | StorageError::Locked => 403,
+            StorageError::Corrupt | StorageError::DeleteFailed | StorageError::AuditFailed => 500,
+            StorageError::SaveFailed => 400,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            StorageError::NotFound => "Not found / Forbidden",
+            StorageError::CustomerKeyRequired => "Object is encrypted with a customer key, send it in X-Encryption-Key",
+            StorageError::CustomerKeyMismatch => "X-Encryption-Key is not the key the object is encrypted with",
+            StorageError::CustomerKeyUnexpected => "Object is not encrypted with a customer key",
+            StorageError::Corrupt => "Failed to read object",
+            StorageError::Forbidden => "Forbidden",
+            StorageError::DeleteFailed => "Failed to delete",
+            StorageError::SaveFailed => "Failed to save",
+            StorageError::NoSuchBucket => "No such bucket",
+            StorageError::QuotaExceeded => "Bucket quota exceeded",
+            StorageError::Locked => "Object is locked",
+            StorageError::AuditFailed => "Failed to write the audit log",
+        }
+    }
+}
+
+// Everything about an object besides its contents that a client sets on upload
+pub struct PutOptions<'a> {
+    pub mime_type: MimeType,
+    pub readable_by: AuthLevel,
+    pub expires_at: Option<u64>,
+    pub customer_key: Option<&'a CustomerKey>,
+    pub user_metadata: BTreeMap<String, String>,
+    // Retention and legal hold of a new object, a retention in the past is none
+    pub retain_until: Option<u64>,
+    pub legal_hold: bool,
+    // Id of the multipart upload the object completes, its parts stop
+    // counting toward the bucket quota once the object is stored
+    pub completes: Option<&'a str>,
+}
+
+// Keys answered by the server itself, objects can never be stored under them
+const RESERVED_KEYS: [&str; 2] = ["healthz", "readyz"];
+
 pub struct Storage {
     auth_context: AuthContext,
+    // Keys are relative to the bucket, the global namespace without one
+    bucket: Option<Bucket>,
+    bypass_governance: bool,
 }
 
 impl Storage {
     pub fn new(auth_context: AuthContext) -> Self {
-        Self { auth_context }
+        Self {
+            auth_context,
+            bucket: None,
+            bypass_governance: false,
+        }
     }
 
-    pub async fn get_object(&self, key: &str, read_data: bool) -> Option<Object> {
-        let metadata = match Metadata::load(key).await {
-            Ok(metadata) => metadata,
-            Err(_) => return None,
-        };
+    pub fn in_bucket(mut self, bucket: Option<Bucket>) -> Self {
+        self.bucket = bucket;
+        self
+    }
 
-        if !self.is_object_readable(&metadata).await {
-            return None;
+    // Key of an object within the bucket, the reverse of `object_key`
+    pub fn relative_key<'a>(&self, key: &'a str) -> &'a str {
+        match &self.bucket {
+            Some(bucket) => key.strip_prefix(&bucket.prefix()).unwrap_or(key),
+            None => key,
+        }
+    }
+
+    // Key an object is stored under
+    fn object_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match &self.bucket {
+            Some(bucket) => Cow::Owned(bucket.object_key(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    // Lets retentions be overridden, only admins can and only in governance
+    // mode. Every bypass is audited
+    pub fn bypass_governance(mut self) -> Result<Self, StorageError> {
+        if self.auth_context.access_level != AuthLevel::Admin || config::get().locks.mode != LockMode::Governance {
+            return Err(StorageError::Forbidden);
+        }
+
+        self.bypass_governance = true;
+        Ok(self)
+    }
+
+    pub fn is_reserved_key(key: &str) -> bool {
+        let first = key.split('/').next();
+        RESERVED_KEYS.contains(&key) || [Some(trash::PATH), Some(buckets::PATH), Some(s3::CREDENTIALS_PATH)].contains(&first)
+    }
+
+    // Loads an object the caller may read. Objects encrypted with a customer
+    // key can only be read with that key, which is kept on the object
+    pub async fn get_object(
+        &self,
+        key: &str,
+        read_data: bool,
+        customer_key: Option<&CustomerKey>,
+    ) -> Result<Object, StorageError> {
+        let key = self.object_key(key);
+        let mut object = self.find_object(&key).await.ok_or(StorageError::NotFound)?;
+
+        match (&object.metadata.encryption, customer_key) {
+            (Some(info), Some(customer_key)) if info.is_customer_key() => {
+                if !customer_key.matches(info) {
+                    return Err(StorageError::CustomerKeyMismatch);
+                }
+                object.customer_key = Some(customer_key.clone());
+            }
+            (Some(info), None) if info.is_customer_key() => return Err(StorageError::CustomerKeyRequired),
+            (_, Some(_)) => return Err(StorageError::CustomerKeyUnexpected),
+            _ => {}
         }
 
         // Use get_object to fetch ownership of the metadata and do
         // the access check. If we don't need to read the data, we can
         // return early
         if !read_data {
-            return Some(Object {
-                key: key.to_string(),
-                metadata,
-                data: None,
-            });
+            return Ok(object);
         }
 
         // We own the metadata now, so now we can load the data
-        let data = match Object::load(key).await {
-            Ok(data) => Some(data),
-            Err(_) => return None,
-        };
+        let data = Object::load(&key).await.map_err(|_| StorageError::NotFound)?;
+        let data = decode_stored(&object.metadata, customer_key, data).map_err(|e| {
+            error!("{}: failed to decode - {}", key, e);
+            StorageError::Corrupt
+        })?;
+        object.data = Some(data);
+
+        Ok(object)
+    }
+
+    // The metadata of an object the caller may read, without loading its
+    // data. Takes the key the object is stored under
+    async fn find_object(&self, key: &str) -> Option<Object> {
+        let metadata = index::get().get(key)?;
+
+        // Expired objects are gone as far as clients are concerned, the
+        // maintenance task removes them from disk
+        if metadata.is_expired(now()) {
+            return None;
+        }
+
+        if !self.is_object_readable(&metadata).await {
+            return None;
+        }
 
         Some(Object {
             key: key.to_string(),
             metadata,
-            data,
+            data: None,
+            customer_key: None,
         })
     }
 
-    pub async fn put_object(&self, key: &str, data: &[u8], mime_type: MimeType, readable_by: AuthLevel) -> bool {
-        if let Some(object) = self.get_object(key, false).await {
+    pub async fn put_object(
+        &self,
+        key: &str,
+        data: &[u8],
+        options: PutOptions<'_>,
+    ) -> Result<(), StorageError> {
+        let PutOptions {
+            mime_type,
+            readable_by,
+            expires_at,
+            customer_key,
+            user_metadata,
+            retain_until,
+            legal_hold,
+            completes,
+        } = options;
+
+        if Self::is_reserved_key(key) {
+            return Err(StorageError::SaveFailed);
+        }
+
+        let key = self.object_key(key);
+        let key = key.as_ref();
+
+        // Buckets only take objects from whoever manages them, up to their quota
+        let _quota = match &self.bucket {
+            Some(bucket) => {
+                if !bucket.is_managed_by(&self.auth_context) {
+                    return Err(StorageError::Forbidden);
+                }
+
+                reserve_quota(bucket, key, data.len() as u64, completes).await?
+            }
+            None if config::get().buckets.required => return Err(StorageError::NoSuchBucket),
+            None => None,
+        };
+
+        if let Some(object) = self.find_object(key).await {
             if !self.is_object_writable(&object.metadata).await {
-                return false;
+                return Err(StorageError::SaveFailed);
             }
         };
 
-        let mut hasher = Sha256::new();
-        hasher.update(data);
+        // Locks hold against everyone, whether they can read the object or not
+        if let Some(existing) = index::get().get(key) {
+            if self.check_lock(&existing)? {
+                self.audit_bypass("overwrite", &existing)?;
+            }
+        }
+
+        let compressed = compression::compress_at_rest(data, mime_type.to_str());
+        let stored_encoding = compressed
+            .is_some()
+            .then(|| compression::STORED_ENCODING.to_string());
+        let mut stored = compressed.unwrap_or_else(|| data.to_vec());
+
+        // A customer key takes precedence over server side encryption
+        let mut encryption = None;
+        if customer_key.is_some() || config::get().encryption.enabled {
+            match encryption::encrypt(&stored, customer_key) {
+                Ok((sealed, info)) => {
+                    stored = sealed;
+                    encryption = Some(info);
+                }
+                Err(e) => {
+                    error!("{}: failed to encrypt - {}", key, e);
+                    return Err(StorageError::SaveFailed);
+                }
+            }
+        }
+
+        // The etag identifies the uncompressed contents, unless they are
+        // sealed with a customer key: digests of the plaintext would let
+        // anyone who can list the object confirm a guess of its contents
+        let (etag, checksums) = match customer_key {
+            Some(_) => (hex::encode(Sha256::digest(&stored)), None),
+            None => (hex::encode(Sha256::digest(data)), Some(Checksums::compute(data))),
+        };
 
         let metadata = Metadata {
             name: key
                 .split_terminator(path::MAIN_SEPARATOR)
-                .last()
+                .next_back()
                 .unwrap()
                 .to_string(),
             key: key.to_string(),
             size: data.len() as u64,
-            last_modified: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            etag: hex::encode(hasher.finalize()),
+            last_modified: now(),
+            etag,
             mime_type: mime_type.to_str().to_string(),
             owner_id: self.auth_context.access_key.clone(),
-            readable_by
+            readable_by,
+            expires_at,
+            stored_encoding,
+            encryption,
+            checksums,
+            user_metadata,
+            retain_until: retain_until.filter(|&retain_until| retain_until > now()),
+            legal_hold,
         };
 
         match &metadata.save().await {
@@ -116,57 +348,333 @@ impl Storage {
                 let object = Object {
                     key: key.to_string(),
                     metadata,
-                    data: Some(data.to_vec()),
+                    data: Some(stored),
+                    customer_key: None,
                 };
 
 
                 match object.save().await {
-                    Ok(_) => true,
+                    Ok(_) => {
+                        if let Err(e) = index::get().put(&object.metadata) {
+                            error!("{}: failed to log the metadata index change - {}", key, e);
+                        }
+
+                        Ok(())
+                    }
                     Err(_) => {
-                        object.metadata.delete().await.unwrap_or_else(|_| {});                    
+                        object.metadata.delete().await.unwrap_or(());                    
 
-                        false
+                        Err(StorageError::SaveFailed)
                     },
                 }
             }
-            Err(_) => return false,
+            Err(_) => Err(StorageError::SaveFailed),
         }
     }
 
-    pub async fn delete_object(&self, key: &str) -> bool {
-        if let Some(object) = self.get_object(key, false).await {
-            if !self.is_object_writable(&object.metadata).await {
-                return false;
+    pub async fn delete_object(&self, key: &str) -> Result<(), StorageError> {
+        let metadata = self.find_deletable(key).await?;
+        if self.check_lock(&metadata)? {
+            self.audit_bypass("delete", &metadata)?;
+        }
+
+        // Owners get to change their mind until the trash is purged
+        if trash::is_enabled() {
+            let bucket = self.bucket.as_ref().map(|bucket| bucket.name.as_str());
+            return match trash::trash(metadata, &self.auth_context.access_key, bucket).await {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    error!("{}: failed to move to the trash - {}", key, e);
+                    Err(StorageError::DeleteFailed)
+                }
+            };
+        }
+
+        match Self::remove_object(metadata).await {
+            true => Ok(()),
+            false => Err(StorageError::DeleteFailed),
+        }
+    }
+
+    // Looks up an object the caller may delete without deleting it
+    pub async fn find_deletable(&self, key: &str) -> Result<Metadata, StorageError> {
+        let object = self.find_object(&self.object_key(key)).await.ok_or(StorageError::NotFound)?;
+
+        if !self.is_object_writable(&object.metadata).await {
+            return Err(StorageError::Forbidden);
+        }
+
+        self.check_lock(&object.metadata)?;
+        Ok(object.metadata)
+    }
+
+    // Whether the lock of an object lets the caller change it, true when
+    // that takes a governance bypass the caller then has to audit
+    fn check_lock(&self, metadata: &Metadata) -> Result<bool, StorageError> {
+        if metadata.legal_hold {
+            return Err(StorageError::Locked);
+        }
+
+        match metadata.is_retained(now()) {
+            false => Ok(false),
+            true if self.bypass_governance => Ok(true),
+            true => Err(StorageError::Locked),
+        }
+    }
+
+    fn audit_bypass(&self, action: &str, metadata: &Metadata) -> Result<(), StorageError> {
+        let detail = format!("bypassed retention until {}", metadata.retain_until.unwrap_or_default());
+        self.audit(action, &metadata.key, detail)
+    }
+
+    fn audit(&self, action: &str, key: &str, detail: String) -> Result<(), StorageError> {
+        audit::record(&self.auth_context.access_key, action, key, detail).map_err(|e| {
+            error!("{}: failed to write the audit log - {}", key, e);
+            StorageError::AuditFailed
+        })
+    }
+
+    // Changes the retention or legal hold of an object. Retentions can be
+    // extended by anyone who may write the object, shortening one takes a
+    // governance bypass and only admins lift legal holds
+    pub async fn update_lock(&self, key: &str, lock: LockRequest) -> Result<Metadata, StorageError> {
+        let key = self.object_key(key);
+        let key = key.as_ref();
+        let object = self.find_object(key).await.ok_or(StorageError::NotFound)?;
+        if !self.is_object_writable(&object.metadata).await {
+            return Err(StorageError::Forbidden);
+        }
+
+        let mut metadata = object.metadata;
+        let now = now();
+        let mut audits = Vec::new();
+
+        if let Some(retain_until) = lock.retain_until {
+            let retain_until = Some(retain_until).filter(|&retain_until| retain_until > now);
+
+            if metadata.is_retained(now) && retain_until < metadata.retain_until {
+                if !self.bypass_governance {
+                    return Err(StorageError::Locked);
+                }
+
+                let detail = format!(
+                    "shortened retention from {} to {}",
+                    metadata.retain_until.unwrap_or_default(),
+                    retain_until.unwrap_or_default()
+                );
+                audits.push(("shorten-retention", detail));
             }
 
-            match object.metadata.delete().await {
-                Ok(_) => {}
-                Err(_) => return false,
+            metadata.retain_until = retain_until;
+        }
+
+        if let Some(legal_hold) = lock.legal_hold {
+            if metadata.legal_hold && !legal_hold {
+                if self.auth_context.access_level != AuthLevel::Admin {
+                    return Err(StorageError::Forbidden);
+                }
+                audits.push(("lift-legal-hold", "lifted legal hold".to_string()));
             }
 
-            match object.delete().await {
-                Ok(_) => true,
-                Err(_) => false,
+            metadata.legal_hold = legal_hold;
+        }
+
+        for (action, detail) in audits {
+            self.audit(action, key, detail)?;
+        }
+
+        metadata.save().await.map_err(|_| StorageError::SaveFailed)?;
+        if let Err(e) = index::get().put(&metadata) {
+            error!("{}: failed to log the metadata index change - {}", key, e);
+        }
+
+        Ok(metadata)
+    }
+
+    // Removes an object without any access checks
+    pub async fn remove_object(metadata: Metadata) -> bool {
+        if metadata.delete().await.is_err() {
+            return false;
+        }
+        if let Err(e) = index::get().remove(&metadata.key) {
+            error!("{}: failed to log the metadata index change - {}", metadata.key, e);
+        }
+
+        let object = Object {
+            key: metadata.key.clone(),
+            metadata,
+            data: None,
+            customer_key: None,
+        };
+
+        object.delete().await.is_ok()
+    }
+
+    // Deletes stored blobs that have no metadata and were last written more
+    // than `min_age` ago, younger ones may belong to an upload in progress
+    pub async fn remove_orphaned_blobs(min_age: Duration) -> std::io::Result<usize> {
+        Object::ensure_base_dir_exists()?;
+        let base_dir = Object::base_dir();
+        let mut pending = vec![base_dir.clone()];
+        let mut removed = 0;
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(_) if dir != base_dir => continue,
+                Err(error) => return Err(error),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                // Entries can disappear while we walk, e.g. by a concurrent delete
+                let Ok(file_type) = entry.file_type().await else {
+                    continue;
+                };
+
+                if file_type.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+
+                let Some(key) = path.strip_prefix(&base_dir).ok().and_then(Path::to_str) else {
+                    continue;
+                };
+
+                // A corrupt record still claims its blob, fsck can rebuild it
+                let record = Metadata::base_dir().join(format!("{}.json", hex::encode(Sha256::digest(key.as_bytes()))));
+                if tokio::fs::try_exists(&record).await.unwrap_or(true) {
+                    continue;
+                }
+
+                let age = entry
+                    .metadata()
+                    .await
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .unwrap_or_default();
+
+                if age >= min_age && tokio::fs::remove_file(&path).await.is_ok() {
+                    removed += 1;
+                }
             }
-        } else {
-            false
         }
+
+        Ok(removed)
     }
 
+    // Objects readable by the caller ordered by key, corrupt records are skipped
     pub async fn list_objects(&self) -> Vec<Metadata> {
         let mut objects = Vec::new();
+        let now = now();
+
+        let candidates = match &self.bucket {
+            Some(bucket) => index::get().with_prefix(&bucket.prefix()),
+            None => index::get().list(),
+        };
 
-        if let Ok(mut list) = Metadata::list().await {
-            while let Some(metadata) = list.next().await.unwrap() {
-                if self.is_object_readable(&metadata).await {
-                    objects.push(metadata);
+        for metadata in candidates {
+            if !metadata.is_expired(now) && self.is_object_readable(&metadata).await {
+                objects.push(metadata);
+            }
+        }
+
+        objects
+    }
+
+    // Puts a trashed object back. Objects only go back into a bucket the
+    // caller manages that has room for them, the one they were deleted from
+    // may have been deleted or even re-created by someone else since
+    pub async fn restore(&self, trashed: TrashedObject) -> Result<Metadata, RestoreError> {
+        let key = &trashed.metadata.key;
+        let bucket = key.split_once('/').and_then(|(name, _)| buckets::get().get(name));
+
+        let _quota = match (bucket, &trashed.bucket) {
+            (Some(bucket), _) => {
+                if !bucket.is_managed_by(&self.auth_context) {
+                    return Err(RestoreError::Refused(StorageError::Forbidden));
                 }
+
+                reserve_quota(&bucket, key, trashed.metadata.size, None).await.map_err(RestoreError::Refused)?
+            }
+            (None, Some(_)) => return Err(RestoreError::Refused(StorageError::NoSuchBucket)),
+            (None, None) if config::get().buckets.required => {
+                return Err(RestoreError::Refused(StorageError::NoSuchBucket))
             }
+            (None, None) => None,
+        };
+
+        trash::restore(trashed).await
+    }
 
-            objects
-        } else {
-            Vec::new()
+    // Trashed objects the caller could have deleted, newest first
+    pub async fn trashed_objects(&self) -> Vec<TrashedObject> {
+        let mut visible = Vec::new();
+
+        for trashed in trash::list().await.unwrap_or_default() {
+            if self.is_object_writable(&trashed.metadata).await {
+                visible.push(trashed);
+            }
+        }
+
+        visible
+    }
+
+    // Objects matching a search the caller may read, one page at a time
+    pub async fn search(&self, mut query: Query) -> (Vec<Metadata>, Option<String>) {
+        let index = index::get();
+        let now = now();
+
+        if self.bucket.is_some() {
+            query.prefix = Some(self.object_key(query.prefix.as_deref().unwrap_or_default()).into_owned());
+        }
+
+        // Start from the narrowest secondary index the query allows
+        let candidates = match (&query.owner, &query.mime_type, &query.prefix) {
+            (Some(owner), _, _) => index.owned_by(owner),
+            (None, Some(mime_type), _) if !mime_type.ends_with("/*") => index.with_mime_type(mime_type),
+            (None, _, Some(prefix)) => index.with_prefix(prefix),
+            _ => index.list(),
+        };
+
+        let mut results = Vec::new();
+        for metadata in candidates {
+            if query.matches(&metadata) && !metadata.is_expired(now) && self.is_object_readable(&metadata).await {
+                results.push(metadata);
+            }
+        }
+
+        query.paginate(results)
+    }
+
+    // Up to `limit` objects the caller may read whose key starts with
+    // `prefix` and sorts after `start_after`, ordered by key. Fewer are
+    // returned only once there are no more
+    pub async fn list_after(&self, prefix: &str, start_after: &str, limit: usize) -> Vec<Metadata> {
+        let prefix = self.object_key(prefix);
+        let mut after = self.object_key(start_after).into_owned();
+        let now = now();
+        let mut results = Vec::new();
+
+        while results.len() < limit {
+            let wanted = limit - results.len();
+            let candidates = index::get().with_prefix_after(&prefix, &after, wanted);
+            let exhausted = candidates.len() < wanted;
+
+            for metadata in candidates {
+                after.clone_from(&metadata.key);
+                if !metadata.is_expired(now) && self.is_object_readable(&metadata).await {
+                    results.push(metadata);
+                }
+            }
+
+            if exhausted {
+                break;
+            }
         }
+
+        results
     }
 
     pub async fn is_object_readable(&self, metadata: &Metadata) -> bool {
@@ -174,20 +682,20 @@ impl Storage {
             return true;
         }
 
-        match AuthContext::load(&self.auth_context.access_key).await {
-            Ok(context) => {
-                match AuthContext::load(&metadata.owner_id).await {
-                    Ok(owner_context) => {
+        match sessions::get().get(&self.auth_context.access_key) {
+            Some(context) => {
+                match sessions::get().get(&metadata.owner_id) {
+                    Some(owner_context) => {
                         // Admins cannot read other admins' objects        
                         if self.auth_context.access_level == AuthLevel::Admin && owner_context.access_level != AuthLevel::Admin {
                             return true;
                         }
 
-                        if metadata.readable_by == AuthLevel::Owner && self.auth_context == owner_context {
+                        if metadata.readable_by == AuthLevel::Owner && self.auth_context.access_key == owner_context.access_key {
                             return true;
                         }
                     },
-                    Err(_) => return context.access_level == AuthLevel::Admin,
+                    None => return context.access_level == AuthLevel::Admin,
                 }
   
                 if metadata.readable_by <= self.auth_context.access_level {
@@ -195,19 +703,19 @@ impl Storage {
                 }
         
             }
-            Err(_) => return false,
+            None => return false,
         }
       
 
-        return false;
+        false
 
     }
 
     pub async fn is_object_writable(&self, metadata: &Metadata) -> bool {
-        match AuthContext::load(&self.auth_context.access_key).await {
-            Ok(context) => {
-                match AuthContext::load(&metadata.owner_id).await {
-                    Ok(owner_context) => {
+        match sessions::get().get(&self.auth_context.access_key) {
+            Some(context) => {
+                match sessions::get().get(&metadata.owner_id) {
+                    Some(owner_context) => {
                         // Admins cannot write other admins' objects
                         if self.auth_context.access_level == AuthLevel::Admin {
                             if context.access_level == AuthLevel::Admin {
@@ -217,16 +725,262 @@ impl Storage {
                             return true;
                         }
                 
-                        if self.auth_context == owner_context {
+                        if self.auth_context.access_key == owner_context.access_key {
                             return true;
                         }
                     },
-                    Err(_) => return context.access_level == AuthLevel::Admin,
+                    None => return context.access_level == AuthLevel::Admin,
                 }
             }
-            Err(_) => return false,
+            None => return false,
         }
 
-        return false;
+        false
+    }
+}
+
+// Checks `bucket` has room for `size` bytes stored under `key`, replacing
+// any object stored there now, and returns the quota lock to hold until
+// they are. `completes` is the multipart upload whose parts they replace
+async fn reserve_quota(
+    bucket: &Bucket,
+    key: &str,
+    size: u64,
+    completes: Option<&str>,
+) -> Result<Option<OwnedMutexGuard<()>>, StorageError> {
+    let guard = buckets::get().lock_quota(bucket).await;
+    if guard.is_none() {
+        return Ok(None);
     }
+
+    let (objects, bytes) = index::get().prefix_totals(&bucket.prefix());
+    let replaced = index::get().get(key).map(|metadata| metadata.size);
+    let objects = objects + replaced.is_none() as u64;
+    let bytes = bytes - replaced.unwrap_or(0) + pending_bytes(bucket, completes).await? + size;
+
+    match bucket.settings.quota.allows(objects, bytes) {
+        true => Ok(guard),
+        false => Err(StorageError::QuotaExceeded),
+    }
+}
+
+// Like `reserve_quota` for a part of a multipart upload, which takes room
+// before it is an object
+pub async fn reserve_part_quota(
+    bucket: &Bucket,
+    upload: &Upload,
+    number: u32,
+    size: u64,
+) -> Result<Option<OwnedMutexGuard<()>>, StorageError> {
+    let guard = buckets::get().lock_quota(bucket).await;
+    if guard.is_none() {
+        return Ok(None);
+    }
+
+    let (objects, bytes) = index::get().prefix_totals(&bucket.prefix());
+    let bytes = bytes + pending_bytes(bucket, None).await? - upload.part_size(number).await + size;
+
+    match bucket.settings.quota.allows(objects, bytes) {
+        true => Ok(guard),
+        false => Err(StorageError::QuotaExceeded),
+    }
+}
+
+async fn pending_bytes(bucket: &Bucket, except: Option<&str>) -> Result<u64, StorageError> {
+    multipart::pending_bytes(&bucket.name, except).await.map_err(|e| {
+        error!("{}: failed to sum up multipart uploads - {}", bucket.name, e);
+        StorageError::SaveFailed
+    })
+}
+
+// Undoes the compression and encryption a blob is stored with
+pub fn decode_stored(
+    metadata: &Metadata,
+    customer_key: Option<&CustomerKey>,
+    mut data: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    if let Some(info) = &metadata.encryption {
+        data = encryption::decrypt(info, customer_key, &data)?;
+    }
+
+    if metadata.stored_encoding.is_some() {
+        data = compression::decode(compression::STORED_ENCODING, &data, metadata.size as usize)
+            .map_err(|(_, message)| message.to_string())?;
+    }
+
+    Ok(data)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // Actions audited on `key`, oldest first
+    fn audited(key: &str) -> Vec<String> {
+        let log = std::fs::read_to_string(audit::path()).unwrap_or_default();
+        log.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|event| event["key"] == key)
+            .map(|event| event["action"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn locked(retain_until: Option<u64>, legal_hold: bool) -> PutOptions<'static> {
+        PutOptions {
+            retain_until,
+            legal_hold,
+            ..testing::options()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_locks_refuse_changes() {
+        let _guard = testing::setup().await;
+        let owner = Storage::new(testing::session(AuthLevel::ReadWrite));
+        let admin = Storage::new(testing::session(AuthLevel::Admin));
+
+        owner.put_object("locks/retained", b"a", locked(Some(now() + 3600), false)).await.unwrap();
+        owner.put_object("locks/held", b"a", locked(None, true)).await.unwrap();
+
+        for storage in [&owner, &admin] {
+            for key in ["locks/retained", "locks/held"] {
+                let overwrite = storage.put_object(key, b"b", testing::options()).await;
+                assert_eq!(overwrite.unwrap_err(), StorageError::Locked);
+                assert_eq!(storage.delete_object(key).await.unwrap_err(), StorageError::Locked);
+            }
+        }
+
+        // A retention in the past is no retention at all
+        owner.put_object("locks/lapsed", b"a", locked(Some(now() - 1), false)).await.unwrap();
+        owner.put_object("locks/lapsed", b"b", testing::options()).await.unwrap();
+        owner.delete_object("locks/lapsed").await.unwrap();
+
+        assert!(audited("locks/retained").is_empty());
+        assert!(audited("locks/held").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_governance_bypass() {
+        let _guard = testing::setup().await;
+        let owner = testing::session(AuthLevel::ReadWrite);
+        let admin = testing::session(AuthLevel::Admin);
+
+        let storage = Storage::new(owner.clone());
+        storage.put_object("bypass/a", b"a", locked(Some(now() + 3600), false)).await.unwrap();
+        storage.put_object("bypass/held", b"a", locked(Some(now() + 3600), true)).await.unwrap();
+
+        // Only admins bypass, everyone else is refused before touching anything
+        let refused = Storage::new(owner).bypass_governance();
+        assert_eq!(refused.err(), Some(StorageError::Forbidden));
+
+        let bypass = Storage::new(admin).bypass_governance().unwrap();
+        bypass.put_object("bypass/a", b"b", locked(Some(now() + 3600), false)).await.unwrap();
+        bypass.delete_object("bypass/a").await.unwrap();
+        assert_eq!(audited("bypass/a"), ["overwrite", "delete"]);
+
+        // A legal hold is no retention, bypassing doesn't lift it
+        assert_eq!(bypass.delete_object("bypass/held").await.unwrap_err(), StorageError::Locked);
+        assert!(audited("bypass/held").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_update_lock() {
+        let _guard = testing::setup().await;
+        let owner = testing::session(AuthLevel::ReadWrite);
+        let admin = testing::session(AuthLevel::Admin);
+        let retain_until = now() + 3600;
+
+        let storage = Storage::new(owner);
+        storage.put_object("update-lock/a", b"a", locked(Some(retain_until), true)).await.unwrap();
+
+        // Retentions only grow without a bypass
+        let extend = LockRequest {
+            retain_until: Some(retain_until + 60),
+            legal_hold: None,
+        };
+        let metadata = storage.update_lock("update-lock/a", extend).await.unwrap();
+        assert_eq!(metadata.retain_until, Some(retain_until + 60));
+
+        let shorten = LockRequest {
+            retain_until: Some(retain_until),
+            legal_hold: None,
+        };
+        let refused = storage.update_lock("update-lock/a", shorten).await;
+        assert_eq!(refused.unwrap_err(), StorageError::Locked);
+        let refused = Storage::new(admin.clone()).update_lock("update-lock/a", shorten).await;
+        assert_eq!(refused.unwrap_err(), StorageError::Locked);
+
+        let bypass = Storage::new(admin.clone()).bypass_governance().unwrap();
+        let metadata = bypass.update_lock("update-lock/a", shorten).await.unwrap();
+        assert_eq!(metadata.retain_until, Some(retain_until));
+
+        // Only admins lift a legal hold
+        let lift = LockRequest {
+            retain_until: None,
+            legal_hold: Some(false),
+        };
+        let refused = storage.update_lock("update-lock/a", lift).await;
+        assert_eq!(refused.unwrap_err(), StorageError::Forbidden);
+        assert!(index::get().get("update-lock/a").unwrap().legal_hold);
+
+        let metadata = Storage::new(admin).update_lock("update-lock/a", lift).await.unwrap();
+        assert!(!metadata.legal_hold);
+        assert!(!index::get().get("update-lock/a").unwrap().legal_hold);
+
+        assert_eq!(audited("update-lock/a"), ["shorten-retention", "lift-legal-hold"]);
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let _guard = testing::setup().await;
+        let owner = testing::session(AuthLevel::ReadWrite);
+        let settings = buckets::BucketSettings {
+            quota: buckets::Quota {
+                max_objects: None,
+                max_bytes: Some(10),
+            },
+            ..Default::default()
+        };
+        let bucket = buckets::get().put("quota-test", settings, &owner).await.unwrap();
+        let storage = Storage::new(owner.clone()).in_bucket(Some(bucket.clone()));
+
+        // Concurrent writes can't both take the room left for one
+        let (a, b) = tokio::join!(
+            storage.put_object("a", b"123456", testing::options()),
+            storage.put_object("b", b"123456", testing::options()),
+        );
+        assert!(a.is_ok() != b.is_ok());
+        storage.delete_object(if a.is_ok() { "a" } else { "b" }).await.unwrap();
+
+        // Parts take room before they are an object
+        let upload = Upload::create("quota-test", "big", &owner.access_key, "text/plain", AuthLevel::Owner, BTreeMap::new())
+            .await
+            .unwrap();
+        reserve_part_quota(&bucket, &upload, 1, 6).await.unwrap();
+        upload.put_part(1, b"123456").await.unwrap();
+        let refused = storage.put_object("c", b"123456", testing::options()).await;
+        assert_eq!(refused.unwrap_err(), StorageError::QuotaExceeded);
+        let refused = reserve_part_quota(&bucket, &upload, 2, 6).await;
+        assert_eq!(refused.err(), Some(StorageError::QuotaExceeded));
+        // Replacing a part frees what it replaces
+        reserve_part_quota(&bucket, &upload, 1, 8).await.unwrap();
+
+        // Until the object completing the upload takes their place
+        let options = PutOptions {
+            completes: Some(&upload.id),
+            ..testing::options()
+        };
+        storage.put_object("big", b"123456", options).await.unwrap();
+        upload.remove().await.unwrap();
+        assert_eq!(index::get().prefix_totals(&bucket.prefix()), (1, 6));
+    }
+
 }
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+use a_http_parser::http::MimeType;
+use a_http_parser::request::Request;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, MutexGuard, OnceCell};
+
+use crate::authentication::{AuthContext, AuthLevel};
+use crate::storage::PutOptions;
+use crate::{buckets, config, index, search, sessions};
+
+static STORES: OnceCell<()> = OnceCell::const_new();
+// Tests going through the global stores share one data directory
+static LOCK: Mutex<()> = Mutex::const_new(());
+
+// Loads the global stores on first use and serializes the tests using them,
+// hold the returned guard for the whole test
+pub async fn setup() -> MutexGuard<'static, ()> {
+    let guard = LOCK.lock().await;
+
+    STORES
+        .get_or_init(|| async {
+            let _ = std::fs::remove_dir_all(&config::get().data_dir);
+            index::init().unwrap();
+            sessions::init().unwrap();
+            buckets::init().await.unwrap();
+        })
+        .await;
+
+    guard
+}
+
+// A saved session, the storage access checks look callers up by access key
+pub fn session(access_level: AuthLevel) -> AuthContext {
+    let mut context = AuthContext::random();
+    context.access_level = access_level;
+    sessions::get().save(&context).unwrap();
+    context
+}
+
+// What a client uploading without any options gets
+pub fn options() -> PutOptions<'static> {
+    PutOptions {
+        mime_type: MimeType::default(),
+        readable_by: AuthLevel::Owner,
+        expires_at: None,
+        customer_key: None,
+        user_metadata: BTreeMap::new(),
+        retain_until: None,
+        legal_hold: false,
+        completes: None,
+    }
+}
+
+// When `sign` signs, requests it signed authenticate at this time
+pub const SIGNED_AT: u64 = 1704067200;
+
+// Signs a request with SigV4 as an S3 client would, covering `signed_headers`.
+// Paths and queries must already be encoded the way SigV4 canonicalizes them
+pub fn sign(req: &mut Request, auth_context: &AuthContext, signed_headers: &str) {
+    let hmac = |key: &[u8], data: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    };
+
+    req.headers.insert("x-amz-date".to_string(), "20240101T000000Z".to_string());
+    req.headers.insert("x-amz-content-sha256".to_string(), "UNSIGNED-PAYLOAD".to_string());
+
+    let (path, query) = search::split_uri(&req.uri);
+    let mut query: Vec<String> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.contains('=') {
+            true => pair.to_string(),
+            false => format!("{}=", pair),
+        })
+        .collect();
+    query.sort();
+
+    let headers: String = signed_headers
+        .split(';')
+        .map(|name| format!("{}:{}\n", name, req.headers.get(name).map(|value| value.trim()).unwrap_or_default()))
+        .collect();
+    let canonical_request = [
+        req.method.to_str(),
+        path,
+        &query.join("&"),
+        &headers,
+        signed_headers,
+        "UNSIGNED-PAYLOAD",
+    ]
+    .join("\n");
+
+    let scope = "20240101/us-east-1/s3/aws4_request";
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n20240101T000000Z\n{}\n{}",
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = scope
+        .split('/')
+        .fold(format!("AWS4{}", auth_context.secret_key()).into_bytes(), |key, part| hmac(&key, part));
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        auth_context.access_key,
+        scope,
+        signed_headers,
+        hex::encode(hmac(&key, &string_to_sign))
+    );
+    req.headers.insert("authorization".to_string(), authorization);
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::CertificateConfig;
+
+// The loaded certificates indexed by the hostnames they serve
+#[derive(Default)]
+struct CertificateSet {
+    by_hostname: HashMap<String, Arc<CertifiedKey>>,
+    // Served when the client sends no SNI or an unknown hostname
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl CertificateSet {
+    fn load(configs: &[CertificateConfig], provider: &CryptoProvider) -> Result<Self, String> {
+        let mut set = Self::default();
+
+        for config in configs {
+            let key = Arc::new(load_certified_key(config, provider)?);
+
+            for hostname in &config.hostnames {
+                set.by_hostname
+                    .insert(hostname.to_ascii_lowercase(), Arc::clone(&key));
+            }
+
+            if set.default.is_none() {
+                set.default = Some(key);
+            }
+        }
+
+        Ok(set)
+    }
+
+    fn find(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = server_name.map(str::to_ascii_lowercase) {
+            if let Some(key) = self.by_hostname.get(&name) {
+                return Some(Arc::clone(key));
+            }
+
+            // A wildcard entry covers exactly one additional label
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(key) = self.by_hostname.get(&format!("*.{}", parent)) {
+                    return Some(Arc::clone(key));
+                }
+            }
+        }
+
+        self.default.clone()
+    }
+}
+
+fn load_certified_key(
+    config: &CertificateConfig,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: failed to read certificates - {}", config.cert.display(), e))?;
+
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates found", config.cert.display()));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| format!("{}: failed to read private key - {}", config.key.display(), e))?;
+
+    // Also verifies that the key belongs to the leaf certificate
+    CertifiedKey::from_der(chain, key, provider).map_err(|e| {
+        format!(
+            "{}: unusable certificate/key pair - {}",
+            config.cert.display(),
+            e
+        )
+    })
+}
+
+// Selects certificates by SNI and swaps them in place when the files on disk
+// change, existing connections keep the certificate they were started with
+pub struct CertificateStore {
+    configs: Vec<CertificateConfig>,
+    provider: Arc<CryptoProvider>,
+    certificates: RwLock<Arc<CertificateSet>>,
+}
+
+impl std::fmt::Debug for CertificateStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertificateStore")
+            .field("certificates", &self.configs.len())
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certificates
+            .read()
+            .unwrap()
+            .find(client_hello.server_name())
+    }
+}
+
+impl CertificateStore {
+    pub fn load(configs: &[CertificateConfig]) -> Result<Arc<Self>, String> {
+        let provider = Arc::new(ring::default_provider());
+        let certificates = CertificateSet::load(configs, &provider)?;
+
+        Ok(Arc::new(Self {
+            configs: configs.to_vec(),
+            provider,
+            certificates: RwLock::new(Arc::new(certificates)),
+        }))
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> Result<TlsAcceptor, String> {
+        let mut server_config = ServerConfig::builder_with_provider(Arc::clone(&self.provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("failed to configure TLS - {}", e))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        self.configs
+            .iter()
+            .flat_map(|config| [&config.cert, &config.key])
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    // Polls the certificate files and reloads them all when any changed. A
+    // failed reload keeps serving the previous certificates
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut last_seen = self.modified_times();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let current = self.modified_times();
+            if current == last_seen {
+                continue;
+            }
+            last_seen = current;
+
+            match CertificateSet::load(&self.configs, &self.provider) {
+                Ok(certificates) => {
+                    *self.certificates.write().unwrap() = Arc::new(certificates);
+                    info!("Reloaded TLS certificates");
+                }
+                Err(e) => error!("Failed to reload TLS certificates, keeping the old ones: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, error};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+
+use crate::compression::{self, Compressor, Decompressor, Encoding};
+use crate::config;
+use crate::encryption::Decryptor;
+use crate::metrics::METRICS;
+use crate::server::write_with_timeout;
+use crate::storable::{FileIterator, StorableBlob};
+use crate::storage::Object;
+
+// A slice of the object contents, `end` is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    // Value of the Content-Range header for an object of `size` bytes
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end - 1, size)
+    }
+}
+
+// Parses a Range header for an object of `size` bytes. Only single byte ranges
+// are supported, others are ignored and the whole object is sent. Err means
+// the range lies outside of the object
+pub fn parse_range(header: &str, size: u64) -> Option<Result<ByteRange, ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // The last `end` bytes
+        let length = end.parse::<u64>().ok()?;
+        ByteRange {
+            start: size.saturating_sub(length),
+            end: size,
+        }
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = match end {
+            "" => u64::MAX,
+            end => end.parse::<u64>().ok()?.saturating_add(1),
+        };
+
+        // A last byte before the first makes the header invalid
+        if end <= start {
+            return None;
+        }
+
+        ByteRange {
+            start,
+            end: end.min(size),
+        }
+    };
+
+    if range.start >= size || range.len() == 0 {
+        return Some(Err(()));
+    }
+
+    Some(Ok(range))
+}
+
+enum Source {
+    Object(Box<Object>),
+    // A precompressed variant of an object
+    Variant(PathBuf),
+}
+
+// A response body streamed to the client after the headers
+pub struct Transfer {
+    source: Source,
+    // Undo the compression and encryption the blob is stored with
+    decode: bool,
+    range: Option<ByteRange>,
+    compressor: Option<Compressor>,
+    // Compressed output is also written here and kept as a variant once complete
+    cache: Option<PathBuf>,
+}
+
+impl Transfer {
+    // Sends the contents of `object`
+    pub fn object(object: Object) -> Self {
+        Self {
+            decode: object.metadata.is_transformed(),
+            source: Source::Object(Box::new(object)),
+            range: None,
+            compressor: None,
+            cache: None,
+        }
+    }
+
+    // Sends the blob exactly as it is stored
+    pub fn stored(object: Object) -> Self {
+        Self {
+            source: Source::Object(Box::new(object)),
+            decode: false,
+            range: None,
+            compressor: None,
+            cache: None,
+        }
+    }
+
+    pub fn with_range(mut self, range: ByteRange) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    // Serves `object` compressed with `encoding`, from the variant cache when
+    // it holds a copy. Returns the size of the body if it is known up front
+    pub async fn compressed(object: Object, encoding: Encoding) -> (Self, Option<u64>) {
+        let config = &config::get().compression;
+        let path = compression::variant_path(&object.metadata.etag, encoding);
+        // Variants are plaintext, so encrypted objects never get one
+        let cache_variants = config.cache_variants && object.metadata.encryption.is_none();
+
+        if cache_variants {
+            if let Ok(metadata) = tokio::fs::metadata(&path).await {
+                METRICS.variant_cache_hit();
+                let transfer = Self {
+                    source: Source::Variant(path),
+                    decode: false,
+                    range: None,
+                    compressor: None,
+                    cache: None,
+                };
+
+                return (transfer, Some(metadata.len()));
+            }
+
+            METRICS.variant_cache_miss();
+        }
+
+        let transfer = Self {
+            decode: object.metadata.is_transformed(),
+            source: Source::Object(Box::new(object)),
+            range: None,
+            compressor: Some(Compressor::new(encoding, config)),
+            cache: cache_variants.then_some(path),
+        };
+
+        (transfer, None)
+    }
+
+    // Where reading starts: the offset in the stored blob, the first
+    // encrypted chunk there and how many decoded bytes precede the range
+    fn start(&self, object: &Object) -> (u64, u64, u64) {
+        let start = self.range.map_or(0, |range| range.start);
+        let metadata = &object.metadata;
+
+        match &metadata.encryption {
+            _ if !self.decode => (start, 0, 0),
+            // Encrypted chunks can be decrypted on their own
+            Some(info) if metadata.stored_encoding.is_none() => {
+                let chunk = start / info.chunk_size as u64;
+                let skip = start - chunk * info.chunk_size as u64;
+                (info.chunk_offset(chunk), chunk, skip)
+            }
+            // A compressed stream has to be decoded from the start
+            _ => (0, 0, start),
+        }
+    }
+
+    // Writes the body to `writer`, returning how many bytes were sent
+    pub async fn send<W: AsyncWrite + Unpin>(
+        self,
+        writer: &mut W,
+        write_timeout: Duration,
+    ) -> std::io::Result<usize> {
+        let mut decryptor = None;
+        let mut decompressor = None;
+
+        let (mut iterator, skip) = match &self.source {
+            Source::Object(object) => {
+                let (offset, first_chunk, skip) = self.start(object);
+
+                if self.decode {
+                    if let Some(info) = &object.metadata.encryption {
+                        let created = Decryptor::new(info, object.customer_key.as_ref(), first_chunk).map_err(|e| {
+                            error!("{}: {}", object.metadata.key, e);
+                            std::io::Error::other(e)
+                        })?;
+                        decryptor = Some(created);
+                    }
+
+                    if object.metadata.stored_encoding.is_some() {
+                        decompressor = Some(Decompressor::new()?);
+                    }
+                }
+
+                let iterator = object
+                    .stream_file_from(offset)
+                    .await
+                    .map_err(|_| std::io::Error::other("failed to open object"))?;
+
+                (iterator, skip)
+            }
+            Source::Variant(path) => (FileIterator::new(File::open(path).await?), 0),
+        };
+
+        let cache = match &self.cache {
+            Some(path) => VariantWriter::create(path).await,
+            None => None,
+        };
+
+        let mut sink = Sink {
+            writer,
+            write_timeout,
+            skip,
+            remaining: self.range.map(|range| range.len()),
+            compressor: self.compressor,
+            cache,
+            sent: 0,
+        };
+
+        while let Some(chunk) = iterator.next().await {
+            let mut chunk = chunk?;
+
+            if let Some(decryptor) = &mut decryptor {
+                chunk = decryptor.update(&chunk)?;
+            }
+            if let Some(decompressor) = &mut decompressor {
+                chunk = decompressor.update(&chunk)?;
+            }
+
+            sink.write(chunk).await?;
+
+            if sink.is_done() {
+                return Ok(sink.sent);
+            }
+        }
+
+        // The final encrypted chunk is only known once the blob ended
+        if let Some(decryptor) = decryptor {
+            let mut chunk = decryptor.finish()?;
+            if let Some(decompressor) = &mut decompressor {
+                chunk = decompressor.update(&chunk)?;
+            }
+
+            sink.write(chunk).await?;
+        }
+
+        sink.finish().await
+    }
+}
+
+// The end of the pipeline: cuts the requested range out of the contents,
+// compresses it and writes it to the client
+struct Sink<'a, W> {
+    writer: &'a mut W,
+    write_timeout: Duration,
+    skip: u64,
+    remaining: Option<u64>,
+    compressor: Option<Compressor>,
+    cache: Option<VariantWriter>,
+    sent: usize,
+}
+
+impl<W: AsyncWrite + Unpin> Sink<'_, W> {
+    fn is_done(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    async fn write(&mut self, mut chunk: Vec<u8>) -> std::io::Result<()> {
+        let skipped = self.skip.min(chunk.len() as u64);
+        chunk.drain(..skipped as usize);
+        self.skip -= skipped;
+
+        if let Some(remaining) = &mut self.remaining {
+            chunk.truncate((*remaining).min(chunk.len() as u64) as usize);
+            *remaining -= chunk.len() as u64;
+        }
+
+        if let Some(compressor) = &mut self.compressor {
+            chunk = compressor.update(&chunk)?;
+        }
+
+        self.send(chunk).await
+    }
+
+    async fn send(&mut self, chunk: Vec<u8>) -> std::io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        write_with_timeout(self.writer, &chunk, self.write_timeout).await?;
+        self.sent += chunk.len();
+
+        if let Some(variant) = &mut self.cache {
+            variant.write(&chunk).await;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> std::io::Result<usize> {
+        if let Some(compressor) = self.compressor.take() {
+            self.send(compressor.finish()?).await?;
+        }
+
+        if let Some(variant) = self.cache.take() {
+            variant.complete().await;
+        }
+
+        Ok(self.sent)
+    }
+}
+
+// Writes a variant to a temporary file that only replaces the cached copy
+// once it is complete, an abandoned transfer leaves nothing behind
+struct VariantWriter {
+    file: Option<File>,
+    temporary: PathBuf,
+    path: PathBuf,
+}
+
+impl VariantWriter {
+    async fn create(path: &Path) -> Option<Self> {
+        tokio::fs::create_dir_all(compression::variants_dir()).await.ok()?;
+
+        let temporary = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        let file = File::create(&temporary).await.ok()?;
+
+        Some(Self {
+            file: Some(file),
+            temporary,
+            path: path.to_path_buf(),
+        })
+    }
+
+    async fn write(&mut self, data: &[u8]) {
+        if let Some(file) = &mut self.file {
+            if file.write_all(data).await.is_err() {
+                self.file = None;
+            }
+        }
+    }
+
+    async fn complete(mut self) {
+        let Some(mut file) = self.file.take() else {
+            return;
+        };
+
+        if file.flush().await.is_ok() && tokio::fs::rename(&self.temporary, &self.path).await.is_ok() {
+            debug!("Cached variant {}", self.path.display());
+        }
+    }
+}
+
+impl Drop for VariantWriter {
+    fn drop(&mut self) {
+        // Gone already when the variant was moved into place
+        let _ = std::fs::remove_file(&self.temporary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        let range = |start, end| Some(Ok(ByteRange { start, end }));
+
+        assert_eq!(parse_range("bytes=0-99", 1000), range(0, 100));
+        assert_eq!(parse_range("bytes=900-", 1000), range(900, 1000));
+        assert_eq!(parse_range("bytes=-100", 1000), range(900, 1000));
+        assert_eq!(parse_range("bytes=990-2000", 1000), range(990, 1000));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+    }
+}
@@ -0,0 +1,314 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use crate::config;
+use crate::index;
+use crate::metadata::Metadata;
+use crate::storable::{StorableBase, StorableJson};
+use crate::storage::{Object, StorageError};
+
+// Served by the trash endpoints, no object can be stored under it
+pub const PATH: &str = ".trash";
+
+// A deleted object kept until its retention is over. The blob is moved
+// aside as is, still compressed and encrypted like it was stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedObject {
+    pub id: String,
+    pub deleted_at: u64,
+    // Unix time it is purged at, the retention when it was deleted applies
+    pub expires_at: u64,
+    // Access key of whoever deleted the object
+    pub deleted_by: String,
+    // The bucket the object was deleted from, None for the global namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreError {
+    // Another object has been stored under the key since
+    KeyInUse,
+    // The bucket the object belongs in refuses it
+    Refused(StorageError),
+    Failed,
+}
+
+impl StorableBase for TrashedObject {
+    fn base_dir() -> PathBuf {
+        config::get().data_dir.join("trash").join("metadata")
+    }
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl StorableJson for TrashedObject {}
+
+impl TrashedObject {
+    fn blob_path(&self) -> PathBuf {
+        blob_dir().join(&self.id)
+    }
+}
+
+fn blob_dir() -> PathBuf {
+    config::get().data_dir.join("trash").join("storage")
+}
+
+pub fn is_enabled() -> bool {
+    config::get().trash.retention_secs > 0
+}
+
+// Moves an object into the trash. The trash record is written first, so a
+// crash part way leaves at worst a record without its blob
+pub async fn trash(metadata: Metadata, deleted_by: &str, bucket: Option<&str>) -> std::io::Result<TrashedObject> {
+    let deleted_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let key_hash = hex::encode(Sha256::digest(metadata.key.as_bytes()));
+
+    let trashed = TrashedObject {
+        // Unique even when the same key is deleted again, and safe as a file name
+        id: format!("{}-{}", deleted_at.as_nanos(), &key_hash[..16]),
+        deleted_at: deleted_at.as_secs(),
+        expires_at: deleted_at.as_secs().saturating_add(config::get().trash.retention_secs),
+        deleted_by: deleted_by.to_string(),
+        bucket: bucket.map(str::to_string),
+        metadata,
+    };
+    trashed.save().await?;
+
+    tokio::fs::create_dir_all(blob_dir()).await?;
+    let blob = Object::base_dir().join(&trashed.metadata.key);
+    match tokio::fs::rename(&blob, trashed.blob_path()).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            let _ = trashed.delete().await;
+            return Err(e);
+        }
+    }
+
+    trashed.metadata.delete().await?;
+    if let Err(e) = index::get().remove(&trashed.metadata.key) {
+        error!("{}: failed to log the metadata index change - {}", trashed.metadata.key, e);
+    }
+
+    Ok(trashed)
+}
+
+pub async fn find(id: &str) -> Option<TrashedObject> {
+    // Ids are generated by `trash`, anything else can't name a record
+    if !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        return None;
+    }
+
+    let path = TrashedObject::base_dir().join(format!("{}.json", hex::encode(Sha256::digest(id.as_bytes()))));
+    let contents = tokio::fs::read(path).await.ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+// Every trashed object, most recently deleted first
+pub async fn list() -> std::io::Result<Vec<TrashedObject>> {
+    TrashedObject::ensure_base_dir_exists()?;
+    let mut list = TrashedObject::list().await?;
+    let mut trashed = Vec::new();
+
+    while let Some(object) = list.next().await {
+        trashed.push(object);
+    }
+
+    trashed.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then_with(|| b.id.cmp(&a.id)));
+    Ok(trashed)
+}
+
+// Puts a trashed object back under its key, see Storage::restore for the
+// checks that come first
+pub async fn restore(trashed: TrashedObject) -> Result<Metadata, RestoreError> {
+    let key = trashed.metadata.key.clone();
+    if index::get().get(&key).is_some() {
+        return Err(RestoreError::KeyInUse);
+    }
+
+    let blob = Object::base_dir().join(&key);
+    if let Some(parent) = blob.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(|_| RestoreError::Failed)?;
+    }
+
+    if let Err(e) = tokio::fs::rename(trashed.blob_path(), &blob).await {
+        error!("{}: failed to restore blob of {} - {}", trashed.id, key, e);
+        return Err(RestoreError::Failed);
+    }
+
+    if let Err(e) = trashed.metadata.save().await {
+        error!("{}: failed to restore record of {} - {}", trashed.id, key, e);
+        let _ = tokio::fs::rename(&blob, trashed.blob_path()).await;
+        return Err(RestoreError::Failed);
+    }
+
+    if let Err(e) = index::get().put(&trashed.metadata) {
+        error!("{}: failed to log the metadata index change - {}", key, e);
+    }
+
+    if let Err(e) = trashed.delete().await {
+        error!("{}: failed to remove restored trash record - {}", trashed.id, e);
+    }
+
+    info!("Restored {} from the trash", key);
+    Ok(trashed.metadata)
+}
+
+// Deletes a trashed object for good
+pub async fn purge(trashed: &TrashedObject) -> std::io::Result<()> {
+    match tokio::fs::remove_file(trashed.blob_path()).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    trashed.delete().await
+}
+
+// Purges trashed objects past their retention, returning how many were
+// purged and how many corrupt records were skipped
+pub async fn purge_expired(shutdown: watch::Receiver<bool>) -> std::io::Result<(usize, usize)> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    TrashedObject::ensure_base_dir_exists()?;
+    let mut list = TrashedObject::list().await?.cancel_on(shutdown);
+    let mut purged = 0;
+
+    while let Some(trashed) = list.next().await {
+        if trashed.expires_at > now {
+            continue;
+        }
+
+        match purge(&trashed).await {
+            Ok(_) => purged += 1,
+            Err(e) => error!("{}: failed to purge {} - {}", trashed.id, trashed.metadata.key, e),
+        }
+    }
+
+    Ok((purged, list.skipped()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::AuthLevel;
+    use crate::buckets::{self, BucketSettings, Quota};
+    use crate::storage::Storage;
+    use crate::testing;
+
+    async fn trashed(key: &str) -> TrashedObject {
+        list().await.unwrap().into_iter().find(|trashed| trashed.metadata.key == key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_trash_and_restore() {
+        let _guard = testing::setup().await;
+        let owner = testing::session(AuthLevel::ReadWrite);
+        let storage = Storage::new(owner.clone());
+
+        storage.put_object("trash/restore.txt", b"kept", testing::options()).await.unwrap();
+        storage.delete_object("trash/restore.txt").await.unwrap();
+        assert!(index::get().get("trash/restore.txt").is_none());
+
+        let trashed = trashed("trash/restore.txt").await;
+        assert_eq!(trashed.deleted_by, owner.access_key);
+        assert_eq!(trashed.bucket, None);
+        assert!(trashed.blob_path().exists());
+        assert_eq!(find(&trashed.id).await.unwrap().metadata.key, "trash/restore.txt");
+
+        // Nothing goes back over an object stored since
+        storage.put_object("trash/restore.txt", b"newer", testing::options()).await.unwrap();
+        assert_eq!(storage.restore(trashed.clone()).await.unwrap_err(), RestoreError::KeyInUse);
+        storage.delete_object("trash/restore.txt").await.unwrap();
+
+        storage.restore(trashed.clone()).await.unwrap();
+        assert_eq!(index::get().get("trash/restore.txt").unwrap().size, 4);
+        assert!(storage.get_object("trash/restore.txt", true, None).await.is_ok());
+        assert!(find(&trashed.id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let _guard = testing::setup().await;
+        let storage = Storage::new(testing::session(AuthLevel::ReadWrite));
+
+        storage.put_object("trash/purge.txt", b"gone", testing::options()).await.unwrap();
+        storage.delete_object("trash/purge.txt").await.unwrap();
+        let trashed = trashed("trash/purge.txt").await;
+
+        purge(&trashed).await.unwrap();
+        assert!(find(&trashed.id).await.is_none());
+        assert!(!trashed.blob_path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired() {
+        let _guard = testing::setup().await;
+        let storage = Storage::new(testing::session(AuthLevel::ReadWrite));
+
+        storage.put_object("trash/expired.txt", b"old", testing::options()).await.unwrap();
+        storage.delete_object("trash/expired.txt").await.unwrap();
+        storage.put_object("trash/unexpired.txt", b"new", testing::options()).await.unwrap();
+        storage.delete_object("trash/unexpired.txt").await.unwrap();
+
+        let mut expired = trashed("trash/expired.txt").await;
+        expired.expires_at = 0;
+        expired.save().await.unwrap();
+
+        let (_, shutdown) = watch::channel(false);
+        purge_expired(shutdown).await.unwrap();
+        assert!(find(&expired.id).await.is_none());
+        assert!(find(&trashed("trash/unexpired.txt").await.id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_restore_checks_bucket() {
+        let _guard = testing::setup().await;
+        let owner = testing::session(AuthLevel::ReadWrite);
+        let other = testing::session(AuthLevel::ReadWrite);
+
+        let settings = BucketSettings::default();
+        let bucket = buckets::get().put("trash-restore", settings.clone(), &owner).await.unwrap();
+        let storage = Storage::new(owner.clone()).in_bucket(Some(bucket));
+        storage.put_object("a.txt", b"aaaa", testing::options()).await.unwrap();
+        storage.delete_object("a.txt").await.unwrap();
+        let trashed = trashed("trash-restore/a.txt").await;
+        assert_eq!(trashed.bucket.as_deref(), Some("trash-restore"));
+
+        // Not while the bucket is gone
+        buckets::get().remove("trash-restore", &owner).await.unwrap();
+        let refused = Storage::new(owner.clone()).restore(trashed.clone()).await;
+        assert_eq!(refused.unwrap_err(), RestoreError::Refused(StorageError::NoSuchBucket));
+
+        // Nor into a bucket someone else re-created
+        buckets::get().put("trash-restore", settings, &other).await.unwrap();
+        let refused = Storage::new(owner.clone()).restore(trashed.clone()).await;
+        assert_eq!(refused.unwrap_err(), RestoreError::Refused(StorageError::Forbidden));
+
+        // Nor past its quota
+        let settings = BucketSettings {
+            quota: Quota {
+                max_objects: None,
+                max_bytes: Some(3),
+            },
+            ..Default::default()
+        };
+        buckets::get().put("trash-restore", settings, &other).await.unwrap();
+        let refused = Storage::new(testing::session(AuthLevel::Admin)).restore(trashed.clone()).await;
+        assert_eq!(refused.unwrap_err(), RestoreError::Refused(StorageError::QuotaExceeded));
+        assert!(find(&trashed.id).await.is_some());
+
+        buckets::get().put("trash-restore", BucketSettings::default(), &other).await.unwrap();
+        Storage::new(other).restore(trashed).await.unwrap();
+        assert!(index::get().get("trash-restore/a.txt").is_some());
+    }
+}
@@ -2,11 +2,10 @@
 DIR="$( cd "$( dirname "${BASH_SOURCE[0]}" )" >/dev/null 2>&1 && pwd )"
 ENDPOINT="http://localhost/cdn/"
 
-curl -X LIST $ENDPOINT -c cookie.txt -v
-
+# Sessions are only created by uploads, the first POST sets the cookie
 for file in $DIR/../data/*; do
     content_type=$(file -b --mime-type $file)
-    curl -b cookie.txt -X POST $ENDPOINT$(basename $file) --data-binary "@$file" -H "Content-Type: $content_type" > /dev/null
+    curl -b cookie.txt -c cookie.txt -X POST $ENDPOINT$(basename $file) --data-binary "@$file" -H "Content-Type: $content_type" > /dev/null
 done
 
 JWT=$(cat cookie.txt | grep "authorization" | cut -f 7)